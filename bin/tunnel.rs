@@ -1,7 +1,9 @@
 
 
-use anyhow::{Result};
-use clap::Arg;
+use std::{io::{Read, Write}, net::{IpAddr, SocketAddr, TcpStream}};
+
+use anyhow::{anyhow, bail, Result};
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
 use futures::{FutureExt};
 use log::error;
 
@@ -10,15 +12,112 @@ use tunnel::{
     start,
 };
 
-fn load() -> Result<()> {
-    let app = clap::App::new("tunnel").arg(
-        Arg::with_name("config")
-            .short("-c")
-            .long("--config")
-            .required(true)
-            .value_name("FILE"),
+const DEFAULT_API: &str = "127.0.0.1:9090";
+
+// 未指定 --api 时使用配置文件中的 api，都没有时使用默认地址
+fn api_address(matches: &ArgMatches, config_path: Option<&str>) -> Result<String> {
+    if let Some(api) = matches.value_of("api") {
+        return Ok(api.to_string());
+    }
+    let config_path = match config_path {
+        Some(x) => x,
+        None => return Ok(DEFAULT_API.to_string()),
+    };
+    let config = tunnel::load_from_file(config_path)?;
+    let api = config
+        .api
+        .ok_or_else(|| anyhow!("control api is not enabled in {}", config_path))?;
+    let ip: IpAddr = api
+        .address
+        .parse()
+        .map_err(|err| anyhow!("invalid api address {} {}", api.address, err))?;
+    Ok(SocketAddr::new(ip, api.port).to_string())
+}
+
+// 通过控制 API 发送请求，返回 status 和 body
+fn request_api(api: &str, method: &str, path: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(api)?;
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        api,
+        body.len(),
+        body
     );
+    stream.write_all(request.as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow!("bad response from control api"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| x.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("bad response from control api"))?;
+    Ok((status, body.to_string()))
+}
+
+fn run_command(name: &str, matches: &ArgMatches, config_path: Option<&str>) -> Result<()> {
+    let api = &*api_address(matches, config_path)?;
+    let (status, body) = match name {
+        "groups" => request_api(api, "GET", "/groups", "")?,
+        "select" => {
+            let group = matches.value_of("group").expect("group required");
+            let member = matches.value_of("member").expect("member required");
+            let body = serde_json::json!({
+                "name": member,
+                "closeConnections": matches.is_present("close"),
+            });
+            request_api(api, "PUT", &format!("/groups/{}", group), &body.to_string())?
+        }
+        _ => unreachable!(),
+    };
+    if status >= 300 {
+        bail!("control api returned {} {}", status, body);
+    }
+    if !body.is_empty() {
+        println!("{}", body);
+    }
+    Ok(())
+}
+
+fn load() -> Result<()> {
+    let api = Arg::with_name("api")
+        .long("--api")
+        .value_name("ADDRESS")
+        .help("control api address, defaults to the api of --config or 127.0.0.1:9090");
+    let app = clap::App::new("tunnel")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("config")
+                .short("-c")
+                .long("--config")
+                .required(true)
+                .value_name("FILE"),
+        )
+        .subcommand(
+            SubCommand::with_name("groups")
+                .about("show outbound groups")
+                .arg(api.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("select")
+                .about("switch the member of a selector group")
+                .arg(api)
+                .arg(Arg::with_name("group").required(true).index(1))
+                .arg(Arg::with_name("member").required(true).index(2))
+                .arg(
+                    Arg::with_name("close")
+                        .long("--close")
+                        .help("close connections made through the selector"),
+                ),
+        );
     let matchers = app.get_matches();
+    if let (name, Some(matches)) = matchers.subcommand() {
+        return run_command(name, matches, matchers.value_of("config"));
+    }
     let config_path = matchers
         .value_of("config")
         .expect("config file path required");
@@ -39,6 +138,7 @@ fn load() -> Result<()> {
 }
 fn main() {
     if let Err(err) = load() {
+        eprintln!("{}", err);
         error!("{}", err);
     }
 }
@@ -0,0 +1,224 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, info};
+use serde_derive::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use super::OutboundManager;
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 控制 API，每个连接只处理一个请求
+// GET /groups
+// GET /groups/{tag}
+// PUT /groups/{tag} {"name": "member", "closeConnections": false}
+pub async fn serve(addr: SocketAddr, manager: Arc<OutboundManager>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(x) => x,
+        Err(err) => {
+            error!("failed to bind control api at {} {}", addr, err);
+            futures::future::pending::<()>().await;
+            return;
+        }
+    };
+    info!("control api listening at {}", addr);
+    run(listener, manager).await
+}
+
+async fn run(listener: TcpListener, manager: Arc<OutboundManager>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(err) => {
+                error!("control api accept failed {}", err);
+                continue;
+            }
+        };
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, manager).await {
+                debug!("control api request from {} failed {}", peer, err);
+            }
+        });
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct SelectRequest {
+    name: String,
+    #[serde(default, rename = "closeConnections")]
+    close_connections: bool,
+}
+
+async fn handle(mut stream: TcpStream, manager: Arc<OutboundManager>) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow!("read request timeout"))??;
+    let (status, body) = route(&request, &manager);
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        _ => "Method Not Allowed",
+    };
+    let body = body.map(|x| x.to_string()).unwrap_or_default();
+    let mut response = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", status, reason);
+    if status != 204 {
+        response.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n",
+            body.len()
+        ));
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn route(request: &Request, manager: &OutboundManager) -> (u16, Option<serde_json::Value>) {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["groups"]) => {
+            let groups: Vec<serde_json::Value> =
+                manager.groups().iter().map(|x| x.to_json()).collect();
+            (200, Some(serde_json::json!({ "groups": groups })))
+        }
+        ("GET", ["groups", tag]) => match manager.get_group(tag) {
+            Some(group) => (200, Some(group.to_json())),
+            None => not_found(format!("outbound group {} not found", tag)),
+        },
+        ("PUT", ["groups", tag]) => {
+            let group = match manager.get_group(tag) {
+                Some(x) => x,
+                None => return not_found(format!("outbound group {} not found", tag)),
+            };
+            let select: SelectRequest = match serde_json::from_slice(&request.body) {
+                Ok(x) => x,
+                Err(err) => return (400, Some(error_json(err.to_string()))),
+            };
+            if !group.is_selector() {
+                return (400, Some(error_json(format!("outbound group {} is not a selector", tag))));
+            }
+            if !group.members.contains(&select.name) {
+                return not_found(format!("{} is not a member of outbound group {}", select.name, tag));
+            }
+            match manager.select(tag, &select.name, select.close_connections) {
+                Ok(_) => (204, None),
+                Err(err) => (400, Some(error_json(err.to_string()))),
+            }
+        }
+        (_, ["groups"]) | (_, ["groups", _]) => (405, Some(error_json("method not allowed".to_string()))),
+        _ => not_found(format!("{} not found", path)),
+    }
+}
+
+fn not_found(message: String) -> (u16, Option<serde_json::Value>) {
+    (404, Some(error_json(message)))
+}
+
+fn error_json(message: String) -> serde_json::Value {
+    serde_json::json!({ "message": message })
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed before request complete");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            bail!("request too large");
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        let header_len = match req.parse(&buf)? {
+            httparse::Status::Complete(x) => x,
+            httparse::Status::Partial => continue,
+        };
+        let content_length = req
+            .headers
+            .iter()
+            .find(|x| x.name.eq_ignore_ascii_case("content-length"))
+            .map(|x| std::str::from_utf8(x.value).unwrap_or_default().trim().parse::<usize>())
+            .transpose()?
+            .unwrap_or(0);
+        if header_len + content_length > MAX_REQUEST_SIZE {
+            bail!("request too large");
+        }
+        if buf.len() < header_len + content_length {
+            continue;
+        }
+        return Ok(Request {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            body: buf[header_len..header_len + content_length].to_vec(),
+        });
+    }
+}
+
+#[tokio::test]
+async fn control_api_test() {
+    let outbounds: Vec<crate::config::Outbound> = serde_json::from_str(
+        r#"[
+        {"protocol": "direct", "tag": "a"},
+        {"protocol": "direct", "tag": "b"},
+        {"protocol": "selector", "tag": "proxy", "settings": {"outbounds": ["a", "b"]}}
+    ]"#,
+    )
+    .unwrap();
+    let manager = Arc::new(OutboundManager::new(outbounds).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(run(listener, manager.clone()));
+
+    async fn request(addr: SocketAddr, raw: String) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(raw.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+    let put = |tag: &str, body: &str| {
+        format!(
+            "PUT /groups/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            tag,
+            body.len(),
+            body
+        )
+    };
+
+    let response = request(addr, "GET /groups HTTP/1.1\r\nHost: localhost\r\n\r\n".to_string()).await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains(r#""type":"selector""#));
+
+    let response = request(addr, put("proxy", r#"{"name": "b"}"#)).await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert_eq!(manager.get_group("proxy").unwrap().selected(), Some("b".to_string()));
+
+    let response = request(addr, "GET /groups/proxy HTTP/1.1\r\n\r\n".to_string()).await;
+    assert!(response.contains(r#""selected":"b""#), "{}", response);
+
+    let response = request(addr, put("proxy", r#"{"name": "c"}"#)).await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let response = request(addr, put("missing", r#"{"name": "a"}"#)).await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let response = request(addr, put("proxy", "not json")).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+}
@@ -1,18 +1,30 @@
-use std::{convert::TryFrom, sync::Arc, net::SocketAddr};
+use std::{
+    collections::HashMap, convert::TryFrom, hash::Hash, net::SocketAddr, sync::Arc,
+    time::Duration,
+};
 
 use log::{debug, error, trace};
 use tokio::{
-    net::{TcpStream, UdpSocket},
-    sync::RwLock,
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex, Notify, RwLock,
+    },
+    time::Instant,
 };
 
 use crate::{
     config::Config,
-    proxy::{Address, Session, StreamWrapperTrait, TcpOutboundHandlerTrait},
+    proxy::{
+        relay_udp_over_stream, Address, AnyDeferredReply, AnyStream, ConnectError, ConnectionGuard, Network,
+        OutboundHandler, Session, UdpOutboundHandlerTrait,
+    },
     Context,
 };
+#[cfg(target_os = "linux")]
+use crate::net::sys::linux::{create_transparent_udp_socket, recv_from_with_destination};
 
-use super::{sniffer::Sniffer, DnsClient, OutboundManager, Router};
+use super::{router::Route, sniffer::Sniffer, DnsClient, OutboundManager, Router};
 
 // 负责将请求分发给不同的 代理协议 处理
 pub struct Dispatcher {
@@ -22,10 +34,25 @@ pub struct Dispatcher {
     outbound_manager: Arc<OutboundManager>,
 }
 impl Dispatcher {
-    pub async fn dispatch_tcp(&self, stream: TcpStream, sess: &mut Session) {
+    pub async fn dispatch_tcp(&self, stream: AnyStream, sess: &mut Session) {
+        self.dispatch_tcp_with_reply(stream, sess, None).await
+    }
+
+    // reply 不为空时，inbound 等待 outbound 建立连接后才回复 client
+    pub async fn dispatch_tcp_with_reply(&self, stream: AnyStream, sess: &mut Session, reply: Option<AnyDeferredReply>) {
+        self.dispatch_stream(stream, sess, reply, true).await
+    }
+
+    // destination 由配置固定，嗅探到的 SNI 不能覆盖
+    pub async fn dispatch_fixed_tcp(&self, stream: AnyStream, sess: &mut Session) {
+        self.dispatch_stream(stream, sess, None, false).await
+    }
+
+    async fn dispatch_stream(&self, stream: AnyStream, sess: &mut Session, reply: Option<AnyDeferredReply>, sniff: bool) {
         // https://github.com/iamwwc/v2ray-core/blob/8cdd680f5ca8d05c618752eb944a42a7b4d31f6c/app/dispatcher/default.go#L207
         // 由于需要提供 domain routing，所以如果 port == 443，首先尝试嗅探 TLS SNI
-        let mut local_stream: Box<dyn StreamWrapperTrait> = if sess.local_peer.port() == 443 {
+        // 延迟回复时 client 收到 reply 前不会发送数据，无法嗅探
+        let mut local_stream: AnyStream = if sniff && sess.local_peer.port() == 443 && reply.is_none() {
             // TLS，嗅探 SNI
             let mut sniffer = Sniffer::new(stream);
             match sniffer.sniff().await {
@@ -47,76 +74,273 @@ impl Dispatcher {
                 Err(_err) => return,
             }
         } else {
-            Box::new(stream)
+            stream
         };
         // starting routing match
-        let outbound_handler = match self.router.route(&sess) {
-            Some(tag) => match self.outbound_manager.get_handler(&*tag) {
-                Some(h) => h,
-                None => {
-                    error!("no outbound tag found {}", tag);
-                    return;
-                }
-            },
+        let route = match self.router.find(&sess) {
+            Some(route) => route,
             None => {
                 error!("no outbound session {:?} found!", &sess);
+                send_reply(&reply, &mut local_stream, Err(ConnectError::NotAllowed)).await;
                 return;
             }
         };
-        // connect to remote proxy server
-        let tcp = if let Some(tcp) = &outbound_handler.tcp_handler {
-            tcp
-        } else {
-            error!("tag {} not have tcp handler !", outbound_handler.tag);
+        let (outbound_handler, close, connected) = match self.connect(&route, sess, false).await {
+            Ok(x) => x,
+            Err(err) => {
+                send_reply(&reply, &mut local_stream, Err(err)).await;
+                return;
+            }
+        };
+        if !send_reply(&reply, &mut local_stream, Ok(())).await {
             return;
+        }
+        let guard = outbound_handler.track_connection(close);
+        let mut remote_stream = match connected {
+            Connected::Stream(stream) => stream,
+            Connected::Datagram(socket) => {
+                // inbound 将 UDP 承载在 stream 上 (例如 vless UDP command)
+                trace!(
+                    "udp over stream established. {} => {} => tunnel => {}",
+                    sess.peer_address,
+                    sess.local_peer,
+                    sess.destination
+                );
+                tokio::select! {
+                    res = relay_udp_over_stream(local_stream, socket, UDP_SESSION_TIMEOUT) => if let Err(err) = res {
+                        debug!("error when relay udp over stream {}", err);
+                    },
+                    _ = guard.closed() => {}
+                }
+                return;
+            }
         };
-        let mut remote_stream =
-            match TcpOutboundHandlerTrait::handle(tcp.as_ref(), self.ctx.clone(), sess).await {
-                Ok(res) => res,
+        // start pipe
+        trace!(
+            "connection established. {} => {} => tunnel => {}. Final destination: {}",
+            sess.peer_address,
+            sess.local_peer,
+            outbound_handler.tag,
+            sess.destination
+        );
+        tokio::select! {
+            res = tokio::io::copy_bidirectional(&mut local_stream, &mut remote_stream) => match res {
                 Err(err) => {
+                    debug!("error when in copy bidirectional {}", err);
+                }
+                _ => {}
+            },
+            _ = guard.closed() => {
+                debug!("connection to {} closed by outbound {}", sess.destination, outbound_handler.tag);
+            }
+        }
+    }
+
+    // 依次尝试 route 的 target 和 fallback，总时间不超过 connect_timeout
+    // 同时返回经过的 selector 的通知，selector 切换成员时关闭连接
+    // datagram 为 true 时只使用有 udp handler 的 outbound，不会返回 Connected::Stream
+    async fn connect(&self, route: &Route, sess: &Session, datagram: bool) -> Result<(Arc<OutboundHandler>, Vec<Arc<Notify>>, Connected), ConnectError> {
+        let deadline = Instant::now() + route.connect_timeout;
+        let mut last_error = ConnectError::General;
+        for tag in route.targets() {
+            let (outbound_handler, close) = match self.outbound_manager.resolve(tag, sess) {
+                Some(x) => x,
+                None => {
+                    error!("no outbound tag found {}", tag);
+                    continue;
+                }
+            };
+            let res = match &outbound_handler.udp_handler {
+                Some(udp) if sess.network == Network::UDP => {
+                    let connect = UdpOutboundHandlerTrait::handle(udp.as_ref(), self.ctx.clone(), sess);
+                    tokio::time::timeout_at(deadline, connect).await.map(|x| x.map(Connected::Datagram))
+                }
+                _ if datagram => {
+                    error!("tag {} not have udp handler !", outbound_handler.tag);
+                    continue;
+                }
+                _ => {
+                    let connect = outbound_handler.connect_tcp(self.ctx.clone(), sess);
+                    tokio::time::timeout_at(deadline, connect).await.map(|x| x.map(Connected::Stream))
+                }
+            };
+            match res {
+                Ok(Ok(connected)) => return Ok((outbound_handler, close, connected)),
+                Ok(Err(err)) => {
                     debug!(
-                        "Error {}, destination: {}. connection {} => {} => tunnel",
+                        "Error {}, destination: {}. connection {} => {} => tunnel => {}",
                         err,
                         sess.destination,
                         sess.peer_address,
                         sess.local_peer,
+                        outbound_handler.tag,
                     );
-                    return;
+                    last_error = ConnectError::from(&err);
+                }
+                Err(_) => {
+                    debug!(
+                        "connect to {} timeout after {:?}, last outbound {}",
+                        sess.destination,
+                        route.connect_timeout,
+                        outbound_handler.tag,
+                    );
+                    return Err(ConnectError::TimedOut);
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // 每个 peer 对应一个 outbound socket，空闲超过 UDP_SESSION_TIMEOUT 后回收
+    // 新 peer 的 outbound 在单独的 task 中建立，慢的 DNS 或 dial 不会阻塞其他 peer
+    pub async fn dispatch_udp(self: &Arc<Self>, socket: UdpSocket, sess: Session) {
+        let socket = Arc::new(socket);
+        let nat: UdpNat<SocketAddr> = Arc::new(Mutex::new(HashMap::new()));
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let (n, peer) = match socket.recv_from(&mut buf).await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("udp recv from failed {}", err);
+                    continue;
                 }
             };
-        // start pipe
-        let local_addr = match remote_stream.local_addr() {
-            Ok(res) => res.to_string(),
-            Err(err) => {
-                debug!("{}", err);
-                "unknown".to_string()
+            let packet = match send_to_session(&nat, &peer, buf[..n].to_vec()).await {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut sess = sess.clone();
+            sess.peer_address = peer;
+            let rx = new_session(&nat, peer, packet).await;
+            tokio::spawn(self.clone().relay_udp(sess, rx, socket.clone(), nat.clone(), peer));
+        }
+    }
+
+    // TPROXY，每个 datagram 的 destination 都不同
+    // 回包需要从伪造的 destination 地址发出，所以每个 (peer, destination) 都有单独的 reply socket
+    #[cfg(target_os = "linux")]
+    pub async fn dispatch_transparent_udp(self: &Arc<Self>, socket: UdpSocket, sess: Session) {
+        let nat: UdpNat<(SocketAddr, SocketAddr)> = Arc::new(Mutex::new(HashMap::new()));
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            let (n, peer, destination) = match recv_from_with_destination(&socket, &mut buf).await {
+                Ok(x) => x,
+                // 包括缺少 IP_RECVORIGDSTADDR cmsg 的 datagram，丢弃后继续接收
+                Err(err) => {
+                    error!("transparent udp recv from failed {}", err);
+                    continue;
+                }
+            };
+            let key = (peer, destination);
+            let packet = match send_to_session(&nat, &key, buf[..n].to_vec()).await {
+                Some(x) => x,
+                None => continue,
+            };
+            let reply_socket = match create_transparent_udp_socket(destination, false) {
+                Ok(s) => Arc::new(s),
+                Err(err) => {
+                    error!("failed to bind transparent reply socket at {} {}", destination, err);
+                    continue;
+                }
+            };
+            let mut sess = sess.clone();
+            sess.peer_address = peer;
+            sess.destination = Address::Ip(destination);
+            let rx = new_session(&nat, key, packet).await;
+            tokio::spawn(self.clone().relay_udp(sess, rx, reply_socket, nat.clone(), key));
+        }
+    }
+
+    // 建立 outbound socket 后，把 rx 中 peer 发来的 packet 发给 remote，remote 的回包通过 reply_socket 发回 peer
+    async fn relay_udp<K>(
+        self: Arc<Self>,
+        sess: Session,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        reply_socket: Arc<UdpSocket>,
+        nat: UdpNat<K>,
+        key: K,
+    ) where
+        K: Eq + Hash,
+    {
+        if let Some((remote_socket, guard)) = self.connect_udp(&sess).await {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            loop {
+                tokio::select! {
+                    _ = guard.closed() => {
+                        debug!("udp session of {} closed by outbound", sess.peer_address);
+                        break;
+                    }
+                    packet = rx.recv() => {
+                        let packet = match packet {
+                            Some(x) => x,
+                            None => break,
+                        };
+                        if let Err(err) = remote_socket.send(&packet).await {
+                            debug!("udp send to {} failed {}", sess.destination, err);
+                        }
+                    }
+                    res = tokio::time::timeout(UDP_SESSION_TIMEOUT, remote_socket.recv(&mut buf)) => {
+                        let n = match res {
+                            Ok(Ok(n)) => n,
+                            Ok(Err(err)) => {
+                                debug!("udp recv from remote failed {}", err);
+                                break;
+                            }
+                            Err(_) => {
+                                trace!("udp session of {} timeout", sess.peer_address);
+                                break;
+                            }
+                        };
+                        if let Err(err) = reply_socket.send_to(&buf[..n], sess.peer_address).await {
+                            debug!("udp send to {} failed {}", sess.peer_address, err);
+                            break;
+                        }
+                    }
+                }
             }
-        };
-        let peer_addr = match remote_stream.peer_addr() {
-            Ok(res) => res.to_string(),
-            Err(err) => {
-                debug!("{}", err);
-                "unknown".to_string()
+        }
+        // 关闭 channel 后再移除，避免移除同一个 key 上新建立的 session
+        rx.close();
+        let mut nat = nat.lock().await;
+        if nat.get(&key).map(|tx| tx.is_closed()).unwrap_or(false) {
+            nat.remove(&key);
+        }
+    }
+
+    // 与 tcp 相同，依次尝试 target 和 fallback，session 结束前持有返回的 guard
+    async fn connect_udp(&self, sess: &Session) -> Option<(UdpSocket, ConnectionGuard)> {
+        let route = match self.router.find(sess) {
+            Some(route) => route,
+            None => {
+                error!("no outbound session {:?} found!", sess);
+                return None;
             }
         };
-        trace!(
-            "connection established. {} => {} => tunnel => {} => {}. Final destination: {}",
-            sess.peer_address,
-            sess.local_peer,
-            local_addr,
-            peer_addr,
-            sess.destination
-        );
-        match tokio::io::copy_bidirectional(&mut local_stream, &mut remote_stream).await {
+        match self.connect(&route, sess, true).await {
+            Ok((outbound_handler, close, Connected::Datagram(socket))) => {
+                trace!(
+                    "udp session established. {} => {} => tunnel => {}. Final destination: {}",
+                    sess.peer_address,
+                    sess.local_peer,
+                    outbound_handler.tag,
+                    sess.destination
+                );
+                Some((socket, outbound_handler.track_connection(close)))
+            }
+            Ok((_, _, Connected::Stream(_))) => None,
             Err(err) => {
-                debug!("error when in copy bidirectional {}", err);
+                debug!(
+                    "Error {:?}, destination: {}. udp {} => {} => tunnel",
+                    err,
+                    sess.destination,
+                    sess.peer_address,
+                    sess.local_peer,
+                );
+                None
             }
-            _ => {}
-        };
+        }
     }
 
-    pub async fn dispatch_udp(&self, _socket: UdpSocket, _sess: Session) {}
-
     pub fn new(
         context: Arc<Context>,
         router: Arc<Router>,
@@ -132,3 +356,61 @@ impl Dispatcher {
         }
     }
 }
+
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 每个 session 等待发送的 packet 数，超过后丢弃
+const UDP_SESSION_QUEUE_SIZE: usize = 64;
+
+// key 到 session task 的映射
+type UdpNat<K> = Arc<Mutex<HashMap<K, mpsc::Sender<Vec<u8>>>>>;
+
+// 交给 key 已有的 session，返回 None；没有 session 或 session 已经结束时返回 packet
+async fn send_to_session<K>(nat: &UdpNat<K>, key: &K, packet: Vec<u8>) -> Option<Vec<u8>>
+where
+    K: Eq + Hash,
+{
+    let tx = match nat.lock().await.get(key).cloned() {
+        Some(x) => x,
+        None => return Some(packet),
+    };
+    match tx.try_send(packet) {
+        Ok(_) => None,
+        Err(TrySendError::Full(_)) => {
+            debug!("udp session queue full, packet dropped");
+            None
+        }
+        Err(TrySendError::Closed(packet)) => Some(packet),
+    }
+}
+
+async fn new_session<K>(nat: &UdpNat<K>, key: K, packet: Vec<u8>) -> mpsc::Receiver<Vec<u8>>
+where
+    K: Eq + Hash,
+{
+    let (tx, rx) = mpsc::channel(UDP_SESSION_QUEUE_SIZE);
+    let _ = tx.try_send(packet);
+    nat.lock().await.insert(key, tx);
+    rx
+}
+
+enum Connected {
+    Stream(AnyStream),
+    // UDP over stream
+    Datagram(UdpSocket),
+}
+
+// 返回 false 表示 client 已经断开
+async fn send_reply(reply: &Option<AnyDeferredReply>, stream: &mut AnyStream, result: Result<(), ConnectError>) -> bool {
+    let reply = match reply {
+        Some(x) => x,
+        None => return true,
+    };
+    match reply.reply(stream, result).await {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("failed to reply inbound client {}", err);
+            false
+        }
+    }
+}
@@ -6,7 +6,7 @@ use futures_util::{
 use log::trace;
 use rand::{Rng, SeedableRng};
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     vec,
 };
@@ -17,9 +17,11 @@ use trust_dns_proto::{
     serialize::binary::{BinDecodable, BinEncodable},
 };
 
+use tokio::net::UdpSocket;
+
 use crate::{
     config::{Config, GeneralSettings},
-    proxy::create_bounded_udp_socket,
+    net::SocketOpts,
 };
 
 macro_rules! random_get {
@@ -61,10 +63,10 @@ impl DnsClient {
             config: config,
         }
     }
-    pub fn new_query(host: &String, ty: RecordType) -> Message {
+    pub fn new_query(host: &str, ty: RecordType) -> Message {
         let mut message = Message::new();
         let mut query = Query::new();
-        let name = Name::from_str(&*host).expect("wrong host!");
+        let name = Name::from_str(host).expect("wrong host!");
         let mut random_generator = rand::rngs::StdRng::from_entropy();
         let random = random_generator.gen();
         query.set_name(name).set_query_type(ty);
@@ -83,48 +85,39 @@ impl DnsClient {
             use_ipv6,
             ..
         } = self.config.general;
+        let server = random_get!(self.remote_dns_servers);
         let mut tasks: Vec<BoxFuture<Result<Vec<IpAddr>>>> = Vec::new();
-        match (use_ipv6, prefer_ipv6) {
-            (true, true) => {
-                // only wait ipv6 result
-                let query = DnsClient::new_query(host, RecordType::AAAA);
-                let server = random_get!(self.remote_dns_servers);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-            }
-            (true, false) => {
-                // wait the first result
-                let server = random_get!(self.remote_dns_servers);
-                let query = DnsClient::new_query(&host, RecordType::A);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-                let query = DnsClient::new_query(&host, RecordType::AAAA);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
-            }
-            (false, ..) => {
-                // don't use ipv6
-                // just use ipv4
-                let server = random_get!(self.remote_dns_servers);
-                let query = DnsClient::new_query(&host, RecordType::A);
-                let v = query.to_vec()?;
-                let task = DnsClient::do_lookup(v, &*host, server).boxed();
-                tasks.push(task);
+        // prefer_ipv6 时 AAAA 的结果排在前面
+        let mut types = vec![RecordType::A];
+        if use_ipv6 {
+            if prefer_ipv6 {
+                types.insert(0, RecordType::AAAA);
+            } else {
+                types.push(RecordType::AAAA);
             }
-        };
+        }
+        for ty in types {
+            let query = DnsClient::new_query(host, ty);
+            let v = query.to_vec()?;
+            let task = DnsClient::do_lookup(v, host, server).boxed();
+            tasks.push(task);
+        }
+        // 只要有一个地址族查询成功即可，部分域名的 AAAA 查询会失败
         let mut ips = Vec::new();
-        for mut res in future::join_all(tasks).await {
+        let mut last_err = None;
+        for res in future::join_all(tasks).await {
             match res {
-                Ok(ref mut x) => {
-                    ips.append(x);
+                Ok(mut x) => ips.append(&mut x),
+                Err(err) => {
+                    trace!("lookup {} failed {}", host, err);
+                    last_err = Some(err);
                 }
-                Err(err) => return Err(anyhow!("lookup failed error {}", err)),
             }
         }
-        Ok(ips)
+        match last_err {
+            Some(err) if ips.is_empty() => Err(anyhow!("lookup failed error {}", err)),
+            _ => Ok(ips),
+        }
     }
     pub async fn do_lookup(
         request: Vec<u8>,
@@ -132,18 +125,8 @@ impl DnsClient {
         server: &SocketAddr,
     ) -> Result<Vec<IpAddr>> {
         trace!("lookup {} on DNS server {}", host, &server);
-        let socket = match server {
-            SocketAddr::V4(_v4) => {
-                // let bind_addr = get_default_ipv4_gateway()?;
-                let bind_addr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
-                create_bounded_udp_socket(bind_addr)?
-            }
-            SocketAddr::V6(_v6) => {
-                // let bind_addr = get_default_ipv6_gateway()?;
-                let bind_addr = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0));
-                create_bounded_udp_socket(bind_addr)?
-            }
-        };
+        // tun 接管默认路由时与 outbound 一样绑定物理网卡，回环地址的 DNS server 除外
+        let socket = UdpSocket::from_std(SocketOpts::default().udp_socket(*server)?)?;
         match socket.send_to(&*request, server).await {
             Ok(..) => {
                 let mut buf = vec![0u8; 512];
@@ -0,0 +1,654 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
+use futures::future::join_all;
+use log::{debug, info, warn};
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Notify,
+    time::timeout,
+};
+
+use crate::{
+    config::{GroupOutboundSettings, HealthCheckSettings, Outbound},
+    proxy::{Address, Network, Session},
+    Context,
+};
+
+use super::OutboundManager;
+
+enum Strategy {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    // 同一个 host 总是使用同一个成员
+    ConsistentHash,
+    // 第一个可用的成员
+    Fallback,
+    // 延迟最低的成员
+    UrlTest,
+    // 通过控制 API 手动选择，默认第一个
+    Selector,
+}
+
+// 每个成员保留的延迟记录数
+const MAX_HISTORY: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct Latency {
+    pub time: SystemTime,
+    // 失败时为 None
+    pub delay: Option<Duration>,
+}
+
+struct HealthCheck {
+    http: bool,
+    destination: Address,
+    // http 请求的 path
+    path: String,
+    interval: Duration,
+    timeout: Duration,
+    failures: u32,
+    successes: u32,
+}
+
+impl HealthCheck {
+    fn new(settings: &HealthCheckSettings) -> Result<Self> {
+        let http = match settings.check_type.as_deref().unwrap_or("http") {
+            "http" => true,
+            "tcp" => false,
+            x => bail!("unsupported health check type {}", x),
+        };
+        let url = settings.url.as_deref().unwrap_or("http://www.gstatic.com/generate_204");
+        let uri: http::Uri = url.parse()?;
+        if uri.scheme_str() != Some("http") {
+            bail!("only http health check url supported {}", url);
+        }
+        let host = uri.host().ok_or_else(|| anyhow!("no host in health check url {}", url))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        let destination = Address::try_from((host, uri.port_u16().unwrap_or(80)))?;
+        Ok(HealthCheck {
+            http,
+            destination,
+            path: uri.path_and_query().map(|x| x.as_str()).unwrap_or("/").to_string(),
+            interval: Duration::from_secs(settings.interval.unwrap_or(60).max(1)),
+            timeout: Duration::from_secs(settings.timeout.unwrap_or(5).max(1)),
+            failures: settings.failures.unwrap_or(3).max(1),
+            successes: settings.successes.unwrap_or(1).max(1),
+        })
+    }
+
+    // 通过 outbound 探测，返回耗时
+    async fn probe(&self, manager: &OutboundManager, ctx: Arc<Context>, member: &str) -> Result<Duration> {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let sess = Session {
+            destination: self.destination.clone(),
+            local_peer: unspecified,
+            peer_address: unspecified,
+            network: Network::TCP,
+        };
+        let handler = manager
+            .get_handler(member, &sess)
+            .ok_or_else(|| anyhow!("outbound {} not found", member))?;
+        let start = Instant::now();
+        let probe = async {
+            let mut stream = handler.connect_tcp(ctx, &sess).await?;
+            if !self.http {
+                return Ok(());
+            }
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: tunnel\r\nConnection: close\r\n\r\n",
+                self.path,
+                self.destination.host()
+            );
+            stream.write_all(request.as_bytes()).await?;
+            // 只需要 status line
+            let mut response = Vec::new();
+            let mut buf = [0u8; 256];
+            while !response.windows(2).any(|x| x == b"\r\n") {
+                let n = stream.read(&mut buf).await?;
+                if n == 0 || response.len() > 1024 {
+                    bail!("bad http response");
+                }
+                response.extend_from_slice(&buf[..n]);
+            }
+            let status_line = String::from_utf8_lossy(&response);
+            match status_line.split_whitespace().nth(1) {
+                Some("204") => Ok(()),
+                status => bail!("unexpected http status {:?}", status),
+            }
+        };
+        match timeout(self.timeout, probe).await {
+            Ok(Ok(_)) => Ok(start.elapsed()),
+            Ok(Err(err)) => Err(err),
+            Err(_) => bail!("timeout"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemberHealth {
+    down: bool,
+    // 连续失败或连续成功的次数
+    failures: u32,
+    successes: u32,
+}
+
+// 引用其他 outbound 的 group，由 OutboundManager::get_handler 为每个连接选择成员
+pub struct OutboundGroup {
+    pub tag: String,
+    // load-balance, fallback, url-test, selector
+    pub kind: String,
+    pub members: Vec<String>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    health_check: Option<HealthCheck>,
+    // 没有记录的成员视为可用
+    health: StdMutex<HashMap<String, MemberHealth>>,
+    history: StdMutex<HashMap<String, VecDeque<Latency>>>,
+    tolerance: Duration,
+    // url-test 和 selector 当前使用的成员
+    selected: StdMutex<Option<String>>,
+    // selector 切换成员时通知经过该 selector 的连接关闭
+    pub close: Arc<Notify>,
+}
+
+impl OutboundGroup {
+    pub fn is_group(protocol: &str) -> bool {
+        matches!(protocol, "load-balance" | "fallback" | "url-test" | "selector")
+    }
+
+    pub fn new(outbound: &Outbound) -> Result<Self> {
+        let settings = outbound
+            .settings
+            .as_ref()
+            .ok_or_else(|| anyhow!("no group settings found"))?;
+        let settings: GroupOutboundSettings = serde_json::from_str(settings.get())?;
+        let strategy = match &*outbound.protocol {
+            "fallback" => Strategy::Fallback,
+            "url-test" => Strategy::UrlTest,
+            "selector" => Strategy::Selector,
+            _ => match settings.strategy.as_deref().unwrap_or("random") {
+                "random" => Strategy::Random,
+                "round-robin" => Strategy::RoundRobin,
+                "least-connections" => Strategy::LeastConnections,
+                "consistent-hash" => Strategy::ConsistentHash,
+                x => bail!("unsupported group strategy {}", x),
+            },
+        };
+        let health_check = match (&settings.health_check, &strategy) {
+            (Some(x), _) => Some(HealthCheck::new(x)?),
+            (None, Strategy::Fallback | Strategy::UrlTest) => Some(HealthCheck::new(&HealthCheckSettings::default())?),
+            (None, _) => None,
+        };
+        Ok(OutboundGroup {
+            tag: outbound.tag.clone(),
+            kind: outbound.protocol.clone(),
+            members: settings.outbounds,
+            strategy,
+            next: AtomicUsize::new(0),
+            health_check,
+            health: StdMutex::new(HashMap::new()),
+            history: StdMutex::new(HashMap::new()),
+            tolerance: Duration::from_millis(settings.tolerance.unwrap_or(50)),
+            selected: StdMutex::new(None),
+            close: Arc::new(Notify::new()),
+        })
+    }
+
+    pub fn is_up(&self, member: &str) -> bool {
+        !matches!(self.health.lock().unwrap().get(member), Some(x) if x.down)
+    }
+
+    pub fn select(&self, manager: &OutboundManager, sess: &Session) -> Option<&str> {
+        // 全部不可用时仍然从所有成员中选择
+        let mut members: Vec<&str> = self.members.iter().filter(|x| self.is_up(x)).map(|x| x.as_str()).collect();
+        if members.is_empty() {
+            members = self.members.iter().map(|x| x.as_str()).collect();
+        }
+        let len = members.len();
+        if len == 0 {
+            return None;
+        }
+        let index = match self.strategy {
+            Strategy::Random => rand::thread_rng().gen_range(0..len),
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+            Strategy::LeastConnections => {
+                // 连接数相同时轮流选择
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..len)
+                    .map(|i| (start + i) % len)
+                    .min_by_key(|i| manager.connections(members[*i]))?
+            }
+            Strategy::ConsistentHash => {
+                let host = match &sess.destination {
+                    Address::Domain(name, _) => name.clone(),
+                    Address::Ip(addr) => addr.ip().to_string(),
+                };
+                // rendezvous hashing，成员变化时只影响该成员上的 host
+                (0..len).max_by_key(|i| hash_member(&host, members[*i]))?
+            }
+            Strategy::Fallback => 0,
+            Strategy::UrlTest | Strategy::Selector => {
+                let selected = self.selected.lock().unwrap();
+                members.iter().position(|x| Some(*x) == selected.as_deref()).unwrap_or(0)
+            }
+        };
+        Some(members[index])
+    }
+
+    // 连续失败 failures 次标记为不可用，不可用时连续成功 successes 次后恢复
+    fn report(&self, member: &str, result: &Result<Duration>) {
+        let check = match &self.health_check {
+            Some(x) => x,
+            None => return,
+        };
+        let mut history = self.history.lock().unwrap();
+        let history = history.entry(member.to_string()).or_default();
+        if history.len() >= MAX_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(Latency {
+            time: SystemTime::now(),
+            delay: result.as_ref().ok().copied(),
+        });
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(member.to_string()).or_default();
+        match result {
+            Ok(rtt) => {
+                debug!("health check of {} in group {} ok {:?}", member, self.tag, rtt);
+                health.failures = 0;
+                health.successes += 1;
+                if health.down && health.successes >= check.successes {
+                    health.down = false;
+                    info!("outbound {} in group {} is up", member, self.tag);
+                }
+            }
+            Err(err) => {
+                debug!("health check of {} in group {} failed {}", member, self.tag, err);
+                health.successes = 0;
+                health.failures += 1;
+                if !health.down && health.failures >= check.failures {
+                    health.down = true;
+                    warn!("outbound {} in group {} is down", member, self.tag);
+                }
+            }
+        }
+    }
+
+    // 并发探测所有成员
+    pub async fn check_health(&self, manager: &OutboundManager, ctx: Arc<Context>) {
+        let check = match &self.health_check {
+            Some(x) => x,
+            None => return,
+        };
+        let results = join_all(self.members.iter().map(|x| check.probe(manager, ctx.clone(), x))).await;
+        for (member, result) in self.members.iter().zip(results) {
+            self.report(member, &result);
+        }
+        if let Strategy::UrlTest = self.strategy {
+            self.update_fastest();
+        }
+    }
+
+    pub fn is_selector(&self) -> bool {
+        matches!(self.strategy, Strategy::Selector)
+    }
+
+    pub fn selected(&self) -> Option<String> {
+        self.selected.lock().unwrap().clone()
+    }
+
+    // 切换 selector 的成员，返回之前的成员
+    pub fn set_selected(&self, member: &str) -> Result<Option<String>> {
+        if !self.is_selector() {
+            bail!("outbound group {} is not a selector", self.tag);
+        }
+        if !self.members.iter().any(|x| x == member) {
+            bail!("{} is not a member of outbound group {}", member, self.tag);
+        }
+        let previous = self.selected.lock().unwrap().replace(member.to_string());
+        Ok(previous.or_else(|| self.members.first().cloned()))
+    }
+
+    // 控制 API 返回的状态
+    pub fn to_json(&self) -> serde_json::Value {
+        let history = self.latency_history();
+        let members: Vec<serde_json::Value> = self
+            .members
+            .iter()
+            .map(|member| {
+                let history: Vec<serde_json::Value> = history
+                    .get(member)
+                    .map(|x| x.as_slice())
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|x| {
+                        let time = x.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                        serde_json::json!({
+                            "time": time.as_millis() as u64,
+                            "delay": x.delay.map(|x| x.as_millis() as u64),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "name": member,
+                    "up": self.is_up(member),
+                    "history": history,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "tag": self.tag,
+            "type": self.kind,
+            "selected": self.selected(),
+            "members": members,
+        })
+    }
+
+    // 每个成员最近的延迟记录，按时间排序
+    pub fn latency_history(&self) -> HashMap<String, Vec<Latency>> {
+        let history = self.history.lock().unwrap();
+        history.iter().map(|(k, v)| (k.clone(), v.iter().cloned().collect())).collect()
+    }
+
+    // 当前成员比最快的成员慢 tolerance 以上时才切换，避免频繁切换
+    fn update_fastest(&self) {
+        let history = self.history.lock().unwrap();
+        let latest = |member: &str| history.get(member).and_then(|x| x.back()).and_then(|x| x.delay);
+        let (fastest, fastest_delay) = match self
+            .members
+            .iter()
+            .filter(|x| self.is_up(x))
+            .filter_map(|x| latest(x).map(|delay| (x, delay)))
+            .min_by_key(|x| x.1)
+        {
+            Some(x) => x,
+            None => return,
+        };
+        let mut selected = self.selected.lock().unwrap();
+        if let Some(current) = selected.as_deref() {
+            if let Some(delay) = latest(current) {
+                if self.is_up(current) && delay <= fastest_delay + self.tolerance {
+                    return;
+                }
+            }
+        }
+        info!("group {} select {} {:?}", self.tag, fastest, fastest_delay);
+        *selected = Some(fastest.clone());
+    }
+
+    pub async fn run_health_check(self: Arc<Self>, manager: Arc<OutboundManager>, ctx: Arc<Context>) {
+        let interval = match &self.health_check {
+            Some(x) => x.interval,
+            None => return,
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.check_health(&manager, ctx.clone()).await;
+        }
+    }
+}
+
+fn hash_member(host: &str, member: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    host.hash(&mut hasher);
+    member.hash(&mut hasher);
+    hasher.finish()
+}
+
+// group 之间的引用不能成环
+pub fn check_cycles(groups: &HashMap<String, Arc<OutboundGroup>>) -> Result<()> {
+    fn visit<'a>(
+        tag: &'a str,
+        groups: &'a HashMap<String, Arc<OutboundGroup>>,
+        done: &mut Vec<&'a str>,
+        path: &mut Vec<&'a str>,
+    ) -> Result<()> {
+        if done.contains(&tag) {
+            return Ok(());
+        }
+        let group = match groups.get(tag) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if path.contains(&tag) {
+            path.push(tag);
+            bail!("outbound group cycle {}", path.join(" => "));
+        }
+        path.push(tag);
+        for member in group.members.iter() {
+            visit(member, groups, done, path)?;
+        }
+        path.pop();
+        done.push(tag);
+        Ok(())
+    }
+    let mut tags: Vec<&String> = groups.keys().collect();
+    tags.sort();
+    let mut done = Vec::new();
+    for tag in tags {
+        visit(tag, groups, &mut done, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn load_balance_test() {
+    use std::net::SocketAddr;
+
+    use crate::proxy::Network;
+
+    let outbounds: Vec<Outbound> = serde_json::from_str(
+        r#"[
+        {"protocol": "direct", "tag": "a"},
+        {"protocol": "direct", "tag": "b"},
+        {"protocol": "direct", "tag": "c"},
+        {"protocol": "load-balance", "tag": "rr", "settings": {"outbounds": ["a", "b", "unknown", "c"], "strategy": "round-robin"}},
+        {"protocol": "load-balance", "tag": "least", "settings": {"outbounds": ["a", "b", "c"], "strategy": "least-connections"}},
+        {"protocol": "load-balance", "tag": "hash", "settings": {"outbounds": ["a", "b", "c"], "strategy": "consistent-hash"}},
+        {"protocol": "load-balance", "tag": "nested", "settings": {"outbounds": ["rr"]}}
+    ]"#,
+    )
+    .unwrap();
+    let manager = OutboundManager::new(outbounds).unwrap();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let session = |host: &str| Session {
+        destination: Address::Domain(host.to_string(), 443),
+        local_peer: addr,
+        peer_address: addr,
+        network: Network::TCP,
+    };
+    let sess = session("example.com");
+    let select = |tag: &str, sess: &Session| manager.get_handler(tag, sess).unwrap().tag.clone();
+
+    // 不存在的成员被忽略
+    let tags: Vec<String> = (0..4).map(|_| select("rr", &sess)).collect();
+    assert_eq!(tags, vec!["a", "b", "c", "a"]);
+    assert_eq!(select("nested", &sess), "b");
+
+    let a = manager.get_handler("a", &sess).unwrap();
+    let b = manager.get_handler("b", &sess).unwrap();
+    let guards = vec![a.track_connection(Vec::new()), a.track_connection(Vec::new()), b.track_connection(Vec::new())];
+    assert_eq!(manager.connections("least"), 3);
+    for _ in 0..3 {
+        assert_eq!(select("least", &sess), "c");
+    }
+    drop(guards);
+    assert_eq!(manager.connections("least"), 0);
+
+    // 同一个 host 总是选择同一个成员
+    let hosts: Vec<String> = (0..32).map(|i| format!("host{}.com", i)).collect();
+    let first: Vec<String> = hosts.iter().map(|x| select("hash", &session(x))).collect();
+    let second: Vec<String> = hosts.iter().map(|x| select("hash", &session(x))).collect();
+    assert_eq!(first, second);
+    assert!(first.iter().any(|x| x != &first[0]));
+
+    let outbounds: Vec<Outbound> = serde_json::from_str(
+        r#"[
+        {"protocol": "load-balance", "tag": "x", "settings": {"outbounds": ["y"]}},
+        {"protocol": "load-balance", "tag": "y", "settings": {"outbounds": ["x"]}}
+    ]"#,
+    )
+    .unwrap();
+    let err = OutboundManager::new(outbounds).err().unwrap();
+    assert_eq!(err.to_string(), "outbound group cycle x => y => x");
+}
+
+#[tokio::test]
+async fn fallback_test() {
+    use tokio::{net::TcpListener, sync::RwLock};
+
+    use crate::{app::DnsClient, config::Config};
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    // 只有 /generate_204 返回 204
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                let response: &[u8] = if buf[..n].starts_with(b"GET /generate_204 ") {
+                    b"HTTP/1.1 204 No Content\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\n\r\n"
+                };
+                stream.write_all(response).await.unwrap();
+            });
+        }
+    });
+    let config = format!(
+        r#"[
+        {{"protocol": "socks", "tag": "bad", "settings": {{"address": "127.0.0.1", "port": 1}}}},
+        {{"protocol": "direct", "tag": "good"}},
+        {{"protocol": "fallback", "tag": "http", "settings": {{"outbounds": ["bad", "good"],
+            "healthCheck": {{"url": "http://127.0.0.1:{0}/generate_204", "failures": 2, "successes": 2}}}}}},
+        {{"protocol": "fallback", "tag": "tcp", "settings": {{"outbounds": ["bad", "good"],
+            "healthCheck": {{"type": "tcp", "url": "http://127.0.0.1:{0}/", "failures": 1}}}}}},
+        {{"protocol": "fallback", "tag": "404", "settings": {{"outbounds": ["bad", "good"],
+            "healthCheck": {{"url": "http://127.0.0.1:{0}/unknown", "failures": 1}}}}}}
+    ]"#,
+        port
+    );
+    let outbounds: Vec<Outbound> = serde_json::from_str(&config).unwrap();
+    let manager = OutboundManager::new(outbounds).unwrap();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let sess = Session {
+        destination: Address::Ip(addr),
+        local_peer: addr,
+        peer_address: addr,
+        network: Network::TCP,
+    };
+    let select = |tag: &str| manager.get_handler(tag, &sess).unwrap().tag.clone();
+
+    // 检查之前使用第一个成员
+    assert_eq!(select("http"), "bad");
+    let group = manager.get_group("http").unwrap();
+    group.check_health(&manager, ctx.clone()).await;
+    assert_eq!(select("http"), "bad");
+    group.check_health(&manager, ctx.clone()).await;
+    assert!(!group.is_up("bad"));
+    assert_eq!(select("http"), "good");
+    // 连续成功 2 次后恢复
+    group.report("bad", &Ok(Duration::from_millis(1)));
+    assert!(!group.is_up("bad"));
+    group.report("bad", &Ok(Duration::from_millis(1)));
+    assert_eq!(select("http"), "bad");
+
+    let group = manager.get_group("tcp").unwrap();
+    group.check_health(&manager, ctx.clone()).await;
+    assert_eq!(select("tcp"), "good");
+
+    // 全部不可用时使用第一个成员
+    let group = manager.get_group("404").unwrap();
+    group.check_health(&manager, ctx.clone()).await;
+    assert!(!group.is_up("good"));
+    assert_eq!(select("404"), "bad");
+}
+
+#[tokio::test]
+async fn url_test_test() {
+    use tokio::{net::TcpListener, sync::RwLock};
+
+    use crate::{app::DnsClient, config::Config};
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            });
+        }
+    });
+    let config = format!(
+        r#"[
+        {{"protocol": "direct", "tag": "a"}},
+        {{"protocol": "direct", "tag": "b"}},
+        {{"protocol": "url-test", "tag": "auto", "settings": {{"outbounds": ["a", "b"], "tolerance": 50,
+            "healthCheck": {{"url": "http://127.0.0.1:{}/generate_204", "failures": 1}}}}}}
+    ]"#,
+        port
+    );
+    let outbounds: Vec<Outbound> = serde_json::from_str(&config).unwrap();
+    let manager = OutboundManager::new(outbounds).unwrap();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let sess = Session {
+        destination: Address::Ip(addr),
+        local_peer: addr,
+        peer_address: addr,
+        network: Network::TCP,
+    };
+    let select = |tag: &str| manager.get_handler(tag, &sess).unwrap().tag.clone();
+    let group = manager.get_group("auto").unwrap();
+    assert_eq!(select("auto"), "a");
+
+    group.check_health(&manager, ctx.clone()).await;
+    let history = group.latency_history();
+    assert!(history["a"][0].delay.is_some());
+    assert!(history["b"][0].delay.is_some());
+
+    let report = |a: u64, b: u64| {
+        group.report("a", &Ok(Duration::from_millis(a)));
+        group.report("b", &Ok(Duration::from_millis(b)));
+        group.update_fastest();
+    };
+    report(100, 80);
+    assert_eq!(select("auto"), "b");
+    // 在 tolerance 之内不切换
+    report(10, 50);
+    assert_eq!(select("auto"), "b");
+    report(10, 100);
+    assert_eq!(select("auto"), "a");
+    // 当前成员不可用时切换
+    group.report("a", &Err(anyhow!("timeout")));
+    group.update_fastest();
+    assert_eq!(select("auto"), "b");
+
+    let history = group.latency_history();
+    assert_eq!(history["a"].len(), 5);
+    assert!(history["a"][4].delay.is_none());
+    for _ in 0..20 {
+        report(10, 10);
+    }
+    assert_eq!(group.latency_history()["b"].len(), MAX_HISTORY);
+}
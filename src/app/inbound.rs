@@ -2,16 +2,24 @@ use anyhow::Result;
 use futures::FutureExt;
 use futures_util::future::BoxFuture;
 use log::{error, info};
+use std::convert::TryFrom;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::{collections::HashMap, net::SocketAddr};
 
+use uuid::Uuid;
+
 use crate::{
-    config::{Inbound},
+    config::{DokodemoInboundSettings, Inbound, ShadowsocksInboundSettings, VlessInboundSettings},
     proxy::{
-        socks::{TcpInboundHandler, UdpInboundHandler}, InboundHandler,
+        dokodemo, shadowsocks, socks::{TcpInboundHandler, UdpInboundHandler}, vless, Address, InboundHandler,
     },
 };
+#[cfg(target_os = "linux")]
+use crate::{
+    config::TunInboundSettings,
+    proxy::{redirect, tproxy, tun::Tun},
+};
 
 use super::{Dispatcher, InboundListener};
 // 统一管理全部 inbound 协议
@@ -32,6 +40,100 @@ impl InboundManager {
                     let udp = Arc::new(UdpInboundHandler);
                     InboundHandler::new(inbound.tag.clone(), Some(tcp), Some(udp))
                 }
+                "vless" => {
+                    let vless_settings = match &inbound.settings {
+                        Some(settings) => match serde_json::from_str::<VlessInboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue;
+                            }
+                        },
+                        None => {
+                            error!("no vless settings found!");
+                            continue;
+                        }
+                    };
+                    let mut users = Vec::new();
+                    for client in vless_settings.clients.iter() {
+                        match Uuid::parse_str(&client.id) {
+                            Ok(x) => users.push(x),
+                            Err(err) => {
+                                error!("bad vless id {} {}", client.id, err);
+                            }
+                        }
+                    }
+                    // udp 通过 vless 的 UDP command 承载在 tcp 上
+                    let tcp = Arc::new(vless::TcpInboundHandler { users });
+                    InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+                }
+                "shadowsocks" => {
+                    let ss_settings = match &inbound.settings {
+                        Some(settings) => match serde_json::from_str::<ShadowsocksInboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue;
+                            }
+                        },
+                        None => {
+                            error!("no shadowsocks settings found!");
+                            continue;
+                        }
+                    };
+                    let key = match shadowsocks::CipherKey::new(&ss_settings.method, &ss_settings.password) {
+                        Ok(x) => x,
+                        Err(err) => {
+                            error!("bad shadowsocks settings {}", err);
+                            continue;
+                        }
+                    };
+                    // 只支持 tcp，udp relay 尚未实现
+                    let tcp = Arc::new(shadowsocks::TcpInboundHandler { key });
+                    InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+                }
+                "dokodemo-door" => {
+                    let dokodemo_settings = match &inbound.settings {
+                        Some(settings) => match serde_json::from_str::<DokodemoInboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue;
+                            }
+                        },
+                        None => {
+                            error!("no dokodemo-door settings found!");
+                            continue;
+                        }
+                    };
+                    let destination = match Address::try_from((dokodemo_settings.address.clone(), dokodemo_settings.port)) {
+                        Ok(x) => x,
+                        Err(_err) => {
+                            error!("bad dokodemo-door addr found {}:{}", dokodemo_settings.address, dokodemo_settings.port);
+                            continue;
+                        }
+                    };
+                    let tcp = Arc::new(dokodemo::TcpInboundHandler { destination: destination.clone() });
+                    let udp = Arc::new(dokodemo::UdpInboundHandler { destination });
+                    InboundHandler::new(inbound.tag.clone(), Some(tcp), Some(udp))
+                }
+                #[cfg(target_os = "linux")]
+                "redirect" => {
+                    // REDIRECT 只支持 tcp
+                    let tcp = Arc::new(redirect::TcpInboundHandler);
+                    InboundHandler::new(inbound.tag.clone(), Some(tcp), None)
+                }
+                #[cfg(target_os = "linux")]
+                "tproxy" => {
+                    let tcp = Arc::new(tproxy::TcpInboundHandler);
+                    let udp = Arc::new(tproxy::UdpInboundHandler);
+                    InboundHandler::new(inbound.tag.clone(), Some(tcp), Some(udp))
+                }
+                #[cfg(target_os = "linux")]
+                "tun" => {
+                    // tun 不经过 InboundListener，device 在 listen 时创建
+                    InboundHandler::new(inbound.tag.clone(), None, None)
+                }
                 _ => {
                     info!("unknown protocol: {} tag: {}", inbound.protocol, inbound.tag);
                     continue;
@@ -49,11 +151,37 @@ impl InboundManager {
         for config in self.configs {
             let dispatcher = dispatcher.clone();
             if let Some(handler) = self.handlers.get_mut(&config.tag) {
-                let Inbound { port, listen, protocol, .. } = config;
+                let Inbound { port, listen, protocol, settings, stream_settings, .. } = config;
                 // 除 tun 外，其他protocol都必须有port
                 let mut future = match protocol.as_str() {
+                    #[cfg(target_os = "linux")]
                     "tun" => {
-                        todo!()
+                        let tun_settings = match &settings {
+                            Some(settings) => match serde_json::from_str::<TunInboundSettings>(settings.get()) {
+                                Ok(res) => res,
+                                Err(err) => {
+                                    error!("{}", err);
+                                    continue;
+                                }
+                            },
+                            None => {
+                                error!("no tun settings found!");
+                                continue;
+                            }
+                        };
+                        let task = async move {
+                            let tun = match Tun::new(tun_settings, dispatcher).await {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    error!("failed to create tun {}", err);
+                                    return;
+                                }
+                            };
+                            if let Err(err) = tun.run().await {
+                                error!("tun exited {}", err);
+                            }
+                        }.boxed();
+                        vec![task]
                     },
                     _ => {
                         let addr = match SocketAddr::from_str(format!("{}:{}", listen.unwrap(), port.unwrap()).as_str()) {
@@ -63,7 +191,13 @@ impl InboundManager {
                                 continue;
                             }
                         };
-                        InboundListener::listen(dispatcher, handler.clone(), addr)?
+                        match protocol.as_str() {
+                            #[cfg(target_os = "linux")]
+                            "tproxy" => InboundListener::listen_transparent(dispatcher, handler.clone(), addr)?,
+                            #[cfg(target_os = "linux")]
+                            "redirect" => InboundListener::listen_redirect(dispatcher, handler.clone(), addr)?,
+                            _ => InboundListener::listen(dispatcher, handler.clone(), addr, stream_settings)?,
+                        }
                     }
                 };
                 tasks.append(&mut future);
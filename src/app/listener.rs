@@ -1,15 +1,22 @@
-use futures_util::{future::BoxFuture, FutureExt, StreamExt};
+use futures_util::{future::{join_all, BoxFuture}, FutureExt, StreamExt};
 use log::{error, info};
 use std::{io::Result, net::SocketAddr, sync::Arc};
-use tokio::{
-    net::{TcpListener, UdpSocket},
-};
+use tokio::net::UdpSocket;
 
 use crate::{
+    config::StreamSettings,
     proxy::{
         Address, AnyInboundHandler, InboundResult, Network, Session,
-        TcpInboundHandlerTrait,
+        TcpInboundHandlerTrait, UdpInboundHandlerTrait,
     },
+    transport::{self, AnyStreamListener},
+};
+
+#[cfg(target_os = "linux")]
+use crate::{
+    net::sys::linux::{create_transparent_tcp_listener, create_transparent_udp_socket},
+    proxy::redirect::RedirectListener,
+    transport::TcpStreamListener,
 };
 
 use super::dispatcher::Dispatcher;
@@ -21,6 +28,7 @@ impl InboundListener {
         dispatcher: Arc<Dispatcher>,
         handler: AnyInboundHandler,
         addr: SocketAddr,
+        stream_settings: Option<StreamSettings>,
     ) -> Result<Vec<TaskFuture>> {
         let mut tasks: Vec<TaskFuture> = vec![];
         if handler.has_tcp() {
@@ -34,7 +42,7 @@ impl InboundListener {
             // 这就要求 tcp_listener 改为 InboundListener
             // 实在不想在 listen 糅合一堆代码，我在这里采用 2
             let f =
-                InboundListener::tcp_listener(handler.clone(), dispatcher.clone(), addr);
+                InboundListener::tcp_listener(handler.clone(), dispatcher.clone(), addr, stream_settings);
             tasks.push(f);
         }
         if handler.has_udp() {
@@ -48,61 +56,164 @@ impl InboundListener {
         handler: AnyInboundHandler,
         dispatcher: Arc<Dispatcher>,
         addr: SocketAddr,
+        stream_settings: Option<StreamSettings>,
     ) -> TaskFuture {
         let task = async move {
-            let listener = TcpListener::bind(addr).await.unwrap();
+            let listeners = match transport::bind(addr, &stream_settings).await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("failed to listen at {} {}", addr, err);
+                    return;
+                }
+            };
             info!("Tcp listening at {}", addr);
-            loop {
-                match listener.accept().await {
-                    Ok((conn, _)) => {
-                        let dispatcher = Arc::clone(&dispatcher);
-                        let handler = handler.clone();
-                        tokio::spawn(async move {
-                            let addr = conn.peer_addr().expect("peer");
-                            let local = conn.local_addr().expect("local");
-                            let session = Session {
-                                destination: Address::Ip(addr),
-                                network: Network::TCP,
-                                local_peer: local,
-                                peer_address: conn.peer_addr().expect("peer")
-                            };
-                            match TcpInboundHandlerTrait::handle(&*handler, session, conn).await {
-                                Ok(InboundResult::Stream(stream, mut sess)) => {
-                                    dispatcher.dispatch_tcp(stream, &mut sess).await;
-                                }
-                                Ok(InboundResult::Datagram(socket, sess)) => {
-                                    dispatcher.dispatch_udp(socket, sess).await;
-                                }
-                                Ok(InboundResult::NOT_SUPPORTED) => {
-                                    error!("not supported");
-                                }
-                                Err(err) => {
-                                    error!("handle tcp inbound failed err {}", err);
-                                }
+            // 每个 SO_REUSEPORT listener 一个 accept 循环
+            let tasks = listeners
+                .into_iter()
+                .map(|listener| InboundListener::serve_tcp(listener, handler.clone(), dispatcher.clone()));
+            join_all(tasks).await;
+        }.boxed();
+        task
+    }
+    async fn serve_tcp(
+        listener: AnyStreamListener,
+        handler: AnyInboundHandler,
+        dispatcher: Arc<Dispatcher>,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((conn, session)) => {
+                    let dispatcher = Arc::clone(&dispatcher);
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        match TcpInboundHandlerTrait::handle(&*handler, session, conn).await {
+                            Ok(InboundResult::Stream(stream, mut sess)) => {
+                                dispatcher.dispatch_tcp(stream, &mut sess).await;
+                            }
+                            Ok(InboundResult::DeferredStream(stream, mut sess, reply)) => {
+                                dispatcher.dispatch_tcp_with_reply(stream, &mut sess, Some(reply)).await;
+                            }
+                            Ok(InboundResult::FixedStream(stream, mut sess)) => {
+                                dispatcher.dispatch_fixed_tcp(stream, &mut sess).await;
+                            }
+                            Ok(InboundResult::Datagram(socket, sess)) => {
+                                dispatcher.dispatch_udp(socket, sess).await;
                             }
-                        });
+                            Ok(InboundResult::NOT_SUPPORTED) => {
+                                error!("not supported");
+                            }
+                            Err(err) => {
+                                error!("handle tcp inbound failed err {}", err);
+                            }
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("accept error {}", err);
+                    return;
+                }
+            }
+        }
+    }
+    // TPROXY, tcp 和 udp socket 都需要 IP_TRANSPARENT
+    #[cfg(target_os = "linux")]
+    pub fn listen_transparent(
+        dispatcher: Arc<Dispatcher>,
+        handler: AnyInboundHandler,
+        addr: SocketAddr,
+    ) -> Result<Vec<TaskFuture>> {
+        let mut tasks: Vec<TaskFuture> = vec![];
+        if handler.has_tcp() {
+            let listener = Box::new(TcpStreamListener::new(create_transparent_tcp_listener(addr)?));
+            info!("Transparent tcp listening at {}", addr);
+            let f = InboundListener::serve_tcp(listener, handler.clone(), dispatcher.clone()).boxed();
+            tasks.push(f);
+        }
+        if handler.has_udp() {
+            let socket = create_transparent_udp_socket(addr, true)?;
+            info!("Transparent udp listen at {}", addr);
+            let handler = handler.clone();
+            let dispatcher = dispatcher.clone();
+            let f = async move {
+                let session = Session {
+                    destination: Address::Ip(addr),
+                    network: Network::UDP,
+                    local_peer: addr,
+                    peer_address: addr,
+                };
+                match UdpInboundHandlerTrait::handle(&*handler, session, socket).await {
+                    Ok(InboundResult::Datagram(socket, sess)) => {
+                        dispatcher.dispatch_transparent_udp(socket, sess).await;
+                    }
+                    Ok(_) => {
+                        error!("unexpected transparent udp inbound result at {}", addr);
                     }
                     Err(err) => {
-                        error!("accept error {}", err);
-                        return;
+                        error!("handle udp inbound failed err {}", err);
                     }
                 }
-
-            }
+            }.boxed();
+            tasks.push(f);
+        }
+        Ok(tasks)
+    }
+    // REDIRECT 只有 tcp，destination 在 accept 时通过 SO_ORIGINAL_DST 获取
+    #[cfg(target_os = "linux")]
+    pub fn listen_redirect(
+        dispatcher: Arc<Dispatcher>,
+        handler: AnyInboundHandler,
+        addr: SocketAddr,
+    ) -> Result<Vec<TaskFuture>> {
+        let f = async move {
+            let listener = match RedirectListener::bind(addr).await {
+                Ok(x) => Box::new(x),
+                Err(err) => {
+                    error!("failed to listen at {} {}", addr, err);
+                    return;
+                }
+            };
+            info!("Redirect tcp listening at {}", addr);
+            InboundListener::serve_tcp(listener, handler, dispatcher).await;
         }.boxed();
-        task
+        Ok(vec![f])
     }
     fn udp_listener(
-        _handler: AnyInboundHandler,
-        _dispatcher: Arc<Dispatcher>,
+        handler: AnyInboundHandler,
+        dispatcher: Arc<Dispatcher>,
         addr: SocketAddr,
     ) -> TaskFuture {
         let future = async move {
-            let _listener = UdpSocket::bind(addr).await.unwrap();
+            let socket = UdpSocket::bind(addr).await.unwrap();
             info!("Udp listen at {}", addr);
-            ()
+            // udp 是 connectionless，整个 socket 作为一个 session 交给 handler
+            // 真正的 peer 由 dispatcher 在收包时区分
+            let session = Session {
+                destination: Address::Ip(addr),
+                network: Network::UDP,
+                local_peer: addr,
+                peer_address: addr,
+            };
+            match UdpInboundHandlerTrait::handle(&*handler, session, socket).await {
+                Ok(InboundResult::Datagram(socket, sess)) => {
+                    dispatcher.dispatch_udp(socket, sess).await;
+                }
+                Ok(InboundResult::Stream(stream, mut sess)) => {
+                    dispatcher.dispatch_tcp(stream, &mut sess).await;
+                }
+                Ok(InboundResult::DeferredStream(stream, mut sess, reply)) => {
+                    dispatcher.dispatch_tcp_with_reply(stream, &mut sess, Some(reply)).await;
+                }
+                Ok(InboundResult::FixedStream(stream, mut sess)) => {
+                    dispatcher.dispatch_fixed_tcp(stream, &mut sess).await;
+                }
+                Ok(InboundResult::NOT_SUPPORTED) => {
+                    info!("udp not supported by inbound at {}", addr);
+                }
+                Err(err) => {
+                    error!("handle udp inbound failed err {}", err);
+                }
+            }
         }.boxed();
-        // todo!("udp listener")
         future
     }
 }
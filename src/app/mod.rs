@@ -15,6 +15,10 @@ pub use listener::InboundListener;
 mod inbound;
 pub use inbound::InboundManager;
 
+mod group;
+
+pub mod api;
+
 mod outbound;
 pub use outbound::OutboundManager;
 
@@ -1,23 +1,124 @@
-use std::{collections::HashMap, sync::Arc, convert::TryFrom};
+use std::{collections::{HashMap, HashSet}, sync::Arc, convert::TryFrom, fs, net::{Ipv4Addr, SocketAddr}};
 use anyhow::{
-    Result
+    anyhow, bail, Result
 };
-use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use log::{error, info, warn};
+use uuid::Uuid;
 
 use crate::{
-    config::{Outbound, Socks5OutboundSettings},
-    proxy::{socks, OutboundHandler, Address, direct},
+    net::SocketOpts,
+    config::{BlackholeOutboundSettings, Outbound, ShadowsocksOutboundSettings, Socks5OutboundSettings, VlessOutboundSettings},
+    proxy::{shadowsocks, socks, vless, OutboundHandler, Address, AnyStream, Network, Session, direct, blackhole},
+    transport::{self, AnyDialer, Dialer},
+    Context,
 };
 
+use super::group::{self, OutboundGroup};
+
+#[derive(Serialize, Deserialize)]
+struct OutboundState {
+    // selector 的 tag => 选择的成员
+    selected: HashMap<String, String>,
+}
+
+// 通过另一个 outbound 建立到 server 的 stream
+struct ChainDialer {
+    handler: Arc<OutboundHandler>,
+}
+
+#[async_trait]
+impl Dialer for ChainDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let session = Session {
+            destination: addr.clone(),
+            network: Network::TCP,
+            local_peer: unspecified,
+            peer_address: unspecified,
+        };
+        self.handler.connect_tcp(ctx, &session).await
+    }
+}
+
+// 按 dialerProxy 排序，被依赖的 outbound 先构建，存在环时报错
+fn chain_order(outbounds: &[Outbound]) -> Result<Vec<&Outbound>> {
+    let by_tag: HashMap<&str, &Outbound> = outbounds.iter().map(|x| (x.tag.as_str(), x)).collect();
+    let mut order = Vec::new();
+    let mut done: HashSet<&str> = HashSet::new();
+    for outbound in outbounds {
+        // 每个 outbound 最多依赖一个 outbound，沿着链查找
+        let mut path: Vec<&str> = Vec::new();
+        let mut current = Some(outbound);
+        while let Some(x) = current {
+            if done.contains(x.tag.as_str()) {
+                break;
+            }
+            let cycle = path.contains(&x.tag.as_str());
+            path.push(&x.tag);
+            if cycle {
+                bail!("dialer proxy cycle {}", path.join(" => "));
+            }
+            current = x.dialer_proxy.as_deref().and_then(|tag| by_tag.get(tag).copied());
+        }
+        for tag in path.into_iter().rev() {
+            if done.insert(tag) {
+                order.push(by_tag[tag]);
+            }
+        }
+    }
+    Ok(order)
+}
+
 // 管理全部的传出协议 outbound
 pub struct OutboundManager {
     pub handlers: HashMap<String, Arc<OutboundHandler>>,
+    groups: HashMap<String, Arc<OutboundGroup>>,
+    // selector 的选择保存在该文件中
+    state_file: Option<String>,
 }
 
 impl OutboundManager {
     pub fn new(outbounds: Vec<Outbound>) -> Result<OutboundManager> {
-        let mut handlers = HashMap::new();
-        for outbound in outbounds.iter() {
+        let mut handlers: HashMap<String, Arc<OutboundHandler>> = HashMap::new();
+        for outbound in chain_order(&outbounds)? {
+            // group 在所有 handler 之后构建
+            if OutboundGroup::is_group(&outbound.protocol) {
+                continue;
+            }
+            let base: Option<AnyDialer> = match &outbound.dialer_proxy {
+                Some(tag) => match handlers.get(tag) {
+                    Some(handler) => Some(Arc::new(ChainDialer { handler: handler.clone() })),
+                    // group 在 handler 之后构建，且每个连接选择的成员不同，无法作为 dialerProxy
+                    None if outbounds.iter().any(|x| &x.tag == tag && OutboundGroup::is_group(&x.protocol)) => {
+                        error!("dialer proxy {} of outbound {} is a group, only plain outbounds are supported", tag, outbound.tag);
+                        continue;
+                    }
+                    None => {
+                        error!("dialer proxy {} of outbound {} not found", tag, outbound.tag);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let opts = SocketOpts {
+                interface: outbound.interface.clone(),
+                source_address: outbound.source_address,
+                fwmark: outbound.fwmark,
+                sockopt: outbound.stream_settings.as_ref().and_then(|x| x.sockopt.clone()),
+            };
+            if base.is_some() && !opts.is_empty() {
+                warn!("socket options of outbound {} are ignored, it dials through {}", outbound.tag, outbound.dialer_proxy.as_deref().unwrap_or_default());
+            }
+            let dialer = match transport::build_chained_dialer(&outbound.stream_settings, base, &opts) {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("bad stream settings of outbound {} {}", outbound.tag, err);
+                    continue;
+                }
+            };
             let handler = match &*outbound.protocol {
                 "socks" => {
                     let socks_settings = match &outbound.settings {
@@ -45,23 +146,111 @@ impl OutboundManager {
                     let tcp = Arc::new(socks::TcpOutboundHandler {
                         address: addr
                     });
-                    let udp = Arc::new(socks::UdpOutboundHandler {
-                        addr: socks_settings.address.clone(),
-                        port: socks_settings.port,
-                    });
+                    // UDP ASSOCIATE 尚未实现，socks outbound 只转发 tcp
                     Arc::new(OutboundHandler::new(
                         outbound.tag.clone(),
+                        dialer,
                         Some(tcp),
-                        Some(udp),
+                        None,
                     ))
                 }
                 "shadowsocks" => {
-                    todo!()
+                    let ss_settings = match &outbound.settings {
+                        Some(settings) => match serde_json::from_str::<ShadowsocksOutboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue
+                            }
+                        },
+                        None => {
+                            error!("no shadowsocks settings found!");
+                            continue;
+                        }
+                    };
+                    let key = match shadowsocks::CipherKey::new(&ss_settings.method, &ss_settings.password) {
+                        Ok(x) => x,
+                        Err(err) => {
+                            error!("bad shadowsocks settings {}", err);
+                            continue
+                        }
+                    };
+                    let addr = match Address::try_from((ss_settings.address.clone(), ss_settings.port)) {
+                        Ok(r) => r,
+                        Err(_err) => {
+                            error!("bad shadowsocks addr found {}:{}", ss_settings.address, ss_settings.port);
+                            continue
+                        }
+                    };
+                    // 只支持 tcp，udp relay 尚未实现
+                    let tcp = Arc::new(shadowsocks::TcpOutboundHandler { address: addr, key });
+                    Arc::new(OutboundHandler::new(outbound.tag.clone(), dialer, Some(tcp), None))
+                }
+                "vless" => {
+                    let vless_settings = match &outbound.settings {
+                        Some(settings) => match serde_json::from_str::<VlessOutboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue
+                            }
+                        },
+                        None => {
+                            error!("no vless settings found!");
+                            continue;
+                        }
+                    };
+                    let uuid = match Uuid::parse_str(&vless_settings.id) {
+                        Ok(x) => x,
+                        Err(err) => {
+                            error!("bad vless id {} {}", vless_settings.id, err);
+                            continue
+                        }
+                    };
+                    let addr = match Address::try_from((vless_settings.address.clone(), vless_settings.port)) {
+                        Ok(r) => r,
+                        Err(_err) => {
+                            error!("bad vless addr found {}:{}", vless_settings.address, vless_settings.port);
+                            continue
+                        }
+                    };
+                    let tcp = Arc::new(vless::TcpOutboundHandler {
+                        address: addr.clone(),
+                        uuid,
+                    });
+                    // udp 通过 vless 的 UDP command 承载在 stream 上
+                    let udp = Arc::new(vless::UdpOutboundHandler {
+                        address: addr,
+                        uuid,
+                        dialer: dialer.clone(),
+                    });
+                    Arc::new(OutboundHandler::new(outbound.tag.clone(), dialer, Some(tcp), Some(udp)))
                 }
                 "direct" => {
                     let tcp = Arc::new(direct::TcpOutboundHandler{});
-                    let udp = Arc::new(direct::UdpOutboundHandler{});
-                    Arc::new(OutboundHandler::new(outbound.tag.clone(), Some(tcp), Some(udp)))
+                    let udp = Arc::new(direct::UdpOutboundHandler::new(opts));
+                    Arc::new(OutboundHandler::new(outbound.tag.clone(), dialer, Some(tcp), Some(udp)))
+                }
+                "blackhole" => {
+                    let blackhole_settings = match &outbound.settings {
+                        Some(settings) => match serde_json::from_str::<BlackholeOutboundSettings>(settings.get()) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                error!("{}", err);
+                                continue
+                            }
+                        },
+                        None => BlackholeOutboundSettings::default(),
+                    };
+                    let tcp = match blackhole::TcpOutboundHandler::new(&blackhole_settings) {
+                        Ok(x) => Arc::new(x),
+                        Err(err) => {
+                            error!("{}", err);
+                            continue
+                        }
+                    };
+                    let udp = Arc::new(blackhole::UdpOutboundHandler{});
+                    Arc::new(OutboundHandler::new(outbound.tag.clone(), dialer, Some(tcp), Some(udp)))
                 }
                 _ => {
                     info!("found unsupported outbound {}", outbound.tag);
@@ -70,9 +259,241 @@ impl OutboundManager {
             };
             handlers.insert(outbound.tag.clone(), handler);
         }
-        Ok(OutboundManager { handlers })
+        let groups = Self::new_groups(&outbounds, &handlers)?;
+        Ok(OutboundManager { handlers, groups, state_file: None })
+    }
+
+    fn new_groups(outbounds: &[Outbound], handlers: &HashMap<String, Arc<OutboundHandler>>) -> Result<HashMap<String, Arc<OutboundGroup>>> {
+        let mut groups = HashMap::new();
+        for outbound in outbounds.iter().filter(|x| OutboundGroup::is_group(&x.protocol)) {
+            match OutboundGroup::new(outbound) {
+                Ok(group) => {
+                    groups.insert(outbound.tag.clone(), group);
+                }
+                Err(err) => error!("bad group outbound {} {}", outbound.tag, err),
+            }
+        }
+        // 忽略不存在的成员
+        let tags: HashSet<String> = groups.keys().cloned().collect();
+        for group in groups.values_mut() {
+            let group_tag = group.tag.clone();
+            group.members.retain(|tag| {
+                let found = handlers.contains_key(tag) || tags.contains(tag);
+                if !found {
+                    error!("member {} of outbound group {} not found", tag, group_tag);
+                }
+                found
+            });
+        }
+        let groups = groups.into_iter().map(|(tag, group)| (tag, Arc::new(group))).collect();
+        group::check_cycles(&groups)?;
+        Ok(groups)
+    }
+
+    // tag 为 group 时按策略为该连接选择成员
+    pub fn get_handler(&self, tag: &str, sess: &Session) -> Option<Arc<OutboundHandler>> {
+        self.resolve(tag, sess).map(|(handler, _)| handler)
+    }
+
+    // 同 get_handler，同时返回连接经过的 selector 的通知，用于 track_connection
+    pub fn resolve(&self, tag: &str, sess: &Session) -> Option<(Arc<OutboundHandler>, Vec<Arc<Notify>>)> {
+        if let Some(handler) = self.handlers.get(tag) {
+            return Some((handler.clone(), Vec::new()));
+        }
+        let group = self.groups.get(tag)?;
+        let (handler, mut close) = self.resolve(group.select(self, sess)?, sess)?;
+        if group.is_selector() {
+            close.push(group.close.clone());
+        }
+        Some((handler, close))
+    }
+
+    pub fn get_group(&self, tag: &str) -> Option<Arc<OutboundGroup>> {
+        self.groups.get(tag).cloned()
+    }
+
+    pub fn groups(&self) -> Vec<Arc<OutboundGroup>> {
+        let mut groups: Vec<Arc<OutboundGroup>> = self.groups.values().cloned().collect();
+        groups.sort_by(|a, b| a.tag.cmp(&b.tag));
+        groups
+    }
+
+    // 恢复保存的 selector 选择，之后每次切换都会写入该文件
+    pub fn load_state(&mut self, path: &str) {
+        self.state_file = Some(path.to_string());
+        let content = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(err) => {
+                info!("no outbound state loaded from {} {}", path, err);
+                return;
+            }
+        };
+        let state: HashMap<String, String> = match serde_json::from_str::<OutboundState>(&content) {
+            Ok(x) => x.selected,
+            Err(err) => {
+                error!("bad outbound state file {} {}", path, err);
+                return;
+            }
+        };
+        for (tag, member) in state {
+            match self.groups.get(&tag).map(|x| x.set_selected(&member)) {
+                Some(Ok(_)) => info!("restore selector {} to {}", tag, member),
+                Some(Err(err)) => error!("failed to restore selector {} {}", tag, err),
+                None => error!("failed to restore selector {}, not found", tag),
+            }
+        }
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let path = match &self.state_file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let selected = self
+            .groups
+            .values()
+            .filter(|x| x.is_selector())
+            .filter_map(|x| x.selected().map(|member| (x.tag.clone(), member)))
+            .collect();
+        // 先写入临时文件，避免写入中途退出导致文件损坏
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_vec_pretty(&OutboundState { selected })?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // 切换 selector 的成员，新的连接使用新成员，close 为 true 时关闭之前经过该 selector 的连接
+    pub fn select(&self, tag: &str, member: &str, close: bool) -> Result<()> {
+        let group = self
+            .groups
+            .get(tag)
+            .ok_or_else(|| anyhow!("outbound group {} not found", tag))?;
+        let previous = group.set_selected(member)?;
+        info!("selector {} switch to {}", tag, member);
+        if let Err(err) = self.save_state() {
+            error!("failed to save outbound state {}", err);
+        }
+        // 直接使用成员或经过其他 selector 的连接不受影响
+        if close && previous.as_deref() != Some(member) {
+            group.close.notify_waiters();
+        }
+        Ok(())
     }
-    pub fn get_handler(&self, tag: &str) -> Option<Arc<OutboundHandler>> {
-        self.handlers.get(tag).and_then(|x| Some(x.clone()))
+
+    // 定期检查 group 成员是否可用，不会结束
+    pub async fn run_health_checks(self: Arc<Self>, ctx: Arc<Context>) {
+        for group in self.groups.values() {
+            tokio::spawn(group.clone().run_health_check(self.clone(), ctx.clone()));
+        }
+        futures::future::pending::<()>().await;
+    }
+
+    // group 的连接数为所有成员之和
+    pub fn connections(&self, tag: &str) -> usize {
+        if let Some(handler) = self.handlers.get(tag) {
+            return handler.connections();
+        }
+        match self.groups.get(tag) {
+            Some(group) => group.members.iter().map(|x| self.connections(x)).sum(),
+            None => 0,
+        }
     }
 }
+
+#[test]
+fn chain_order_test() {
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[
+        {"protocol": "vless", "tag": "a", "dialerProxy": "b"},
+        {"protocol": "socks", "tag": "b", "dialerProxy": "c"},
+        {"protocol": "direct", "tag": "c"},
+        {"protocol": "direct", "tag": "d", "dialerProxy": "unknown"}
+    ]"#).unwrap();
+    let order: Vec<&str> = chain_order(&outbounds).unwrap().iter().map(|x| x.tag.as_str()).collect();
+    assert_eq!(order, vec!["c", "b", "a", "d"]);
+    // dialerProxy 不存在的 outbound 被跳过
+    let manager = OutboundManager::new(outbounds).unwrap();
+    assert!(manager.handlers.get("c").is_some());
+    assert!(manager.handlers.get("d").is_none());
+
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[
+        {"protocol": "direct", "tag": "x"},
+        {"protocol": "socks", "tag": "a", "dialerProxy": "b"},
+        {"protocol": "socks", "tag": "b", "dialerProxy": "a"}
+    ]"#).unwrap();
+    let err = OutboundManager::new(outbounds).err().unwrap();
+    assert_eq!(err.to_string(), "dialer proxy cycle a => b => a");
+
+    // dialerProxy 不能是 group
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[
+        {"protocol": "direct", "tag": "b"},
+        {"protocol": "selector", "tag": "proxy", "settings": {"outbounds": ["b"]}},
+        {"protocol": "socks", "tag": "a", "dialerProxy": "proxy", "settings": {"address": "127.0.0.1", "port": 1080}}
+    ]"#).unwrap();
+    let manager = OutboundManager::new(outbounds).unwrap();
+    assert!(manager.handlers.get("a").is_none());
+    assert!(manager.get_group("proxy").is_some());
+}
+
+#[test]
+fn unsupported_outbound_test() {
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[
+        {"protocol": "direct", "tag": "a"},
+        {"protocol": "vmess", "tag": "v", "streamSettings": {"network": "obfs"}}
+    ]"#).unwrap();
+    // 不支持的协议被跳过，而不是 panic
+    let manager = OutboundManager::new(outbounds).unwrap();
+    assert!(manager.handlers.get("a").is_some());
+    assert!(manager.handlers.get("v").is_none());
+}
+
+#[tokio::test]
+async fn selector_test() {
+    use std::time::Duration;
+
+    let outbounds: Vec<Outbound> = serde_json::from_str(r#"[
+        {"protocol": "direct", "tag": "a"},
+        {"protocol": "direct", "tag": "b"},
+        {"protocol": "selector", "tag": "proxy", "settings": {"outbounds": ["a", "b"]}},
+        {"protocol": "load-balance", "tag": "lb", "settings": {"outbounds": ["a", "b"]}}
+    ]"#).unwrap();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let sess = Session {
+        destination: Address::Domain("example.com".to_string(), 443),
+        local_peer: addr,
+        peer_address: addr,
+        network: Network::TCP,
+    };
+    let path = std::env::temp_dir().join(format!("tunnel-state-{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let mut manager = OutboundManager::new(outbounds.clone()).unwrap();
+    manager.load_state(path);
+    // 默认使用第一个成员
+    assert_eq!(manager.get_handler("proxy", &sess).unwrap().tag, "a");
+    assert!(manager.select("proxy", "c", false).is_err());
+    assert!(manager.select("lb", "b", false).is_err());
+
+    let track = |tag: &str| {
+        let (handler, close) = manager.resolve(tag, &sess).unwrap();
+        let guard = handler.track_connection(close);
+        tokio::spawn(async move { guard.closed().await })
+    };
+    let closed_proxy = track("proxy");
+    // 直接使用 a 的连接没有经过 selector
+    let closed_a = track("a");
+    let closed_b = track("b");
+    tokio::task::yield_now().await;
+    manager.select("proxy", "b", true).unwrap();
+    assert_eq!(manager.get_handler("proxy", &sess).unwrap().tag, "b");
+    // 只关闭经过该 selector 的连接
+    tokio::time::timeout(Duration::from_secs(1), closed_proxy).await.unwrap().unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(100), closed_a).await.is_err());
+    assert!(tokio::time::timeout(Duration::from_millis(100), closed_b).await.is_err());
+
+    // 重启后恢复选择
+    let mut manager = OutboundManager::new(outbounds).unwrap();
+    manager.load_state(path);
+    assert_eq!(manager.get_handler("proxy", &sess).unwrap().tag, "b");
+    fs::remove_file(path).unwrap();
+}
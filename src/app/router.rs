@@ -1,4 +1,4 @@
-use std::{io};
+use std::{io, time::Duration};
 
 use anyhow::{
     Result,
@@ -16,20 +16,40 @@ pub trait ConditionMatcher: Sync + Send + Unpin {
     fn apply(&self, sess: &Session) -> bool;
 }
 
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct MatcherRule {
-    target: String,
+    route: Route,
     matcher: Box<dyn ConditionMatcher>
 }
 
 impl MatcherRule {
-    pub fn new(target: String, matcher: Box<dyn ConditionMatcher>) -> MatcherRule {
+    pub fn new(rule: &Rule, matcher: Box<dyn ConditionMatcher>) -> MatcherRule {
         MatcherRule {
-            target,
+            route: Route {
+                target: rule.target.clone(),
+                fallback: rule.fallback.clone().unwrap_or_default(),
+                connect_timeout: rule.connect_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            },
             matcher
         }
     }
 }
 
+// 命中的 rule，target 连接失败时按顺序尝试 fallback
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub target: String,
+    pub fallback: Vec<String>,
+    pub connect_timeout: Duration,
+}
+
+impl Route {
+    pub fn targets(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.target).chain(self.fallback.iter())
+    }
+}
+
 
 pub struct Router {
     rules: Vec<MatcherRule>
@@ -55,24 +75,28 @@ impl Router {
         for rule in rules.iter() {
             if let Some(ref name) = rule.domain {
                 let matcher = try_rule!(DomainMatcher::new(name.clone()));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)))
+                router.rules.push(MatcherRule::new(rule, Box::new(matcher)))
             }
             if let Some(ref cidr) = rule.ip {
                 let matcher = try_rule!(IpCidrMatcher::new(cidr.clone()));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)));
+                router.rules.push(MatcherRule::new(rule, Box::new(matcher)));
             }
             if let Some(ref regexp) = rule.regexp {
                 let matcher = try_rule!(RegexpMatcher::new(regexp));
-                router.rules.push(MatcherRule::new(rule.target.clone(), Box::new(matcher)));
+                router.rules.push(MatcherRule::new(rule, Box::new(matcher)));
             }
         }
         return router;
     }
 
     pub fn route(&self, sess: &Session) -> Option<String> {
+        self.find(sess).map(|x| x.target)
+    }
+
+    pub fn find(&self, sess: &Session) -> Option<Route> {
         for rule in &self.rules {
             if rule.matcher.apply(&sess) {
-                return Some(rule.target.clone())
+                return Some(rule.route.clone())
             }
         }
         debug!("no routing found {:?}", sess);
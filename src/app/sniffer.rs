@@ -107,10 +107,14 @@ where
         for _i in 1..3 {
             match timeout(wait, self.stream.read(&mut buf)).await? {
                 // https://www.rfc-editor.org/rfc/rfc4346#page-17
-                Ok(_n) => {
+                Ok(0) => {
+                    // EOF
+                    return Ok(None);
+                }
+                Ok(n) => {
                     // 需要存储全部TLS record数据
                     // 当连接server时发过去
-                    self.buf.extend_from_slice(&*buf);
+                    self.buf.extend_from_slice(&buf[..n]);
                     let curr = &self.buf[..];
 
                     if curr.len() < 5 {
@@ -190,7 +194,7 @@ impl<T: AsyncRead + Unpin> AsyncRead for Sniffer<T> {
             self.buf.drain(..accepted_len);
             Poll::Ready(Ok(()))
         } else {
-            AsyncRead::poll_read(self, cx, buf)
+            AsyncRead::poll_read(Pin::new(&mut self.stream), cx, buf)
         }
     }
 }
@@ -1,75 +1,45 @@
 use anyhow::{anyhow, Result};
 
-use std::{
-    io::{self, BufRead},
-    net::IpAddr,
-    process::Command,
-    str::FromStr,
-};
+use std::{net::Ipv4Addr, process::Command};
 
-pub fn get_default_ipv4_gateway() -> Result<IpAddr> {
-    let output = Command::new("ip")
-        .arg("route")
-        .arg("show")
-        .arg("table")
-        .arg("default")
-        .output()
-        .expect("get ipv4 default gateway error");
-    let stdout = &*output.stdout;
-    let out = String::from_utf8_lossy(stdout).to_string();
-    let default = out
-        .lines()
-        .filter(|x| x.contains("via"))
-        .next()
-        .unwrap()
-        .split_whitespace()
-        .collect::<Vec<&str>>()[2];
-    let addr = IpAddr::from_str(default)?;
-    Ok(addr)
+// 使用 0.0.0.0/1 和 128.0.0.0/1 覆盖默认路由，不需要删除原有的 default route
+const DEFAULT_ROUTES: [&str; 2] = ["0.0.0.0/1", "128.0.0.0/1"];
+
+pub fn add_default_routes(interface: &str, gateway: Ipv4Addr) -> Result<()> {
+    for dest in DEFAULT_ROUTES.iter() {
+        ip_route("add", dest, interface, gateway)?;
+    }
+    Ok(())
 }
 
-pub fn get_default_ipv6_gateway() -> Result<IpAddr> {
+// 删除 add_default_routes 添加的路由，某条路由删除失败时继续删除其他路由
+pub fn delete_default_routes(interface: &str, gateway: Ipv4Addr) -> Result<()> {
+    let mut res = Ok(());
+    for dest in DEFAULT_ROUTES.iter() {
+        if let Err(err) = ip_route("del", dest, interface, gateway) {
+            res = Err(err);
+        }
+    }
+    res
+}
+
+fn ip_route(action: &str, dest: &str, interface: &str, gateway: Ipv4Addr) -> Result<()> {
     let output = Command::new("ip")
         .arg("route")
-        .arg("show")
-        .arg("table")
-        .arg("default")
-        .output()
-        .expect("get ipv6 default gateway error");
+        .arg(action)
+        .arg(dest)
+        .arg("via")
+        .arg(gateway.to_string())
+        .arg("dev")
+        .arg(interface)
+        .output()?;
     if !output.status.success() {
         return Err(anyhow!(
-            "exec failed {}",
-            String::from_utf8_lossy(&*output.stderr)
+            "{} route {} failed {}",
+            action,
+            dest,
+            String::from_utf8_lossy(&output.stderr)
         ));
     }
-    let line = String::from_utf8_lossy(&*output.stdout);
-    let line = line
-        .lines()
-        .filter(|s| s.contains("default"))
-        .next()
-        .unwrap()
-        .split_whitespace()
-        .collect::<Vec<&str>>()[2];
-    let addr = IpAddr::from_str(line)?;
-    Ok(addr)
-}
-
-pub fn get_default_interface() -> io::Result<String> {
-    let output = Command::new("ip").arg("route").arg("show").output()?;
-    let out = String::from_utf8_lossy(&*output.stdout).to_string();
-    let line = out
-        .lines()
-        .filter(|s| s.contains("default"))
-        .next()
-        .unwrap()
-        .split_whitespace()
-        .collect::<Vec<&str>>();
-    let a = line.last().unwrap();
-    Ok(String::from(*a))
-}
-
-#[test]
-pub fn test_defaultipv4() {
-    let r = get_default_ipv4_gateway();
-    assert!(r.is_ok());
+    Ok(())
 }
@@ -1,2 +1,5 @@
 #[cfg(target_os = "linux")]
 pub mod linux;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+pub mod varint;
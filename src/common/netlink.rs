@@ -0,0 +1,348 @@
+use std::{
+    ffi::CStr,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+use log::{debug, info};
+use tokio::io::unix::AsyncFd;
+
+// 通过 NETLINK_ROUTE 读取路由表，不需要执行 ip route
+// https://man7.org/linux/man-pages/man7/rtnetlink.7.html
+
+const NLMSG_HEADER_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const RTATTR_HEADER_LEN: usize = 4;
+const RECV_BUFFER_SIZE: usize = 32 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub family: u8,
+    pub dst_len: u8,
+    pub table: u32,
+    pub route_type: u8,
+    pub oif: Option<u32>,
+    pub gateway: Option<IpAddr>,
+    pub priority: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefaultRoute {
+    pub interface: String,
+    pub index: u32,
+    pub gateway: Option<IpAddr>,
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+fn parse_ip(family: u8, data: &[u8]) -> Option<IpAddr> {
+    match (family as i32, data.len()) {
+        (libc::AF_INET, 4) => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        (libc::AF_INET6, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+// payload 为 nlmsghdr 之后的 rtmsg 和 rtattr
+fn parse_route(payload: &[u8]) -> Option<Route> {
+    if payload.len() < RTMSG_LEN {
+        return None;
+    }
+    let mut route = Route {
+        family: payload[0],
+        dst_len: payload[1],
+        table: payload[4] as u32,
+        route_type: payload[7],
+        oif: None,
+        gateway: None,
+        priority: 0,
+    };
+    let mut offset = RTMSG_LEN;
+    while offset + RTATTR_HEADER_LEN <= payload.len() {
+        let len = read_u16(payload, offset) as usize;
+        let ty = read_u16(payload, offset + 2);
+        if len < RTATTR_HEADER_LEN || offset + len > payload.len() {
+            break;
+        }
+        let data = &payload[offset + RTATTR_HEADER_LEN..offset + len];
+        match ty {
+            libc::RTA_OIF if data.len() == 4 => route.oif = Some(read_u32(data, 0)),
+            libc::RTA_GATEWAY => route.gateway = parse_ip(route.family, data),
+            libc::RTA_PRIORITY if data.len() == 4 => route.priority = read_u32(data, 0),
+            libc::RTA_TABLE if data.len() == 4 => route.table = read_u32(data, 0),
+            _ => {}
+        }
+        offset += align(len);
+    }
+    Some(route)
+}
+
+// 解析一次 recv 得到的全部 netlink message，返回 true 表示 dump 结束
+fn parse_messages(buf: &[u8], routes: &mut Vec<Route>) -> io::Result<bool> {
+    let mut offset = 0;
+    while offset + NLMSG_HEADER_LEN <= buf.len() {
+        let len = read_u32(buf, offset) as usize;
+        let ty = read_u16(buf, offset + 4);
+        if len < NLMSG_HEADER_LEN || offset + len > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated netlink message"));
+        }
+        let payload = &buf[offset + NLMSG_HEADER_LEN..offset + len];
+        match ty as i32 {
+            libc::NLMSG_DONE => return Ok(true),
+            libc::NLMSG_ERROR => {
+                let errno = if payload.len() >= 4 { read_u32(payload, 0) as i32 } else { 0 };
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+            }
+            _ if ty == libc::RTM_NEWROUTE => {
+                if let Some(route) = parse_route(payload) {
+                    routes.push(route);
+                }
+            }
+            _ => {}
+        }
+        offset += align(len);
+    }
+    Ok(false)
+}
+
+fn netlink_socket(groups: u32, nonblocking: bool) -> io::Result<OwnedFd> {
+    let mut flags = libc::SOCK_RAW | libc::SOCK_CLOEXEC;
+    if nonblocking {
+        flags |= libc::SOCK_NONBLOCK;
+    }
+    let fd = unsafe { libc::socket(libc::AF_NETLINK, flags, libc::NETLINK_ROUTE) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = groups;
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+fn recv(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    let n = unsafe { libc::recv(fd, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// RTM_GETROUTE dump 指定地址族的全部路由
+pub fn dump_routes(family: i32) -> io::Result<Vec<Route>> {
+    let fd = netlink_socket(0, false)?;
+    let mut request = [0u8; NLMSG_HEADER_LEN + RTMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HEADER_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    request[6..8].copy_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    request[NLMSG_HEADER_LEN] = family as u8;
+    let n = unsafe { libc::send(fd.as_raw_fd(), request.as_ptr() as *const _, request.len(), 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut routes = Vec::new();
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    loop {
+        let n = recv(fd.as_raw_fd(), &mut buf)?;
+        if n == 0 || parse_messages(&buf[..n], &mut routes)? {
+            return Ok(routes);
+        }
+    }
+}
+
+pub fn interface_name(index: u32) -> io::Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let ret = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if ret.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+// main 表中 metric 最小的默认路由，exclude 为 tun 等不应作为出口的网卡
+fn select_default(routes: &[Route], exclude: Option<&str>) -> Option<DefaultRoute> {
+    let mut candidates: Vec<&Route> = routes
+        .iter()
+        .filter(|x| x.dst_len == 0 && x.table == libc::RT_TABLE_MAIN as u32 && x.route_type == libc::RTN_UNICAST)
+        .filter(|x| x.oif.is_some())
+        .collect();
+    candidates.sort_by_key(|x| x.priority);
+    for route in candidates {
+        let index = route.oif.unwrap_or_default();
+        let interface = match interface_name(index) {
+            Ok(x) => x,
+            Err(err) => {
+                debug!("unknown interface index {} {}", index, err);
+                continue;
+            }
+        };
+        if Some(interface.as_str()) == exclude {
+            continue;
+        }
+        return Some(DefaultRoute {
+            interface,
+            index,
+            gateway: route.gateway,
+        });
+    }
+    None
+}
+
+// 返回 (ipv4, ipv6) 的默认路由
+pub fn default_routes(exclude: Option<&str>) -> io::Result<(Option<DefaultRoute>, Option<DefaultRoute>)> {
+    let v4 = select_default(&dump_routes(libc::AF_INET)?, exclude);
+    // 没有开启 ipv6 时 dump 会失败
+    let v6 = match dump_routes(libc::AF_INET6) {
+        Ok(routes) => select_default(&routes, exclude),
+        Err(err) => {
+            debug!("dump ipv6 routes failed {}", err);
+            None
+        }
+    };
+    Ok((v4, v6))
+}
+
+// 订阅路由和网卡的变化 (例如切换 Wi-Fi)，每次变化后重新计算默认路由
+pub struct RouteMonitor {
+    fd: AsyncFd<OwnedFd>,
+    exclude: Option<String>,
+}
+
+impl RouteMonitor {
+    pub fn new(exclude: Option<String>) -> io::Result<Self> {
+        let groups = libc::RTMGRP_LINK | libc::RTMGRP_IPV4_ROUTE | libc::RTMGRP_IPV6_ROUTE;
+        let fd = netlink_socket(groups as u32, true)?;
+        // OwnedFd 在 AsyncFd 的生命周期内一直有效
+        let fd = unsafe { AsyncFd::register(fd)? };
+        Ok(RouteMonitor {
+            fd,
+            exclude,
+        })
+    }
+
+    // 默认路由变化时调用 on_change，初始值通过 current 获取，不会结束
+    pub async fn run<F>(self, mut on_change: F) -> io::Result<()>
+    where
+        F: FnMut(Option<DefaultRoute>),
+    {
+        let mut current = self.current();
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            // 一次变化会产生多个 message，全部读取后只计算一次
+            loop {
+                match recv(self.fd.get_ref().as_raw_fd(), &mut buf) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready();
+                        break;
+                    }
+                    // ENOBUFS 表示有 message 丢失，重新 dump 即可
+                    Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            let route = self.current();
+            if route != current {
+                info!("default interface changed {:?} => {:?}", current, route);
+                current = route;
+                on_change(current.clone());
+            }
+        }
+    }
+
+    // 优先使用 ipv4 的默认路由
+    pub fn current(&self) -> Option<DefaultRoute> {
+        match default_routes(self.exclude.as_deref()) {
+            Ok((v4, v6)) => v4.or(v6),
+            Err(err) => {
+                debug!("dump routes failed {}", err);
+                None
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_messages_test() {
+    fn attr(ty: u16, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&((RTATTR_HEADER_LEN + data.len()) as u16).to_ne_bytes());
+        buf.extend(&ty.to_ne_bytes());
+        buf.extend(data);
+        buf.resize(align(buf.len()), 0);
+        buf
+    }
+    fn message(ty: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&((NLMSG_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+        buf.extend(&ty.to_ne_bytes());
+        buf.extend(&[0u8; 10]);
+        buf.extend(payload);
+        buf
+    }
+    // default via 192.168.1.1 dev 2 metric 100
+    let mut payload = vec![libc::AF_INET as u8, 0, 0, 0, libc::RT_TABLE_MAIN, 3, 0, libc::RTN_UNICAST, 0, 0, 0, 0];
+    payload.extend(attr(libc::RTA_TABLE, &(libc::RT_TABLE_MAIN as u32).to_ne_bytes()));
+    payload.extend(attr(libc::RTA_PRIORITY, &100u32.to_ne_bytes()));
+    payload.extend(attr(libc::RTA_GATEWAY, &[192, 168, 1, 1]));
+    payload.extend(attr(libc::RTA_OIF, &2u32.to_ne_bytes()));
+    let mut buf = message(libc::RTM_NEWROUTE, &payload);
+    buf.extend(message(libc::NLMSG_DONE as u16, &[0u8; 4]));
+
+    let mut routes = Vec::new();
+    assert!(parse_messages(&buf, &mut routes).unwrap());
+    assert_eq!(
+        routes,
+        vec![Route {
+            family: libc::AF_INET as u8,
+            dst_len: 0,
+            table: libc::RT_TABLE_MAIN as u32,
+            route_type: libc::RTN_UNICAST,
+            oif: Some(2),
+            gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+            priority: 100,
+        }]
+    );
+
+    let mut error = (-libc::EPERM).to_ne_bytes().to_vec();
+    error.extend(&[0u8; NLMSG_HEADER_LEN]);
+    let err = parse_messages(&message(libc::NLMSG_ERROR as u16, &error), &mut routes).err().unwrap();
+    assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+}
+
+#[test]
+fn dump_routes_test() {
+    // 只检查能通过 netlink 读取路由表，sandbox 中可能没有默认路由
+    dump_routes(libc::AF_INET).unwrap();
+}
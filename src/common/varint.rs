@@ -0,0 +1,40 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+
+// protobuf 的 base 128 varint，gun (grpc) 和 vless addons 共用
+pub fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+pub fn get_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if data.is_empty() {
+            bail!("unexpected end of varint");
+        }
+        let b = data[0];
+        data.advance(1);
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("varint overflow")
+}
+
+#[test]
+fn varint_test() {
+    let mut buf = BytesMut::new();
+    for value in [0, 1, 127, 128, 300, u64::MAX] {
+        put_varint(&mut buf, value);
+    }
+    let mut data = &buf[..];
+    for value in [0, 1, 127, 128, 300, u64::MAX] {
+        assert_eq!(get_varint(&mut data).unwrap(), value);
+    }
+    assert!(get_varint(&mut &[0x80u8][..]).is_err());
+}
@@ -6,7 +6,7 @@ use std::{
     collections::HashMap,
     fs::{self},
     io::{Read},
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 
 // https://v2ray.com/chapter_02/01_overview.html
@@ -17,6 +17,14 @@ pub struct Config {
     pub outbounds: Vec<Outbound>,
     pub routes: Vec<Rule>,
     pub dns: Option<DnsConfig>,
+    pub api: Option<ApiSettings>,
+}
+
+// 控制 API，只应监听在本地
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiSettings {
+    pub address: String,
+    pub port: u16,
 }
 
 #[derive(Clone, Deserialize)]
@@ -24,12 +32,207 @@ pub struct Outbound {
     pub protocol: String,
     pub settings: Option<Box<RawValue>>,
     pub tag: String,
+    #[serde(rename = "streamSettings")]
+    pub stream_settings: Option<StreamSettings>,
+    // 通过另一个 outbound 连接到该 outbound 的 server，例如 vless over socks
+    // 只能是普通 outbound，不支持 selector 等 group
+    #[serde(rename = "dialerProxy")]
+    pub dialer_proxy: Option<String>,
+    // 绑定的网卡 (SO_BINDTODEVICE)，仅 linux
+    pub interface: Option<String>,
+    // 连接使用的本地地址
+    #[serde(rename = "sourceAddress")]
+    pub source_address: Option<IpAddr>,
+    // SO_MARK，仅 linux，需要 CAP_NET_ADMIN
+    pub fwmark: Option<u32>,
+}
+
+// 传输层配置，proxy 协议运行在其之上
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct StreamSettings {
+    // tcp, ws, h2, grpc, quic, kcp, obfs, 默认 tcp
+    pub network: Option<String>,
+    // none, tls, 默认 none
+    pub security: Option<String>,
+    #[serde(rename = "tlsSettings")]
+    pub tls_settings: Option<TlsSettings>,
+    #[serde(rename = "wsSettings")]
+    pub ws_settings: Option<WsSettings>,
+    #[serde(rename = "httpSettings")]
+    pub http_settings: Option<HttpSettings>,
+    #[serde(rename = "grpcSettings")]
+    pub grpc_settings: Option<GrpcSettings>,
+    #[serde(rename = "quicSettings")]
+    pub quic_settings: Option<QuicSettings>,
+    #[serde(rename = "kcpSettings")]
+    pub kcp_settings: Option<KcpSettings>,
+    // 设置后在 transport 之上多路复用，inbound 和 outbound 需要同时设置
+    #[serde(rename = "muxSettings")]
+    pub mux_settings: Option<MuxSettings>,
+    #[serde(rename = "obfsSettings")]
+    pub obfs_settings: Option<ObfsSettings>,
+    // tcp socket 选项，inbound 作用于 listener，outbound 作用于直接建立的连接
+    pub sockopt: Option<SockoptSettings>,
+}
+
+// 除 tcpNoDelay 和 buffer 外仅 linux 支持
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SockoptSettings {
+    // outbound 使用 TCP_FASTOPEN_CONNECT，inbound 使用 TCP_FASTOPEN
+    // outbound 只在目标只有一个地址时生效，此时 connect_timeout 不包含真正的握手
+    #[serde(rename = "tcpFastOpen")]
+    pub tcp_fast_open: Option<bool>,
+    #[serde(rename = "tcpNoDelay")]
+    pub tcp_no_delay: Option<bool>,
+    // 秒，设置任意一个 keepalive 选项即开启 SO_KEEPALIVE
+    #[serde(rename = "tcpKeepAliveIdle")]
+    pub tcp_keep_alive_idle: Option<u32>,
+    #[serde(rename = "tcpKeepAliveInterval")]
+    pub tcp_keep_alive_interval: Option<u32>,
+    #[serde(rename = "tcpKeepAliveCount")]
+    pub tcp_keep_alive_count: Option<u32>,
+    // SO_RCVBUF / SO_SNDBUF，字节
+    #[serde(rename = "receiveBuffer")]
+    pub receive_buffer: Option<usize>,
+    #[serde(rename = "sendBuffer")]
+    pub send_buffer: Option<usize>,
+    // 毫秒，TCP_USER_TIMEOUT
+    #[serde(rename = "tcpUserTimeout")]
+    pub tcp_user_timeout: Option<u32>,
+    // 拥塞控制算法，例如 bbr，需要内核已加载
+    #[serde(rename = "tcpCongestion")]
+    pub tcp_congestion: Option<String>,
+    // 仅 inbound，大于 1 时通过 SO_REUSEPORT bind 多个 listener，每个 listener 一个 accept task
+    // quic、kcp 和 ws 忽略该选项，只有一个 listener
+    pub acceptors: Option<usize>,
+}
+
+// simple-obfs 兼容，无需外部 SIP003 插件
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ObfsSettings {
+    // http 或 tls，默认 http
+    pub mode: Option<String>,
+    // 伪装的域名 (Host 或 SNI)，默认使用 server 地址
+    pub host: Option<String>,
+    // http 请求的 path，默认 /
+    pub uri: Option<String>,
+}
+
+// smux v1 兼容
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MuxSettings {
+    // 每个底层连接最多的 stream 数，默认 8
+    #[serde(rename = "maxStreams")]
+    pub max_streams: Option<usize>,
+    // 底层连接没有 stream 后保留的时间，秒，默认 60
+    #[serde(rename = "idleTimeout")]
+    pub idle_timeout: Option<u64>,
+}
+
+// 参数与 v2ray mKCP 一致，协议为标准 KCP，忽略 security
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct KcpSettings {
+    // UDP packet 最大长度，576-1460，默认 1350
+    pub mtu: Option<usize>,
+    // 发送间隔，毫秒，10-100，默认 50
+    pub tti: Option<u32>,
+    // 上行、下行带宽，MB/s，决定发送和接收窗口，默认 5 和 20
+    #[serde(rename = "uplinkCapacity")]
+    pub uplink_capacity: Option<usize>,
+    #[serde(rename = "downlinkCapacity")]
+    pub downlink_capacity: Option<usize>,
+    // 拥塞控制，默认关闭
+    pub congestion: Option<bool>,
+    pub header: Option<KcpHeaderSettings>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct KcpHeaderSettings {
+    // 伪装的 packet 头部: none, srtp, utp, wechat-video, dtls, wireguard，默认 none
+    #[serde(rename = "type")]
+    pub header_type: Option<String>,
+}
+
+// quic 自带 TLS 1.3，证书等使用 tlsSettings，忽略 security
+// vless 的 UDP command 通过 QUIC datagram 转发 packet
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct QuicSettings {
+    // 空闲超时，秒，默认 30
+    #[serde(rename = "maxIdleTimeout")]
+    pub max_idle_timeout: Option<u64>,
+    // keep alive 间隔，秒，默认 10，0 关闭
+    #[serde(rename = "keepAlivePeriod")]
+    pub keep_alive_period: Option<u64>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct HttpSettings {
+    // 默认 /
+    pub path: Option<String>,
+    // :authority，默认使用 server 地址
+    pub host: Option<String>,
+}
+
+// v2ray/xray 兼容的 gun 协议
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GrpcSettings {
+    // 默认 GunService
+    #[serde(rename = "serviceName")]
+    pub service_name: Option<String>,
+    // 使用 TunMulti
+    #[serde(rename = "multiMode")]
+    pub multi_mode: Option<bool>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WsSettings {
+    // 默认 /，inbound 按 path 区分共享同一端口的 ws inbound
+    pub path: Option<String>,
+    // 自定义 header，包括 Host
+    pub headers: Option<HashMap<String, String>>,
+    // 首包作为 early data 随握手发送，节省一个 RTT，默认 0 关闭
+    #[serde(rename = "maxEarlyData")]
+    pub max_early_data: Option<usize>,
+    // early data 所在的 header，例如 Sec-WebSocket-Protocol，为空时 early data 追加在 path 之后
+    #[serde(rename = "earlyDataHeaderName")]
+    pub early_data_header_name: Option<String>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TlsSettings {
+    // 覆盖 SNI，默认使用 server 地址
+    #[serde(rename = "serverName")]
+    pub server_name: Option<String>,
+    pub alpn: Option<Vec<String>>,
+    // CA 证书 (PEM) 路径，默认使用内置的 webpki roots
+    pub ca: Option<String>,
+    // server 证书 SubjectPublicKeyInfo 的 sha256，base64 编码
+    #[serde(rename = "pinnedPeerSpkiSha256")]
+    pub pinned_peer_spki_sha256: Option<Vec<String>>,
+    // 跳过证书链校验，pinned 的 SPKI 仍然校验
+    #[serde(rename = "allowInsecure")]
+    pub allow_insecure: Option<bool>,
+    // 证书和私钥 (PEM) 路径，inbound 必须设置，文件变化后自动重新加载
+    // outbound 设置时作为 mTLS 的 client 证书
+    #[serde(rename = "certificateFile")]
+    pub certificate_file: Option<String>,
+    #[serde(rename = "keyFile")]
+    pub key_file: Option<String>,
+    // inbound 校验 client 证书的 CA (PEM) 路径，设置后开启 mTLS
+    #[serde(rename = "clientCa")]
+    pub client_ca: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct GeneralSettings {
     pub prefer_ipv6: bool,
     pub use_ipv6: bool,
+    // 保存 selector 的选择，重启后恢复
+    pub state_file: Option<String>,
+    // 毫秒，tcp 尝试全部地址的总时间，默认 10000
+    pub connect_timeout: Option<u64>,
+    // 毫秒，Happy Eyeballs 开始尝试下一个地址前的等待时间，默认 250
+    pub connection_attempt_delay: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -41,10 +244,55 @@ pub struct Socks5OutboundSettings {
     pub port: u16,
 }
 
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct BlackholeOutboundSettings {
+    pub response: Option<BlackholeResponse>,
+    // 毫秒，关闭连接或回复前等待的时间，默认 0
+    pub delay: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BlackholeResponse {
+    // none: 直接关闭, http: 403, tls: TLS alert, auto: 按 client 发送的数据选择 http 或 tls
+    #[serde(rename = "type")]
+    pub response_type: String,
+}
+
+// 引用其他 outbound 的 group，可以嵌套
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupOutboundSettings {
+    // 成员 outbound 的 tag
+    pub outbounds: Vec<String>,
+    // load-balance: random, round-robin, least-connections, consistent-hash，默认 random
+    pub strategy: Option<String>,
+    // fallback 和 url-test 默认使用 http 检查，load-balance 设置后跳过不可用的成员
+    #[serde(rename = "healthCheck")]
+    pub health_check: Option<HealthCheckSettings>,
+    // url-test 当前成员的延迟不超过最快成员加上 tolerance 时不切换，毫秒，默认 50
+    pub tolerance: Option<u64>,
+}
+
+// 通过成员 outbound 定期探测
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct HealthCheckSettings {
+    // tcp: 能通过 outbound 建立连接，http: 返回 204，默认 http
+    #[serde(rename = "type")]
+    pub check_type: Option<String>,
+    // 默认 http://www.gstatic.com/generate_204，tcp 只使用其中的 host 和 port
+    pub url: Option<String>,
+    // 秒，默认 60
+    pub interval: Option<u64>,
+    // 秒，默认 5
+    pub timeout: Option<u64>,
+    // 连续失败多少次后标记为不可用，默认 3
+    pub failures: Option<u32>,
+    // 不可用的成员连续成功多少次后恢复，默认 1
+    pub successes: Option<u32>,
+}
+
+// method: aes-128-gcm, aes-256-gcm 或 chacha20-ietf-poly1305
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ShadowsocksInboundSettings {
-    pub address: String,
-    pub port: u16,
     pub method: String,
     pub password: String,
 }
@@ -57,6 +305,41 @@ pub struct ShadowsocksOutboundSettings {
     pub method: String,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TunInboundSettings {
+    pub name: Option<String>,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mtu: Option<i32>,
+    // 自动将默认路由指向 tun
+    // tun 目前只转发 IPv4 TCP，接管后 IPv6 和 UDP (包括 DNS) 都会被丢弃
+    pub auto: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DokodemoInboundSettings {
+    pub address: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VlessUser {
+    pub id: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VlessInboundSettings {
+    pub clients: Vec<VlessUser>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VlessOutboundSettings {
+    pub address: String,
+    pub port: u16,
+    pub id: String,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Inbound {
     pub port: Option<u16>,
@@ -65,6 +348,8 @@ pub struct Inbound {
     pub tag: String,
     // domain or socket addr
     pub settings: Option<Box<RawValue>>,
+    #[serde(rename = "streamSettings")]
+    pub stream_settings: Option<StreamSettings>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -76,6 +361,11 @@ pub struct Rule {
     pub domainKeyword: Option<Vec<String>>,
     pub regexp: Option<Vec<String>>,
     pub target: String,
+    // target 连接失败时依次尝试的 outbound，udp 跳过没有 udp handler 的 outbound
+    pub fallback: Option<Vec<String>>,
+    // 秒，包括 fallback 在内建立连接的总时间，默认 10
+    #[serde(rename = "connectTimeout")]
+    pub connect_timeout: Option<u64>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -92,11 +382,15 @@ impl Default for Config {
             general: GeneralSettings {
                 prefer_ipv6: false,
                 use_ipv6: false,
+                state_file: None,
+                connect_timeout: None,
+                connection_attempt_delay: None,
             },
             inbounds: Vec::new(),
             outbounds: Vec::new(),
             routes: Vec::new(),
             dns: None,
+            api: None,
         }
     }
 }
@@ -3,8 +3,9 @@ mod net;
 pub mod config;
 pub mod app;
 pub mod proxy;
+pub mod transport;
 
-use std::{sync::{Arc, Once}};
+use std::{net::{IpAddr, SocketAddr}, sync::{Arc, Once}};
 
 use app::{Dispatcher, DnsClient, InboundManager, OutboundManager, Router};
 use futures::future::BoxFuture;
@@ -58,7 +59,11 @@ pub fn start(config: config::Config, shutdown_handler: BoxFuture<'static, ()>) -
         });
         
         let inbound_manager = InboundManager::new(config.inbounds.clone());
-        let outbound_manager = Arc::new(OutboundManager::new(config.outbounds.clone())?);
+        let mut outbound_manager = OutboundManager::new(config.outbounds.clone())?;
+        if let Some(state_file) = &config.general.state_file {
+            outbound_manager.load_state(state_file);
+        }
+        let outbound_manager = Arc::new(outbound_manager);
         let router = Arc::new(Router::new(config.routes.clone()));
         let dns_client = Arc::new(RwLock::new(DnsClient::new(config.clone())));
         let context = Arc::new(Context::new(dns_client.clone()));
@@ -79,6 +84,11 @@ pub fn start(config: config::Config, shutdown_handler: BoxFuture<'static, ()>) -
     };
     tasks.push(shutdown_handler);
     tasks.push(inbound_futures);
+    tasks.push(Box::pin(outbound_manager.clone().run_health_checks(context.clone())));
+    if let Some(api) = &config.api {
+        let ip: IpAddr = api.address.parse().map_err(|err| anyhow!("invalid api address {} {}", api.address, err))?;
+        tasks.push(Box::pin(app::api::serve(SocketAddr::new(ip, api.port), outbound_manager.clone())));
+    }
     let runtime = newRuntime();
     runtime.block_on(futures::future::select_all(tasks));
     Ok(())
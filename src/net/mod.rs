@@ -1,13 +1,17 @@
 use std::{
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Deref, DerefMut},
+    sync::RwLock,
 };
 
-use tokio::net::TcpListener;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+
+use crate::config::SockoptSettings;
 
 mod stream;
-mod sys;
+pub mod sys;
 pub struct ProxyTcpListener {
     inner: TcpListener,
 }
@@ -33,3 +37,271 @@ impl DerefMut for ProxyTcpListener {
 }
 
 pub use self::{stream::ProxyStream};
+
+// tun 接管默认路由时，由 route monitor 更新为当前的物理网卡
+static AUTO_INTERFACE: RwLock<Option<String>> = RwLock::new(None);
+
+pub fn set_auto_interface(interface: Option<String>) {
+    *AUTO_INTERFACE.write().unwrap() = interface;
+}
+
+pub fn auto_interface() -> Option<String> {
+    AUTO_INTERFACE.read().unwrap().clone()
+}
+
+// outbound 的 socket 选项
+// tun 接管默认路由后，通过 interface 或 fwmark 让 outbound 的流量走物理网卡，避免回环
+#[derive(Clone, Debug, Default)]
+pub struct SocketOpts {
+    pub interface: Option<String>,
+    pub source_address: Option<IpAddr>,
+    pub fwmark: Option<u32>,
+    // streamSettings.sockopt，只作用于 tcp
+    pub sockopt: Option<SockoptSettings>,
+}
+
+impl SocketOpts {
+    pub fn is_empty(&self) -> bool {
+        self.interface.is_none() && self.source_address.is_none() && self.fwmark.is_none() && self.sockopt.is_none()
+    }
+
+    pub fn tcp_socket(&self, remote: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = self.socket(remote, Type::STREAM, Protocol::TCP)?;
+        if let Some(sockopt) = &self.sockopt {
+            set_tcp_options(&socket, sockopt, false)?;
+        }
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    // 没有 source_address 时绑定在与 remote 相同地址族的任意地址
+    pub fn udp_socket(&self, remote: SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let socket = self.socket(remote, Type::DGRAM, Protocol::UDP)?;
+        if self.source_address.is_none() {
+            let local = match remote {
+                SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            };
+            socket.bind(&local.into())?;
+        }
+        Ok(socket.into())
+    }
+
+    pub async fn connect_udp(&self, remote: SocketAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::from_std(self.udp_socket(remote)?)?;
+        socket.connect(remote).await?;
+        Ok(socket)
+    }
+
+    fn socket(&self, remote: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(remote), ty, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        if let Some(source) = self.source_address {
+            if source.is_ipv4() != remote.is_ipv4() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("source address {} can not connect to {}", source, remote),
+                ));
+            }
+            socket.bind(&SocketAddr::new(source, 0).into())?;
+        }
+        if let Some(interface) = &self.bind_interface(remote) {
+            bind_to_interface(&socket, interface)?;
+        }
+        if let Some(mark) = self.fwmark {
+            set_mark(&socket, mark)?;
+        }
+        Ok(socket)
+    }
+
+    // 没有指定 interface、sourceAddress 和 fwmark 时绑定到当前的物理网卡
+    // 回环地址不经过物理网卡，不自动绑定
+    fn bind_interface(&self, remote: SocketAddr) -> Option<String> {
+        match (&self.interface, self.source_address, self.fwmark) {
+            (Some(x), ..) => Some(x.clone()),
+            (None, None, None) if !is_loopback(remote.ip()) => auto_interface(),
+            _ => None,
+        }
+    }
+}
+
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(x) => x.is_loopback(),
+        IpAddr::V6(x) => x.is_loopback() || x.to_ipv4_mapped().map(|x| x.is_loopback()).unwrap_or(false),
+    }
+}
+
+// listener 为 true 时作用于 listening socket，accept 的连接继承这些选项
+pub fn set_tcp_options(socket: &Socket, sockopt: &SockoptSettings, listener: bool) -> io::Result<()> {
+    if let Some(no_delay) = sockopt.tcp_no_delay {
+        socket.set_nodelay(no_delay)?;
+    }
+    if let Some(size) = sockopt.receive_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = sockopt.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    set_linux_tcp_options(socket, sockopt, listener)
+}
+
+#[cfg(target_os = "linux")]
+fn set_linux_tcp_options(socket: &Socket, sockopt: &SockoptSettings, listener: bool) -> io::Result<()> {
+    if sockopt.tcp_fast_open == Some(true) {
+        sys::linux::set_tcp_fast_open(socket, listener)?;
+    }
+    if sockopt.tcp_keep_alive_idle.is_some()
+        || sockopt.tcp_keep_alive_interval.is_some()
+        || sockopt.tcp_keep_alive_count.is_some()
+    {
+        sys::linux::set_tcp_keepalive(
+            socket,
+            sockopt.tcp_keep_alive_idle,
+            sockopt.tcp_keep_alive_interval,
+            sockopt.tcp_keep_alive_count,
+        )?;
+    }
+    if let Some(timeout) = sockopt.tcp_user_timeout {
+        sys::linux::set_tcp_user_timeout(socket, timeout)?;
+    }
+    if let Some(algorithm) = &sockopt.tcp_congestion {
+        sys::linux::set_tcp_congestion(socket, algorithm)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_linux_tcp_options(_socket: &Socket, sockopt: &SockoptSettings, _listener: bool) -> io::Result<()> {
+    if sockopt.tcp_fast_open == Some(true)
+        || sockopt.tcp_keep_alive_idle.is_some()
+        || sockopt.tcp_keep_alive_interval.is_some()
+        || sockopt.tcp_keep_alive_count.is_some()
+        || sockopt.tcp_user_timeout.is_some()
+        || sockopt.tcp_congestion.is_some()
+    {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "tcp socket options are only supported on linux"));
+    }
+    Ok(())
+}
+
+// acceptors 个 listener 时设置 SO_REUSEPORT，由内核在 listener 之间分配连接
+pub fn create_tcp_listener(addr: SocketAddr, sockopt: &SockoptSettings, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    set_tcp_options(&socket, sockopt, true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// 只 bind 不 listen，port 为 0 时多个 SO_REUSEPORT listener 通过它得到同一个 port，不会被分配连接
+pub fn reserve_reuse_port(addr: SocketAddr) -> io::Result<(Socket, SocketAddr)> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    set_reuse_port(&socket)?;
+    socket.bind(&addr.into())?;
+    let addr = socket
+        .local_addr()?
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an inet address"))?;
+    Ok((socket, addr))
+}
+
+#[cfg(target_os = "linux")]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    sys::linux::set_reuse_port(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "acceptors are only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+pub fn bind_to_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    sys::linux::bind_to_device(socket, interface)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_interface(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "interface is only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    sys::linux::set_mark(socket, mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "fwmark is only supported on linux"))
+}
+
+#[tokio::test]
+async fn socket_opts_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = listener.local_addr().unwrap();
+    let opts = SocketOpts {
+        source_address: Some("127.0.0.1".parse().unwrap()),
+        ..Default::default()
+    };
+    let stream = opts.tcp_socket(remote).unwrap().connect(remote).await.unwrap();
+    assert_eq!(stream.local_addr().unwrap().ip(), remote.ip());
+    // 地址族不同
+    assert!(opts.tcp_socket("[::1]:80".parse().unwrap()).is_err());
+
+    let socket = SocketOpts::default().connect_udp("127.0.0.1:53".parse().unwrap()).await.unwrap();
+    assert!(socket.local_addr().unwrap().is_ipv4());
+
+    // tun 接管默认路由时，回环地址不绑定到物理网卡
+    set_auto_interface(Some("tunnel-test0".to_string()));
+    let opts = SocketOpts::default();
+    let interface = opts.bind_interface("1.1.1.1:80".parse().unwrap());
+    let stream = opts.tcp_socket(remote).unwrap().connect(remote).await;
+    assert!(opts.bind_interface("[::ffff:127.0.0.1]:80".parse().unwrap()).is_none());
+    set_auto_interface(None);
+    assert_eq!(interface.as_deref(), Some("tunnel-test0"));
+    assert!(stream.is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn tcp_options_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let sockopt = SockoptSettings {
+        tcp_fast_open: Some(true),
+        tcp_no_delay: Some(true),
+        tcp_keep_alive_idle: Some(30),
+        tcp_user_timeout: Some(5000),
+        // reno 总是编译在内核中
+        tcp_congestion: Some("reno".to_string()),
+        ..Default::default()
+    };
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+    set_tcp_options(&socket, &sockopt, false).unwrap();
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    let unknown = SockoptSettings {
+        tcp_congestion: Some("unknown".to_string()),
+        ..Default::default()
+    };
+    assert!(set_tcp_options(&socket, &unknown, false).is_err());
+
+    // TFO 的 connect 立即返回，数据随 SYN 发送
+    let listener = create_tcp_listener("127.0.0.1:0".parse().unwrap(), &sockopt, false).unwrap();
+    let remote = listener.local_addr().unwrap();
+    let opts = SocketOpts {
+        sockopt: Some(sockopt),
+        ..Default::default()
+    };
+    let mut stream = opts.tcp_socket(remote).unwrap().connect(remote).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let (mut accepted, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 5];
+    accepted.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
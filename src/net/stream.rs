@@ -10,7 +10,7 @@ use tokio::{
 };
 
 
-use crate::proxy::{create_bounded_tcp_socket};
+use super::SocketOpts;
 
 
 #[pin_project]
@@ -20,9 +20,9 @@ pub struct ProxyStream {
 }
 
 impl ProxyStream {
-    // connect will bypass tun routes, always directly connect
-    pub async fn connect(addr: SocketAddr) -> io::Result<ProxyStream> {
-        let socket = create_bounded_tcp_socket(addr.clone())?;
+    // 通过 opts 绑定物理网卡或 fwmark 时绕过 tun 的路由
+    pub async fn connect(addr: SocketAddr, opts: &SocketOpts) -> io::Result<ProxyStream> {
+        let socket = opts.tcp_socket(addr)?;
         let stream = socket.connect(addr).await?;
         Ok(ProxyStream { inner: stream })
     }
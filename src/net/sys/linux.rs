@@ -1,9 +1,17 @@
-use std::os::unix::prelude::AsRawFd;
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
 
-use socket2::Socket;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, UdpSocket},
+};
 
-pub fn bind_to_device(socket: &Socket, interface_name: &str) {
-    unsafe {
+pub fn bind_to_device(socket: &Socket, interface_name: &str) -> io::Result<()> {
+    let ret = unsafe {
         libc::setsockopt(
             socket.as_raw_fd(),
             libc::SOL_SOCKET,
@@ -12,4 +20,226 @@ pub fn bind_to_device(socket: &Socket, interface_name: &str) {
             interface_name.len() as _,
         )
     };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// 配合策略路由 (ip rule fwmark) 使用，需要 CAP_NET_ADMIN
+pub fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    set_int_option(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_MARK, mark as libc::c_int)
+}
+
+// listener 设置 TCP_FASTOPEN (值为等待 accept 的 TFO 连接队列长度)，client 设置 TCP_FASTOPEN_CONNECT
+// TCP_FASTOPEN_CONNECT 下 connect 立即返回，SYN 随第一次 write 一起发送
+pub fn set_tcp_fast_open(socket: &Socket, listener: bool) -> io::Result<()> {
+    if listener {
+        set_int_option(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, TCP_FASTOPEN_QUEUE_LEN)
+    } else {
+        set_int_option(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+    }
+}
+
+const TCP_FASTOPEN_QUEUE_LEN: libc::c_int = 256;
+
+// 秒，None 使用系统默认值 (net.ipv4.tcp_keepalive_*)
+pub fn set_tcp_keepalive(
+    socket: &Socket,
+    idle: Option<u32>,
+    interval: Option<u32>,
+    count: Option<u32>,
+) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    if let Some(idle) = idle {
+        set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle as libc::c_int)?;
+    }
+    if let Some(interval) = interval {
+        set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval as libc::c_int)?;
+    }
+    if let Some(count) = count {
+        set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count as libc::c_int)?;
+    }
+    Ok(())
+}
+
+// 毫秒，已发送的数据超过该时间没有被确认时关闭连接
+pub fn set_tcp_user_timeout(socket: &Socket, timeout: u32) -> io::Result<()> {
+    set_int_option(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, timeout as libc::c_int)
+}
+
+// 算法不存在或没有加载时返回 ENOENT，可用的算法见 net.ipv4.tcp_available_congestion_control
+pub fn set_tcp_congestion(socket: &Socket, algorithm: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr() as *const _,
+            algorithm.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    set_int_option(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)
+}
+
+// iptables REDIRECT 之后，通过 SO_ORIGINAL_DST 拿到连接原本的 destination
+// https://github.com/torvalds/linux/blob/master/net/netfilter/nf_conntrack_proto.c
+// 按 peer 的地址族选择 option，dual-stack listener 上 v4-mapped 的 client 对应的是 IPv4 的 NAT 记录
+pub fn get_original_destination<T: AsRawFd>(socket: &T, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let is_ipv6 = match peer {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(v6) => v6.ip().to_ipv4_mapped().is_none(),
+    };
+    let (level, name) = if is_ipv6 {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let ret = libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut storage as *mut _ as *mut _,
+            &mut len,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        SockAddr::new(storage, len).as_socket().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown original destination family")
+        })
+    }
+}
+
+fn set_int_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const _,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// TPROXY 需要 IP_TRANSPARENT，否则无法 bind / accept 非本机地址
+fn set_ip_transparent(socket: &Socket, is_ipv6: bool) -> io::Result<()> {
+    if is_ipv6 {
+        set_int_option(socket.as_raw_fd(), libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)
+    } else {
+        set_int_option(socket.as_raw_fd(), libc::SOL_IP, libc::IP_TRANSPARENT, 1)
+    }
+}
+
+pub fn create_transparent_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    set_ip_transparent(&socket, addr.is_ipv6())?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// recv_original_destination 为 true 时，通过 recv_from_with_destination 可以拿到 datagram 原本的 destination
+// 为 false 时用于伪造源地址回包
+pub fn create_transparent_udp_socket(
+    addr: SocketAddr,
+    recv_original_destination: bool,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    set_ip_transparent(&socket, addr.is_ipv6())?;
+    if recv_original_destination {
+        if addr.is_ipv6() {
+            set_int_option(socket.as_raw_fd(), libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
+        } else {
+            set_int_option(socket.as_raw_fd(), libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1)?;
+        }
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// 返回 (n, source, original destination)
+pub async fn recv_from_with_destination(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    socket
+        .async_io(Interest::READABLE, || recvmsg_with_destination(socket.as_raw_fd(), buf))
+        .await
+}
+
+fn recvmsg_with_destination(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    unsafe {
+        let mut source: libc::sockaddr_storage = mem::zeroed();
+        // u64 保证 cmsghdr 的对齐
+        let mut control = [0u64; 16];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut source as *mut _ as *mut _;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let n = libc::recvmsg(fd, &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let source = SockAddr::new(source, msg.msg_namelen)
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown source family"))?;
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+                || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_ORIGDSTADDR)
+            {
+                let mut destination: libc::sockaddr_storage = mem::zeroed();
+                let len = header.cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                std::ptr::copy_nonoverlapping(
+                    libc::CMSG_DATA(cmsg),
+                    &mut destination as *mut _ as *mut u8,
+                    usize::min(len, mem::size_of::<libc::sockaddr_storage>()),
+                );
+                let destination = SockAddr::new(destination, len as libc::socklen_t)
+                    .as_socket()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "unknown destination family")
+                    })?;
+                return Ok((n as usize, source, destination));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "original destination not found, IP_RECVORIGDSTADDR not set?",
+        ))
+    }
 }
@@ -0,0 +1,217 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll, Waker},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    time::Sleep,
+};
+
+use crate::{config::BlackholeOutboundSettings, Context};
+
+use super::{AnyStream, OutboundConnect, Session, TcpOutboundHandlerTrait, UdpOutboundHandlerTrait};
+
+const HTTP_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\n\
+Connection: close\r\n\
+Content-Type: text/html\r\n\
+Content-Length: 50\r\n\
+\r\n\
+<html><body><h1>403 Forbidden</h1></body></html>\r\n";
+
+// fatal access_denied
+const TLS_ALERT: &[u8] = &[0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x31];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Response {
+    None,
+    Http,
+    Tls,
+    // 按 client 的第一个数据选择
+    Auto,
+}
+
+impl Response {
+    pub fn new(response_type: &str) -> Result<Self> {
+        Ok(match response_type {
+            "none" => Response::None,
+            "http" => Response::Http,
+            "tls" => Response::Tls,
+            "auto" => Response::Auto,
+            _ => bail!("unsupported blackhole response type {}", response_type),
+        })
+    }
+
+    // first 为 client 发送的第一个数据，client 没有发送数据就关闭时为空
+    fn content(&self, first: &[u8]) -> &'static [u8] {
+        match self {
+            Response::None => &[],
+            Response::Http => HTTP_FORBIDDEN,
+            Response::Tls => TLS_ALERT,
+            Response::Auto => {
+                if first.is_empty() {
+                    &[]
+                } else if first[0] == 0x16 {
+                    TLS_ALERT
+                } else if httparse::Request::new(&mut [httparse::EMPTY_HEADER; 32]).parse(first).is_ok() {
+                    HTTP_FORBIDDEN
+                } else {
+                    &[]
+                }
+            }
+        }
+    }
+}
+
+pub struct TcpOutboundHandler {
+    response: Response,
+    delay: Duration,
+}
+
+impl TcpOutboundHandler {
+    pub fn new(settings: &BlackholeOutboundSettings) -> Result<Self> {
+        let response = match &settings.response {
+            Some(x) => Response::new(&x.response_type)?,
+            None => Response::None,
+        };
+        Ok(TcpOutboundHandler {
+            response,
+            delay: Duration::from_millis(settings.delay.unwrap_or(0)),
+        })
+    }
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    fn remote_addr(&self) -> OutboundConnect {
+        OutboundConnect::Drop
+    }
+    async fn handle(&self, _ctx: Arc<Context>, _sess: &Session, _stream: Option<AnyStream>) -> anyhow::Result<AnyStream> {
+        Ok(Box::new(BlackholeStream::new(self.response, self.delay)))
+    }
+}
+
+pub struct UdpOutboundHandler {}
+
+#[async_trait]
+impl UdpOutboundHandlerTrait for UdpOutboundHandler {
+    async fn handle(&self, _ctx: Arc<Context>, sess: &Session) -> anyhow::Result<UdpSocket> {
+        // 不建立 socket，dispatcher 直接丢弃 datagram
+        bail!("udp to {} dropped by blackhole", sess.destination)
+    }
+}
+
+// 丢弃写入的数据，等待 delay 后返回 response 并关闭
+struct BlackholeStream {
+    response: Response,
+    delay: Option<Pin<Box<Sleep>>>,
+    // 确定回复内容前为 None
+    content: Option<&'static [u8]>,
+    read_waker: Option<Waker>,
+}
+
+impl BlackholeStream {
+    fn new(response: Response, delay: Duration) -> Self {
+        let delay = if delay.is_zero() {
+            None
+        } else {
+            Some(Box::pin(tokio::time::sleep(delay)))
+        };
+        // none 不需要等待 client 的数据
+        let content = match response {
+            Response::None => Some(&[][..]),
+            _ => None,
+        };
+        BlackholeStream {
+            response,
+            delay,
+            content,
+            read_waker: None,
+        }
+    }
+
+    fn decide(&mut self, first: &[u8]) {
+        if self.content.is_none() {
+            self.content = Some(self.response.content(first));
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl AsyncRead for BlackholeStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let content = match self.content {
+            Some(x) => x,
+            None => {
+                self.read_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let n = content.len().min(buf.remaining());
+        buf.put_slice(&content[..n]);
+        self.content = Some(&content[n..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BlackholeStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.decide(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.decide(&[]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn blackhole_stream_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = BlackholeStream::new(Response::Auto, Duration::ZERO);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: ads.example.com\r\n\r\n").await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, HTTP_FORBIDDEN);
+
+    let mut stream = BlackholeStream::new(Response::Auto, Duration::ZERO);
+    stream.write_all(&[0x16, 0x03, 0x01, 0x00, 0x05]).await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, TLS_ALERT);
+
+    // client 没有发送数据就关闭
+    let mut stream = BlackholeStream::new(Response::Http, Duration::ZERO);
+    stream.shutdown().await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, HTTP_FORBIDDEN);
+
+    let start = tokio::time::Instant::now();
+    let mut stream = BlackholeStream::new(Response::None, Duration::from_millis(100));
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert!(buf.is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
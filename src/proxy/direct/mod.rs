@@ -1,27 +1,43 @@
-use std::{sync::Arc};
+use std::sync::Arc;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 
-use crate::Context;
+use crate::{net::SocketOpts, Context};
 
-use super::{TcpOutboundHandlerTrait, Session, UdpOutboundHandlerTrait, connect_to_remote_tcp, connect_to_remote_udp};
+use super::{
+    name_to_socket_addr, AnyStream, OutboundConnect, Session, TcpOutboundHandlerTrait,
+    UdpOutboundHandlerTrait,
+};
 
 pub struct TcpOutboundHandler{}
 
 #[async_trait]
 impl TcpOutboundHandlerTrait for TcpOutboundHandler {
-    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<TcpStream> {
-        connect_to_remote_tcp(ctx.dns_client.clone(), sess.destination.clone()).await
+    fn remote_addr(&self) -> OutboundConnect {
+        OutboundConnect::Direct
     }
+    async fn handle(&self, _ctx: Arc<Context>, _sess: &Session, stream: Option<AnyStream>) -> anyhow::Result<AnyStream> {
+        stream.ok_or_else(|| anyhow!("no stream for direct outbound"))
+    }
+}
+
+pub struct UdpOutboundHandler {
+    opts: SocketOpts,
 }
 
-pub struct UdpOutboundHandler{}
+impl UdpOutboundHandler {
+    pub fn new(opts: SocketOpts) -> Self {
+        UdpOutboundHandler { opts }
+    }
+}
 
 #[async_trait]
 impl UdpOutboundHandlerTrait for UdpOutboundHandler {
     async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<UdpSocket> {
-        connect_to_remote_udp(ctx.dns_client.clone(), sess.local_peer, sess.destination.clone()
-    ).await
+        // 按 destination 的地址族绑定任意地址 (或 sourceAddress)，不能绑定在 inbound 的 local_peer 上
+        let peer = name_to_socket_addr(ctx.dns_client.clone(), sess.destination.clone()).await?;
+        Ok(self.opts.connect_udp(peer).await?)
     }
 }
\ No newline at end of file
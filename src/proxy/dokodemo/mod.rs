@@ -0,0 +1,32 @@
+use std::io;
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use super::{Address, AnyStream, InboundResult, Session, TcpInboundHandlerTrait, UdpInboundHandlerTrait};
+
+// 任意门，将收到的连接和 datagram 全部转发到固定的 destination
+// https://www.v2ray.com/chapter_02/protocols/dokodemo.html
+pub struct TcpInboundHandler {
+    pub destination: Address,
+}
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, mut session: Session, stream: AnyStream) -> io::Result<InboundResult> {
+        session.destination = self.destination.clone();
+        Ok(InboundResult::FixedStream(stream, session))
+    }
+}
+
+pub struct UdpInboundHandler {
+    pub destination: Address,
+}
+
+#[async_trait]
+impl UdpInboundHandlerTrait for UdpInboundHandler {
+    async fn handle(&self, mut session: Session, socket: UdpSocket) -> io::Result<InboundResult> {
+        session.destination = self.destination.clone();
+        Ok(InboundResult::Datagram(socket, session))
+    }
+}
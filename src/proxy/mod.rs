@@ -1,28 +1,37 @@
 use core::fmt;
 use std::{
     io,
-    net::{IpAddr, SocketAddr}, sync::Arc, convert::TryFrom, fmt::Display, ops::Add,
+    net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex as StdMutex}, convert::TryFrom, fmt::Display, ops::Add,
+    time::Duration,
 };
 
 use anyhow::{
     anyhow
 };
 use async_trait::async_trait;
+use futures::{stream::FuturesUnordered, StreamExt};
 use log::{trace, debug};
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::{TcpSocket, UdpSocket, TcpStream}, sync::RwLock,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{UdpSocket, TcpStream}, sync::{Notify, RwLock},
+    time::Instant,
 };
 
-use crate::{app::DnsClient, Context};
+use crate::{app::DnsClient, config::SockoptSettings, net::SocketOpts, transport::AnyDialer, Context};
 
-#[cfg(target_os = "unix")]
-mod tun;
+#[cfg(target_os = "linux")]
+pub mod tun;
 pub mod socks;
 pub mod direct;
-mod shadowsocks;
+pub mod blackhole;
+pub mod dokodemo;
+#[cfg(target_os = "linux")]
+pub mod redirect;
+pub mod shadowsocks;
+#[cfg(target_os = "linux")]
+pub mod tproxy;
+pub mod vless;
 pub enum NetworkType {
     TCP,
     UDP,
@@ -101,7 +110,7 @@ impl Into<String> for Address {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Network {
     TCP,
     UDP
@@ -128,47 +137,57 @@ impl Session {
     }
 }
 
-pub fn create_bounded_udp_socket(addr: IpAddr) -> io::Result<UdpSocket> {
-    let socket = match addr {
-        IpAddr::V4(..) => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?,
-        IpAddr::V6(..) => Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?,
-    };
-    // let s: SockAddr = ;
-    match socket.bind(&SockAddr::from(SocketAddr::new(addr, 0))) {
-        Ok(..) => {},
-        Err(err) => {
-            log::error!("failed to bind socket {}", err.to_string())
-        }
-    }
-    match socket.set_nonblocking(true) {
-        Ok(..) => {},
-        Err(err) => {
-            log::error!("failed to set non blocking {}", err)
-        }
-    }
-    Ok(UdpSocket::from_std(socket.into())?)
-}
 
-pub fn create_bounded_tcp_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
-    let socket = match addr {
-        SocketAddr::V4(..) => Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?,
-        SocketAddr::V6(..) => Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?,
-    };
-    socket.bind(&addr.into());
-    socket.set_nonblocking(true);
-    Ok(TcpSocket::from_std_stream(socket.into()))
-}
 
 
 
 // ----------------------------
 // INBOUND
 pub enum InboundResult {
-    Stream(TcpStream, Session),
+    Stream(AnyStream, Session),
+    // outbound 建立连接后才回复 client，例如 socks5
+    DeferredStream(AnyStream, Session, AnyDeferredReply),
+    // destination 由配置固定 (dokodemo-door)，不通过嗅探 SNI 覆盖
+    FixedStream(AnyStream, Session),
     Datagram(UdpSocket, Session),
     NOT_SUPPORTED
 }
 
+// 连接 outbound 失败的原因，inbound 据此回复 client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectError {
+    General,
+    // 没有匹配的 rule
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TimedOut,
+}
+
+impl From<&anyhow::Error> for ConnectError {
+    fn from(err: &anyhow::Error) -> Self {
+        let kind = match err.chain().find_map(|x| x.downcast_ref::<io::Error>()) {
+            Some(x) => x.kind(),
+            None => return ConnectError::General,
+        };
+        match kind {
+            io::ErrorKind::NetworkUnreachable => ConnectError::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => ConnectError::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => ConnectError::ConnectionRefused,
+            io::ErrorKind::TimedOut => ConnectError::TimedOut,
+            _ => ConnectError::General,
+        }
+    }
+}
+
+pub type AnyDeferredReply = Box<dyn DeferredReplyTrait>;
+
+#[async_trait]
+pub trait DeferredReplyTrait: Send + Sync {
+    async fn reply(&self, stream: &mut AnyStream, result: Result<(), ConnectError>) -> io::Result<()>;
+}
+
 pub type AnyTcpInboundHandler = Arc<dyn TcpInboundHandlerTrait>;
 pub type AnyUdpInboundHandler = Arc<dyn UdpInboundHandlerTrait>;
 pub type AnyInboundHandler = Arc<dyn InboundHandlerTrait>;
@@ -194,7 +213,7 @@ impl InboundHandlerTrait for InboundHandler {
 
 #[async_trait]
 impl TcpInboundHandlerTrait for InboundHandler {
-    async fn handle(&self, sess: Session, stream: TcpStream) -> io::Result<InboundResult> {
+    async fn handle(&self, sess: Session, stream: AnyStream) -> io::Result<InboundResult> {
         if let Some(handler) = &self.tcp_handler {
             return handler.handle(sess, stream).await;
         }
@@ -224,7 +243,7 @@ impl InboundHandler {
 
 #[async_trait]
 pub trait TcpInboundHandlerTrait: Sync + Send + Unpin {
-    async fn handle(&self, session: Session, stream: TcpStream) -> io::Result<InboundResult>;
+    async fn handle(&self, session: Session, stream: AnyStream) -> io::Result<InboundResult>;
 }
 
 #[async_trait]
@@ -236,8 +255,7 @@ pub trait UdpInboundHandlerTrait: Sync + Send + Unpin {
 
 pub enum OutboundConnect {
     // used by socks, shadowsocks ... proxy protocol
-    // String can be socketaddr or domain name
-    Proxy(String, u16),
+    Proxy(Address),
     // direct protocol
     Direct,
     // drop
@@ -248,8 +266,9 @@ pub enum OutboundConnect {
 pub trait TcpOutboundHandlerTrait: Send + Sync + Unpin {
     // remote addr should be connected directly
     // no proxy involved
-    // fn remote_addr(&self) -> OutboundConnect;
-    async fn handle(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<TcpStream>;
+    fn remote_addr(&self) -> OutboundConnect;
+    // stream 由 OutboundHandler 按 remote_addr 通过 transport 建立，Drop 时为 None
+    async fn handle(&self, ctx: Arc<Context>, sess: &Session, stream: Option<AnyStream>) -> anyhow::Result<AnyStream>;
 }
 
 #[derive(Error, Debug)]
@@ -270,27 +289,233 @@ pub type AnyOutboundHandler = Arc<dyn AnyOutboundHandlerTrait>;
 
 pub struct OutboundHandler {
     pub tag: String,
+    pub dialer: AnyDialer,
     pub tcp_handler: Option<AnyTcpOutboundHandler>,
     pub udp_handler: Option<AnyUdpOutboundHandler>,
+    // 正在使用该 outbound 的连接数
+    connections: Arc<AtomicUsize>,
+}
+
+// drop 时减少 outbound 的连接数
+pub struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+    // 连接经过的 selector，任意一个切换成员时关闭连接
+    close: Vec<Arc<Notify>>,
+}
+
+impl ConnectionGuard {
+    // 没有经过 selector 时永远不会返回
+    pub async fn closed(&self) {
+        let notified: Vec<_> = self.close.iter().map(|x| Box::pin(x.notified())).collect();
+        if notified.is_empty() {
+            return futures::future::pending().await;
+        }
+        futures::future::select_all(notified).await;
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl OutboundHandler {
-    pub fn new(tag: String, tcp: Option<AnyTcpOutboundHandler>, udp: Option<AnyUdpOutboundHandler>) -> OutboundHandler {
-        OutboundHandler { tag , tcp_handler: tcp, udp_handler: udp }
+    pub fn new(tag: String, dialer: AnyDialer, tcp: Option<AnyTcpOutboundHandler>, udp: Option<AnyUdpOutboundHandler>) -> OutboundHandler {
+        OutboundHandler {
+            tag,
+            dialer,
+            tcp_handler: tcp,
+            udp_handler: udp,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    // 连接的整个生命周期内持有，close 为连接经过的 selector 的通知
+    pub fn track_connection(&self, close: Vec<Arc<Notify>>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            connections: self.connections.clone(),
+            close,
+        }
+    }
+
+    // 先通过 transport 建立 stream，再交给 tcp handler 完成协议握手
+    pub async fn connect_tcp(&self, ctx: Arc<Context>, sess: &Session) -> anyhow::Result<AnyStream> {
+        let tcp = match &self.tcp_handler {
+            Some(tcp) => tcp,
+            None => return Err(anyhow!("tag {} not have tcp handler", self.tag)),
+        };
+        let stream = match tcp.remote_addr() {
+            OutboundConnect::Proxy(addr) => Some(self.dialer.dial(ctx.clone(), &addr).await?),
+            OutboundConnect::Direct => Some(self.dialer.dial(ctx.clone(), &sess.destination).await?),
+            OutboundConnect::Drop => None,
+        };
+        tcp.handle(ctx, sess, stream).await
     }
 }
 
 pub trait StreamWrapperTrait: AsyncRead + AsyncWrite + Send + Sync + Unpin{}
 impl<T> StreamWrapperTrait for T where T: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+// outbound 返回的 stream 可能被协议层包装过 (vless, shadowsocks ...)
+pub type AnyStream = Box<dyn StreamWrapperTrait>;
+
+// UDP over stream, 每个 packet 都是 | 2 bytes length | payload |
+// 两个方向都超过 idle_timeout 没有 packet 时结束
+pub async fn relay_udp_over_stream(stream: AnyStream, socket: UdpSocket, idle_timeout: Duration) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let active = StdMutex::new(Instant::now());
+    tokio::select! {
+        res = copy_stream_to_socket(reader, &socket, &active) => res,
+        res = copy_socket_to_stream(&socket, writer, &active) => res,
+        _ = wait_idle(&active, idle_timeout) => Ok(()),
+    }
+}
 
+async fn wait_idle(active: &StdMutex<Instant>, idle_timeout: Duration) {
+    loop {
+        let deadline = *active.lock().unwrap() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
 
-pub async fn connect_to_remote_tcp(dns_client:Arc<RwLock<DnsClient>>, addr: Address) -> anyhow::Result<TcpStream>{
-    let socket_addr = name_to_socket_addr(dns_client, addr).await?;
-    trace!("resolved remote addr {}", socket_addr);
-    TcpStream::connect(socket_addr).await.or_else(|err| {
-        debug!("error when connect to {}, error {}", socket_addr, err);
-        Err(err.into())
-    })
+async fn copy_stream_to_socket(
+    mut reader: ReadHalf<AnyStream>,
+    socket: &UdpSocket,
+    active: &StdMutex<Instant>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(x) => x as usize,
+            // EOF, client closed
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        reader.read_exact(&mut buf[..len]).await?;
+        socket.send(&buf[..len]).await?;
+        *active.lock().unwrap() = Instant::now();
+    }
+}
+
+async fn copy_socket_to_stream(
+    socket: &UdpSocket,
+    mut writer: WriteHalf<AnyStream>,
+    active: &StdMutex<Instant>,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        writer.write_u16(n as u16).await?;
+        writer.write_all(&buf[..n]).await?;
+        *active.lock().unwrap() = Instant::now();
+    }
+}
+
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub async fn connect_to_remote_tcp(dns_client:Arc<RwLock<DnsClient>>, addr: Address, opts: &SocketOpts) -> anyhow::Result<TcpStream>{
+    // 配置和 DNS 查询使用同一个 read guard，连接前释放
+    let (addrs, connect_timeout, attempt_delay) = {
+        let dns_client = dns_client.read().await;
+        let general = &dns_client.config.general;
+        let addrs = match addr {
+            Address::Domain(ref name, port) => {
+                let ips = dns_client.lookup(name).await?;
+                let addrs: Vec<SocketAddr> = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                sort_addrs(addrs, general.prefer_ipv6)
+            }
+            Address::Ip(addr) => vec![addr],
+        };
+        (
+            addrs,
+            general.connect_timeout.map(Duration::from_millis).unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            general.connection_attempt_delay.map(Duration::from_millis).unwrap_or(DEFAULT_CONNECTION_ATTEMPT_DELAY),
+        )
+    };
+    trace!("resolved remote addr {} {:?}", addr, addrs);
+    match tokio::time::timeout(connect_timeout, happy_eyeballs(addrs, attempt_delay, opts)).await {
+        Ok(res) => res.map_err(|err| {
+            debug!("error when connect to {}, error {}", addr, err);
+            err.into()
+        }),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("connect to {} timeout", addr)).into()),
+    }
+}
+
+// RFC 8305 section 4，从首选的地址族开始，交替排列两个地址族的地址
+fn sort_addrs(addrs: Vec<SocketAddr>, prefer_ipv6: bool) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|x| x.is_ipv6() == prefer_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut sorted = Vec::new();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return sorted,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// RFC 8305 Happy Eyeballs v2
+// 每隔 attempt_delay 开始尝试下一个地址，某个地址失败时立即开始下一个，返回最先建立的连接
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, attempt_delay: Duration, opts: &SocketOpts) -> io::Result<TcpStream> {
+    // TCP_FASTOPEN_CONNECT 的 connect 不等待握手就返回，无法判断哪个地址可用，多个地址时不使用 TFO
+    let without_tfo;
+    let opts = match &opts.sockopt {
+        Some(sockopt) if addrs.len() > 1 && sockopt.tcp_fast_open == Some(true) => {
+            without_tfo = SocketOpts {
+                sockopt: Some(SockoptSettings { tcp_fast_open: None, ..sockopt.clone() }),
+                ..opts.clone()
+            };
+            &without_tfo
+        }
+        _ => opts,
+    };
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    let connect = |addr: SocketAddr| async move {
+        let res = match opts.tcp_socket(addr) {
+            Ok(socket) => socket.connect(addr).await,
+            Err(err) => Err(err),
+        };
+        (addr, res)
+    };
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => return Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect"))),
+            }
+        }
+        tokio::select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!("error when connect to {}, error {}", addr, err);
+                    last_err = Some(err);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(connect(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(attempt_delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(connect(addr));
+                }
+            }
+        }
+    }
 }
 
 pub async fn name_to_socket_addr(dns_client: Arc<RwLock<DnsClient>>, addr: Address) -> anyhow::Result<SocketAddr> {
@@ -298,13 +523,13 @@ pub async fn name_to_socket_addr(dns_client: Arc<RwLock<DnsClient>>, addr: Addre
         Address::Domain(name, port) => {
             match dns_client.read().await.lookup(&name).await {
                 Ok(ips) => {
-                    // TODO connect to multiple ips
+                    // 按地址族偏好排序，只使用第一个地址，tcp 通过 connect_to_remote_tcp 尝试全部地址
                     let ip = if let Some(ip) = ips.get(0) {
                         ip
                     }else {
                         return Err(anyhow!("dns not ip found"))
                     };
-                    SocketAddr::new(ip.clone(), port)
+                    SocketAddr::new(*ip, port)
                 },
                 Err(e) => {
                     return Err(e)
@@ -321,4 +546,48 @@ pub async fn connect_to_remote_udp(dns_client: Arc<RwLock<DnsClient>>, local: So
     let socket_addr = name_to_socket_addr(dns_client, peer).await?;
     UdpSocket::connect(&socket, socket_addr).await?;
     Ok(socket)
-}
\ No newline at end of file
+}
+#[test]
+fn sort_addrs_test() {
+    let addrs: Vec<SocketAddr> = vec!["1.1.1.1:80", "2.2.2.2:80", "3.3.3.3:80", "[::1]:80", "[::2]:80"]
+        .into_iter()
+        .map(|x| x.parse().unwrap())
+        .collect();
+    let sorted: Vec<String> = sort_addrs(addrs.clone(), true).iter().map(|x| x.to_string()).collect();
+    assert_eq!(sorted, vec!["[::1]:80", "1.1.1.1:80", "[::2]:80", "2.2.2.2:80", "3.3.3.3:80"]);
+    let sorted: Vec<String> = sort_addrs(addrs, false).iter().map(|x| x.to_string()).collect();
+    assert_eq!(sorted, vec!["1.1.1.1:80", "[::1]:80", "2.2.2.2:80", "[::2]:80", "3.3.3.3:80"]);
+}
+
+#[tokio::test]
+async fn happy_eyeballs_test() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // 拒绝连接的端口
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let addrs = vec![closed, closed, addr];
+    let stream = tokio::time::timeout(Duration::from_secs(2), happy_eyeballs(addrs, Duration::from_millis(50), &SocketOpts::default()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+
+    let err = happy_eyeballs(vec![closed], Duration::from_millis(50), &SocketOpts::default()).await.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+    // 开启 TFO 时，已有 cookie 的地址 connect 会立即成功，多个地址时仍然等待真正的握手
+    let opts = SocketOpts {
+        sockopt: Some(SockoptSettings {
+            tcp_fast_open: Some(true),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let stream = tokio::time::timeout(Duration::from_secs(2), happy_eyeballs(vec![closed, addr], Duration::from_millis(50), &opts))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+}
@@ -0,0 +1,50 @@
+use std::{io, net::SocketAddr};
+
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+
+use crate::{net::sys::linux::get_original_destination, transport::StreamListener};
+
+use super::{Address, AnyStream, InboundResult, Network, Session, TcpInboundHandlerTrait};
+
+// 透明代理，配合 iptables REDIRECT 使用
+// iptables -t nat -A PREROUTING -p tcp -j REDIRECT --to-ports <port>
+// 连接被 REDIRECT 到本地后，destination 从 SO_ORIGINAL_DST 中恢复
+pub struct TcpInboundHandler;
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, session: Session, stream: AnyStream) -> io::Result<InboundResult> {
+        // destination 已由 RedirectListener 填充
+        Ok(InboundResult::Stream(stream, session))
+    }
+}
+
+// SO_ORIGINAL_DST 需要原始 socket，只能在 accept 时获取
+pub struct RedirectListener {
+    inner: TcpListener,
+}
+
+impl RedirectListener {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(RedirectListener {
+            inner: TcpListener::bind(addr).await?,
+        })
+    }
+}
+
+#[async_trait]
+impl StreamListener for RedirectListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        let (stream, peer) = self.inner.accept().await?;
+        let local = stream.local_addr()?;
+        let destination = get_original_destination(&stream, peer)?;
+        let session = Session {
+            destination: Address::Ip(destination),
+            network: Network::TCP,
+            local_peer: local,
+            peer_address: peer,
+        };
+        Ok((Box::new(stream), session))
+    }
+}
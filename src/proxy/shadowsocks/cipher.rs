@@ -18,6 +18,7 @@ pub fn password_to_cipher_key(password: &str, cipher_len: usize) -> io::Result<V
         last_digest = calc(&[last_digest, pass_bytes.to_vec()].concat());
         key.extend_from_slice(&*&last_digest);
     }
+    key.truncate(cipher_len);
     Ok(key)
 }
 pub struct CipherInfo {
@@ -68,6 +69,10 @@ lazy_static! {
             "aes-256-gcm",
             CipherInfo::new(32, 32, 12, 16, &aead::AES_256_GCM),
         );
+        m.insert(
+            "chacha20-ietf-poly1305",
+            CipherInfo::new(32, 32, 12, 16, &aead::CHACHA20_POLY1305),
+        );
         m
     };
 }
@@ -113,7 +118,7 @@ pub struct AeadEncryptor {
 
 impl AeadEncryptor {
     pub fn new(valid_key_from_hkdf: &[u8], algorithm: &'static Algorithm) -> anyhow::Result<Self> {
-        let nonce_sequence = NonceSequenceGenerator::new(algorithm.key_len());
+        let nonce_sequence = NonceSequenceGenerator::new(algorithm.nonce_len());
         let key = UnboundKey::new(&algorithm, valid_key_from_hkdf)
             .map_err(|_| anyhow!("unboundKey failed"))?;
         Ok(Self {
@@ -141,7 +146,7 @@ pub struct AeadDecryptor {
 
 impl AeadDecryptor {
     pub fn new(psk: &[u8], algorithm: &'static Algorithm) -> anyhow::Result<Self> {
-        let nonce_sequence = NonceSequenceGenerator::new(algorithm.key_len());
+        let nonce_sequence = NonceSequenceGenerator::new(algorithm.nonce_len());
         let key = UnboundKey::new(&algorithm, psk).map_err(|_| anyhow!("unboundKey failed"))?;
         Ok(Self {
             key: LessSafeKey::new(key),
@@ -175,7 +180,7 @@ impl AEADCipher {
         let s = String::from("ss-subkey");
         let info = s.as_bytes();
         let key = hkdf(psk, salt, info, self.algorithm.key_len())?;
-        AeadDecryptor::new(psk, self.algorithm)
+        AeadDecryptor::new(key.as_ref(), self.algorithm)
     }
     pub fn key_len(&self) -> usize {
         self.algorithm.key_len()
@@ -0,0 +1,34 @@
+use std::io;
+
+use async_trait::async_trait;
+use log::error;
+
+use crate::proxy::{AnyStream, InboundResult, Network, Session, TcpInboundHandlerTrait};
+
+use super::{read_address, CipherKey, ShadowsocksStream};
+
+pub struct TcpInboundHandler {
+    pub key: CipherKey,
+}
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, conn: Session, stream: AnyStream) -> io::Result<InboundResult> {
+        let mut stream = ShadowsocksStream::new(stream, &self.key);
+        // 第一个 chunk 以目标地址开头，密码错误时解密失败
+        let destination = match read_address(&mut stream).await {
+            Ok(x) => x,
+            Err(err) => {
+                error!("failed to process shadowsocks inbound {}", err);
+                return Err(err);
+            }
+        };
+        let session = Session {
+            destination,
+            network: Network::TCP,
+            local_peer: conn.local_peer,
+            peer_address: conn.peer_address,
+        };
+        Ok(InboundResult::Stream(Box::new(stream), session))
+    }
+}
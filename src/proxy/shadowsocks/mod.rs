@@ -3,18 +3,113 @@ use futures::ready;
 use rand::{prelude::StdRng, Rng, SeedableRng};
 use std::{
     io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
 use self::cipher::{
     password_to_cipher_key, AEADCipher, AeadDecryptor, AeadEncryptor, Method, INFOS,
 };
 
+use super::Address;
+
 mod cipher;
+mod inbound;
+mod outbound;
+
+pub use self::inbound::TcpInboundHandler;
+pub use self::outbound::TcpOutboundHandler;
 
 const MAX_PAYLOAD_LEN: usize = 0x3fff;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+// method 和由 password 生成的 key，inbound 和 outbound 共用
+#[derive(Clone)]
+pub struct CipherKey {
+    method: &'static str,
+    key: Vec<u8>,
+}
+
+impl CipherKey {
+    /// method:
+    /// 1. aes-128-gcm
+    /// 2. aes-256-gcm
+    /// 3. chacha20-ietf-poly1305
+    pub fn new(method: &str, password: &str) -> io::Result<Self> {
+        let (method, info) = match INFOS.get_key_value(method) {
+            Some(x) => x,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported shadowsocks method {}", method),
+                ))
+            }
+        };
+        Ok(CipherKey {
+            method,
+            key: password_to_cipher_key(password, info.key_len)?,
+        })
+    }
+}
+
+// 和 socks5 相同的地址格式
+// | atyp (1) | address | port (2) |
+fn encode_address(buf: &mut BytesMut, address: &Address) {
+    match address {
+        Address::Domain(name, _) => {
+            buf.put_u8(ATYP_DOMAIN);
+            buf.put_u8(name.len() as u8);
+            buf.put_slice(name.as_bytes());
+        }
+        Address::Ip(SocketAddr::V4(v4)) => {
+            buf.put_u8(ATYP_IPV4);
+            buf.put_slice(&v4.ip().octets());
+        }
+        Address::Ip(SocketAddr::V6(v6)) => {
+            buf.put_u8(ATYP_IPV6);
+            buf.put_slice(&v6.ip().octets());
+        }
+    }
+    buf.put_u16(address.port());
+}
+
+async fn read_address<T>(stream: &mut T) -> io::Result<Address>
+where
+    T: AsyncRead + Unpin,
+{
+    let address = match stream.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            let port = stream.read_u16().await?;
+            Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            let port = stream.read_u16().await?;
+            Address::Domain(String::from_utf8_lossy(&name).to_string(), port)
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            let port = stream.read_u16().await?;
+            Address::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        atyp => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown shadowsocks atyp {}", atyp),
+            ))
+        }
+    };
+    Ok(address)
+}
 
 enum ReadState {
     // 开始阶段，等待协议开头的salt
@@ -23,30 +118,24 @@ enum ReadState {
     WaitingPayload(usize),
 }
 
-enum WriteState {
-    WaitingSalt,
-    WaitingChunk,
-    WritingChunk(usize),
-}
 // shadowsocks 协议分析
 // https://chaochaogege.com/2022/05/24/58/
-// 针对 0x3fff 的处理
-// poll_write 要求返回 how many bytes written，所以caller poll_write之后需将buf切片，未写完的数据继续写
-// ShadowsocksStream 只需要一次处理少于 0x3fff 的数据即可
-//
-// https://github.com/iamwwc/shadowsocks-rust/blob/218c6ec0e302977212ed4f8ec4816337780789aa/crates/shadowsocks/src/relay/tcprelay/proxy_stream/client.rs#L210
-// shadowsocks-rust encrypted poll_write 多次循环全部将数据写完，而不是返回一次最多写入的bytes
-struct ShadowsocksStream<T> {
+// AEAD stream: [salt][encrypted length][length tag][encrypted payload][payload tag]...
+// 每个 chunk 的 payload 最多 0x3fff，poll_write 每次最多加密一个 chunk，返回写入的 bytes
+// 加密后的数据先放入 write_buf，下一次 poll_write 或 poll_flush 前写完
+pub struct ShadowsocksStream<T> {
     stream: T,
+    // 从 stream 读到的密文
     read_buf: BytesMut,
+    // 解密后还没有返回的 payload
+    plain_buf: BytesMut,
     read_state: ReadState,
 
     write_buf: BytesMut,
-    write_state: WriteState,
 
     psk: Vec<u8>,
-    // WaitingSalt 阶段才能初始化
     cipher: AEADCipher,
+    // 各自的 salt 到达后才能初始化
     encryptor: Option<AeadEncryptor>,
     decryptor: Option<AeadDecryptor>,
 }
@@ -54,24 +143,19 @@ struct ShadowsocksStream<T> {
 // https://github.com/v2fly/v2ray-core/blob/ca5695244c383870aed1976a59ae6e5eda94f999/proxy/shadowsocks/config.go#L228
 
 impl<T> ShadowsocksStream<T> {
-    /// method:
-    /// 1. aes-128-gcm
-    /// 2. aes-256-gcm
-    pub fn new(stream: T, method: &str, password: String) -> io::Result<Self> {
-        let m = INFOS.get(&method).unwrap();
-        let strong_password = password_to_cipher_key(&*password, m.key_len)?;
-        let cipher = AEADCipher::new(m.algorithm);
-        Ok(Self {
+    pub fn new(stream: T, key: &CipherKey) -> Self {
+        let info = INFOS.get(key.method).unwrap();
+        Self {
             stream,
             read_buf: BytesMut::new(),
-            write_buf: BytesMut::new(),
+            plain_buf: BytesMut::new(),
             read_state: ReadState::WaitingSalt,
-            write_state: WriteState::WaitingSalt,
-            cipher,
+            write_buf: BytesMut::new(),
+            psk: key.key.clone(),
+            cipher: AEADCipher::new(info.algorithm),
             encryptor: None,
             decryptor: None,
-            psk: strong_password,
-        })
+        }
     }
 }
 
@@ -79,36 +163,30 @@ impl<T> ShadowsocksStream<T>
 where
     T: AsyncRead + Unpin,
 {
-    fn poll_read_exact(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<usize>> {
+    // read_buf 中至少有 size 个 bytes，在 chunk 边界上 EOF 时返回 false
+    fn poll_read_exact(&mut self, cx: &mut Context<'_>, size: usize) -> Poll<io::Result<bool>> {
         while self.read_buf.len() < size {
             let len = self.read_buf.len();
-            let additional = size - len;
-            self.read_buf.reserve(additional);
-            let dst_buf = unsafe {
-                // [u8] => *[u8] => *[MaybeUninit<u8>] => [MaybeUninit<u8>] => &mut [MaybeUninit<u8>]
-                &mut *(&mut self.read_buf[len..len + additional] as *mut _ as *mut _)
-            };
-            let mut read_buf = ReadBuf::uninit(dst_buf);
-            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf))?;
+            self.read_buf.resize(size, 0);
+            let mut read_buf = ReadBuf::new(&mut self.read_buf[len..]);
+            let res = Pin::new(&mut self.stream).poll_read(cx, &mut read_buf);
             let n = read_buf.filled().len();
+            self.read_buf.truncate(len + n);
+            ready!(res)?;
             if n == 0 {
-                //  If the difference is 0, EOF has been reached.
-                if self.read_buf.is_empty() {
-                    // ok
-                    return Ok(0).into();
-                } else {
-                    // read_buf还有数据，但 read 却返回0，说明 EOF
-                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF!")).into();
+                if len == 0 {
+                    return Ok(false).into();
                 }
+                // read_buf还有数据，但 read 却返回0，说明 EOF
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF in shadowsocks chunk")).into();
             }
-            self.read_buf.advance(n);
         }
-        Ok(size).into()
+        Ok(true).into()
     }
 }
 
 fn map_crypto_error() -> io::Error {
-    io::Error::new(io::ErrorKind::Other, "crypto error")
+    io::Error::new(io::ErrorKind::InvalidData, "crypto error")
 }
 
 impl<T> AsyncRead for ShadowsocksStream<T>
@@ -120,131 +198,118 @@ where
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
         loop {
-            match self.read_state {
+            if !me.plain_buf.is_empty() {
+                let n = usize::min(buf.remaining(), me.plain_buf.len());
+                buf.put_slice(&me.plain_buf[..n]);
+                me.plain_buf.advance(n);
+                return Ok(()).into();
+            }
+            match me.read_state {
                 ReadState::WaitingSalt => {
-                    let salt_len = self.cipher.key_len();
-                    ready!(self.poll_read_exact(cx, salt_len))?;
-                    let decryptor = self
-                        .cipher
-                        .decryptor(&self.psk, &self.read_buf[..salt_len])
-                        .map_err(|x| map_crypto_error())?;
-                    self.decryptor.replace(decryptor);
-                    self.read_buf.clear();
-                    self.read_state = ReadState::WaitingLength;
+                    let salt_len = me.cipher.key_len();
+                    if !ready!(me.poll_read_exact(cx, salt_len))? {
+                        return Ok(()).into();
+                    }
+                    let salt = me.read_buf.split_to(salt_len);
+                    let decryptor = me.cipher.decryptor(&me.psk, &salt).map_err(|_| map_crypto_error())?;
+                    me.decryptor.replace(decryptor);
+                    me.read_state = ReadState::WaitingLength;
                 }
                 ReadState::WaitingLength => {
-                    let tag_len = self.cipher.tag_len();
-                    let encrypted_length_field_len = 2 + tag_len;
-                    ready!(self.poll_read_exact(cx, encrypted_length_field_len))?;
+                    let encrypted_length_field_len = 2 + me.cipher.tag_len();
+                    if !ready!(me.poll_read_exact(cx, encrypted_length_field_len))? {
+                        return Ok(()).into();
+                    }
+                    let mut chunk = me.read_buf.split_to(encrypted_length_field_len);
                     // decryptor should always be Some
-                    let me = &mut *self;
                     let dec = me.decryptor.as_mut().unwrap();
-                    dec.decrypt(&mut me.read_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    let buf = &self.read_buf;
-                    let n = u16::from_be_bytes([buf[0], buf[1]]);
-                    self.read_state = ReadState::WaitingPayload(n as usize);
-                    self.read_buf.clear();
+                    dec.decrypt(&mut chunk).map_err(|_| map_crypto_error())?;
+                    let n = u16::from_be_bytes([chunk[0], chunk[1]]) as usize & MAX_PAYLOAD_LEN;
+                    me.read_state = ReadState::WaitingPayload(n);
                 }
                 ReadState::WaitingPayload(n) => {
-                    let encrypted_payload_field_len = self.cipher.tag_len() + n;
-                    ready!(self.poll_read_exact(cx, encrypted_payload_field_len))?;
-                    let me = &mut *self;
-                    let dec = me.decryptor.as_mut().unwrap();
-                    dec.decrypt(&mut me.read_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    assert!(n == self.read_buf.len());
-                    let remaining = usize::min(buf.remaining(), self.read_buf.len());
-                    buf.put_slice(&self.read_buf[..remaining]);
-                    if remaining < n {
-                        self.read_state = ReadState::WaitingPayload(n - remaining);
-                    } else {
-                        self.read_buf.clear();
-                        self.read_state = ReadState::WaitingLength;
+                    let encrypted_payload_field_len = n + me.cipher.tag_len();
+                    if !ready!(me.poll_read_exact(cx, encrypted_payload_field_len))? {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF in shadowsocks chunk")).into();
                     }
-                    return Ok(()).into();
+                    let mut chunk = me.read_buf.split_to(encrypted_payload_field_len);
+                    let dec = me.decryptor.as_mut().unwrap();
+                    dec.decrypt(&mut chunk).map_err(|_| map_crypto_error())?;
+                    chunk.truncate(n);
+                    me.plain_buf = chunk;
+                    me.read_state = ReadState::WaitingLength;
                 }
             }
         }
     }
 }
 
+impl<T> ShadowsocksStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into()).into();
+            }
+            self.write_buf.advance(n);
+        }
+        Ok(()).into()
+    }
+}
+
 impl<T> AsyncWrite for ShadowsocksStream<T>
 where
     T: Unpin + AsyncWrite,
 {
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buf(cx))?;
         Pin::new(&mut self.stream).poll_flush(cx)
     }
     fn poll_shutdown(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buf(cx))?;
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-        mut buf: &[u8],
+        buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let me = &mut *self;
-        buf = &buf[..MAX_PAYLOAD_LEN];
-        loop {
-            match me.write_state {
-                WriteState::WaitingSalt => {
-                    let salt_len = me.cipher.key_len();
-                    // https://github.com/v2fly/v2ray-core/blob/0746740b1072185634ef0873f1607f922a28efea/proxy/shadowsocks/protocol.go#L104
-                    // secure random number
-                    let mut srn = rand::rngs::StdRng::from_entropy();
-                    for i in 0..salt_len {
-                        me.write_buf[i] = srn.gen();
-                    }
-                    let encryptor = me
-                        .cipher
-                        .encryptor(&me.psk, &me.write_buf[..salt_len])
-                        .map_err(|x| map_crypto_error())?;
-                    // salt 写走
-                    ready!(Pin::new(&mut me.stream).poll_write(cx, &me.write_buf[..salt_len]))?;
-                    me.write_buf.clear();
-                    me.encryptor.replace(encryptor);
-                    me.write_state = WriteState::WaitingChunk;
-                }
-                WriteState::WaitingChunk => {
-                    // length(2) tag(x) + payload(length) tag(x)
-                    let encrypted_payload_length = 2 + me.cipher.tag_len() * 2 + buf.len();
-                    let real_payload_len = buf.len() as u16;
-
-                    me.write_buf.reserve(encrypted_payload_length);
-                    me.write_buf.put_slice(&u16::to_be_bytes(real_payload_len));
-                    let enc = me.encryptor.as_mut().unwrap();
-                    enc.encrypt(&mut me.write_buf)
-                        .map_err(|x| map_crypto_error())?;
-
-                    unsafe {
-                        me.write_buf.set_len(2 + me.cipher.tag_len());
-                    }
-                    me.write_buf.put_slice(&buf);
-                    enc.encrypt(&mut me.write_buf)
-                        .map_err(|x| map_crypto_error())?;
-                    unsafe { me.write_buf.set_len(encrypted_payload_length) };
-                    me.write_state = WriteState::WritingChunk(0);
-                }
-                WriteState::WritingChunk(ref mut total_len) => {
-                    // write all
-                    // so always return Ok(buf.len())
-                    while (*total_len < me.write_buf.len()) {
-                        let n =
-                            ready!(Pin::new(&mut me.stream)
-                                .poll_write(cx, &me.write_buf[*total_len..]))?;
-                        *total_len += n;
-                    }
-                    me.write_buf.clear();
-                    me.write_state = WriteState::WaitingChunk;
-                    return Ok(buf.len()).into();
-                }
-            }
+        ready!(me.poll_write_buf(cx))?;
+        if buf.is_empty() {
+            return Ok(0).into();
         }
+        if me.encryptor.is_none() {
+            // https://github.com/v2fly/v2ray-core/blob/0746740b1072185634ef0873f1607f922a28efea/proxy/shadowsocks/protocol.go#L104
+            // secure random number
+            let mut salt = vec![0u8; me.cipher.key_len()];
+            StdRng::from_entropy().fill(&mut salt[..]);
+            let encryptor = me.cipher.encryptor(&me.psk, &salt).map_err(|_| map_crypto_error())?;
+            me.encryptor.replace(encryptor);
+            me.write_buf.put_slice(&salt);
+        }
+        let n = usize::min(buf.len(), MAX_PAYLOAD_LEN);
+        let enc = me.encryptor.as_mut().unwrap();
+        // length(2) tag(x) + payload(length) tag(x)
+        let mut length = BytesMut::from(&(n as u16).to_be_bytes()[..]);
+        enc.encrypt(&mut length).map_err(|_| map_crypto_error())?;
+        let mut payload = BytesMut::from(&buf[..n]);
+        enc.encrypt(&mut payload).map_err(|_| map_crypto_error())?;
+        me.write_buf.put_slice(&length);
+        me.write_buf.put_slice(&payload);
+        // 尽量立即写出，剩余的在下一次 poll_write 或 poll_flush 时写出
+        if let Poll::Ready(Err(err)) = me.poll_write_buf(cx) {
+            return Err(err).into();
+        }
+        Ok(n).into()
     }
 }
 
@@ -282,11 +347,8 @@ impl ShadowsocksDatagram {
     pub fn encrypt(&self, mut buf: BytesMut) -> io::Result<Vec<u8>> {
         // generate salt
         let salt_len = self.cipher.key_len();
-        let mut encrypted_buf = Vec::new();
-        let mut rng = StdRng::from_entropy();
-        for i in 0..salt_len {
-            encrypted_buf[i] = rng.gen();
-        }
+        let mut encrypted_buf = vec![0u8; salt_len];
+        StdRng::from_entropy().fill(&mut encrypted_buf[..]);
         let mut encryptor = self
             .cipher
             .encryptor(&self.psk, &encrypted_buf[..salt_len])
@@ -322,3 +384,51 @@ fn stream_test() {
     let x = Method::AES_192_GCM;
     println!("{}", x.to_string());
 }
+
+#[tokio::test]
+async fn stream_roundtrip_test() {
+    use tokio::io::AsyncWriteExt;
+
+    let key = CipherKey::new("chacha20-ietf-poly1305", "password").unwrap();
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = ShadowsocksStream::new(client, &key);
+    let mut server = ShadowsocksStream::new(server, &key);
+    // 超过一个 chunk 的数据
+    let data: Vec<u8> = (0..MAX_PAYLOAD_LEN * 2 + 100).map(|x| x as u8).collect();
+    let expected = data.clone();
+    let writer = tokio::spawn(async move {
+        let mut buf = BytesMut::new();
+        encode_address(&mut buf, &Address::Domain("example.com".to_string(), 443));
+        client.write_all(&buf).await.unwrap();
+        client.write_all(&data).await.unwrap();
+        client.shutdown().await.unwrap();
+    });
+    let destination = read_address(&mut server).await.unwrap();
+    assert_eq!(destination.to_string(), "example.com:443");
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, expected);
+    writer.await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_interop_test() {
+    // 由其他 shadowsocks 实现生成: aes-128-gcm, password "password", salt 00..0f
+    // 内容为 example.com:80 + "hello"
+    let encrypted = "000102030405060708090a0b0c0d0e0f5c3a018ad5a3dade7192a2cad7061ed12e91ff1a7ea18456ee41050a92f7f435fa191163cd189da5de5b6da6ae7e83c9795e345a4512";
+    let encrypted: Vec<u8> = (0..encrypted.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&encrypted[i..i + 2], 16).unwrap())
+        .collect();
+    let key = CipherKey::new("aes-128-gcm", "password").unwrap();
+    let mut stream = ShadowsocksStream::new(&encrypted[..], &key);
+    let destination = read_address(&mut stream).await.unwrap();
+    assert_eq!(destination.to_string(), "example.com:80");
+    let mut payload = Vec::new();
+    stream.read_to_end(&mut payload).await.unwrap();
+    assert_eq!(payload, b"hello");
+
+    let key = CipherKey::new("aes-128-gcm", "wrong").unwrap();
+    let mut stream = ShadowsocksStream::new(&encrypted[..], &key);
+    assert!(read_address(&mut stream).await.is_err());
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use log::trace;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    proxy::{Address, AnyStream, OutboundConnect, Session, TcpOutboundHandlerTrait},
+    Context,
+};
+
+use super::{encode_address, CipherKey, ShadowsocksStream};
+
+pub struct TcpOutboundHandler {
+    pub address: Address,
+    pub key: CipherKey,
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    fn remote_addr(&self) -> OutboundConnect {
+        OutboundConnect::Proxy(self.address.clone())
+    }
+    async fn handle(&self, _ctx: Arc<Context>, session: &Session, stream: Option<AnyStream>) -> anyhow::Result<AnyStream> {
+        trace!("connect to shadowsocks server {}", self.address);
+        let stream = stream.ok_or_else(|| anyhow!("no stream for shadowsocks outbound"))?;
+        let mut stream = ShadowsocksStream::new(stream, &self.key);
+        // 目标地址和后续数据一样加密在 chunk 里
+        let mut buf = BytesMut::new();
+        encode_address(&mut buf, &session.destination);
+        stream.write_all(&buf).await?;
+        Ok(Box::new(stream))
+    }
+}
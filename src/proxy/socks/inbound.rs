@@ -3,26 +3,38 @@ use std::io;
 
 use crate::{
     proxy::{
-        socks::handshake_as_server, Session, InboundResult, TcpInboundHandlerTrait,
-        UdpInboundHandlerTrait,
+        socks::{accept_as_server, reply_code, write_reply}, AnyStream, ConnectError,
+        DeferredReplyTrait, Session, InboundResult, TcpInboundHandlerTrait, UdpInboundHandlerTrait,
     },
 };
 use async_trait::async_trait;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::UdpSocket;
 
 pub struct TcpInboundHandler;
 
 #[async_trait]
 impl TcpInboundHandlerTrait for TcpInboundHandler {
-    async fn handle(&self, _conn: Session, mut stream: TcpStream) -> io::Result<InboundResult> {
-        let session = match handshake_as_server(&mut stream).await {
+    async fn handle(&self, conn: Session, mut stream: AnyStream) -> io::Result<InboundResult> {
+        let session = match accept_as_server(&mut stream, &conn).await {
             Ok(session) => session,
             Err(err) => {
                 error!("failed to process socks inbound {}", err);
                 return Err(io::Error::new(io::ErrorKind::Other, "unknown"));
             }
         };
-        Ok(InboundResult::Stream(stream, session))
+        Ok(InboundResult::DeferredStream(stream, session, Box::new(Reply)))
+    }
+}
+
+// 连接 outbound 后再回复 client，失败时 client 能得到对应的 REP
+struct Reply;
+
+#[async_trait]
+impl DeferredReplyTrait for Reply {
+    async fn reply(&self, stream: &mut AnyStream, result: Result<(), ConnectError>) -> io::Result<()> {
+        write_reply(stream, reply_code(result))
+            .await
+            .map_err(|err| io::Error::other(err.to_string()))
     }
 }
 
@@ -30,7 +42,7 @@ pub struct UdpInboundHandler;
 
 #[async_trait]
 impl UdpInboundHandlerTrait for UdpInboundHandler {
-    async fn handle(&self, conn: Session, socket: UdpSocket) -> io::Result<InboundResult> {
+    async fn handle(&self, _conn: Session, _socket: UdpSocket) -> io::Result<InboundResult> {
         // socks5 对 udp 会有单独的连接流程
         // 由于 udp 的connectionless 特性，所以 client 只发送一次，header， data 都包含在其中
         // https://datatracker.ietf.org/doc/html/rfc1928#section-7
@@ -41,6 +53,7 @@ impl UdpInboundHandlerTrait for UdpInboundHandler {
         // does not support fragmentation MUST drop any datagram whose FRAG
         // field is other than X'00'.
         // https://github.com/iamwwc/v2ray-core/blob/02f251ebecbf21095c7b74cb3f0feaed0927d3f9/proxy/socks/protocol.go#L321
-        Ok(InboundResult::Datagram(socket, conn))
+        // TODO UDP ASSOCIATE 未实现，每个 datagram 的 header 都需要解析，不能直接交给 dispatcher
+        Ok(InboundResult::NOT_SUPPORTED)
     }
 }
@@ -8,10 +8,7 @@ use std::{
 use anyhow::{anyhow, bail, Result};
 use log::trace;
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::proxy::{Address, Session};
 
@@ -24,7 +21,7 @@ pub use self::inbound::UdpInboundHandler;
 pub use self::outbound::TcpOutboundHandler;
 pub use self::outbound::UdpOutboundHandler;
 
-use super::{Network, StreamWrapperTrait};
+use super::{ConnectError, Network, StreamWrapperTrait};
 const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
 const CMD_CONNECT: u8 = 0x01;
 const CMD_BIND: u8 = 0x02;
@@ -32,6 +29,14 @@ const CMD_UDP_ASSOCIATE: u8 = 0x03;
 const TYPE_IPV4: u8 = 0x01;
 const TYPE_DOMAIN: u8 = 0x03;
 const TYPE_IPV6: u8 = 0x04;
+// https://datatracker.ietf.org/doc/html/rfc1928#section-6
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
 // as client
 pub async fn handshake_as_client<T>(stream: &mut T, session: &Session) -> Result<()>
 where
@@ -83,7 +88,20 @@ fn build_request(buf: &mut Vec<u8>, session: &Session) {
 }
 
 // as server
-pub async fn handshake_as_server(stream: &mut TcpStream) -> Result<Session> {
+pub async fn handshake_as_server<T>(stream: &mut T, conn: &Session) -> Result<Session>
+where
+    T: StreamWrapperTrait,
+{
+    let session = accept_as_server(stream, conn).await?;
+    write_reply(stream, REP_SUCCEEDED).await?;
+    Ok(session)
+}
+
+// 只读取 request，reply 在 outbound 建立连接后通过 write_reply 发送
+pub async fn accept_as_server<T>(stream: &mut T, conn: &Session) -> Result<Session>
+where
+    T: StreamWrapperTrait,
+{
     let mut buf = vec![0; 3];
     stream.read_exact(&mut buf).await?;
     let version = buf[0];
@@ -136,13 +154,33 @@ pub async fn handshake_as_server(stream: &mut TcpStream) -> Result<Session> {
             }
         },
     };
-    let buf = [0x05, 0x00, 0x00, 0x01, 0x00, 0x00,0x00,0x00, 0x00,0x00];
-    stream.write_all(&buf).await;
     let res = Session {
         destination: address,
         network: Network::TCP,
-        local_peer: stream.local_addr().expect("local"),
-        peer_address: stream.peer_addr().expect("peer"),
+        local_peer: conn.local_peer,
+        peer_address: conn.peer_address,
     };
     Ok(res)
 }
+
+pub async fn write_reply<T>(stream: &mut T, rep: u8) -> Result<()>
+where
+    T: StreamWrapperTrait,
+{
+    let buf = [0x05, rep, 0x00, 0x01, 0x00, 0x00,0x00,0x00, 0x00,0x00];
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+pub fn reply_code(result: Result<(), ConnectError>) -> u8 {
+    match result {
+        Ok(_) => REP_SUCCEEDED,
+        Err(ConnectError::General) => REP_GENERAL_FAILURE,
+        Err(ConnectError::NotAllowed) => REP_NOT_ALLOWED,
+        Err(ConnectError::NetworkUnreachable) => REP_NETWORK_UNREACHABLE,
+        Err(ConnectError::HostUnreachable) => REP_HOST_UNREACHABLE,
+        Err(ConnectError::ConnectionRefused) => REP_CONNECTION_REFUSED,
+        // 与 dante 等实现一致，连接超时回复 TTL expired
+        Err(ConnectError::TimedOut) => REP_TTL_EXPIRED,
+    }
+}
@@ -1,12 +1,13 @@
 use std::{sync::Arc};
 
+use anyhow::{anyhow, bail};
 use async_trait::async_trait;
-use log::{debug, trace};
-use tokio::{net::{TcpStream, UdpSocket}};
+use log::trace;
+use tokio::{net::UdpSocket};
 
 use crate::{
     proxy::{
-        connect_to_remote_tcp, Address, Session, TcpOutboundHandlerTrait,
+        Address, AnyStream, OutboundConnect, Session, TcpOutboundHandlerTrait,
         UdpOutboundHandlerTrait,
     },
     Context,
@@ -20,15 +21,14 @@ pub struct TcpOutboundHandler {
 
 #[async_trait]
 impl TcpOutboundHandlerTrait for TcpOutboundHandler {
-    async fn handle(&self, ctx: Arc<Context>, session: &Session) -> anyhow::Result<TcpStream> {
+    fn remote_addr(&self) -> OutboundConnect {
+        OutboundConnect::Proxy(self.address.clone())
+    }
+    async fn handle(&self, _ctx: Arc<Context>, session: &Session, stream: Option<AnyStream>) -> anyhow::Result<AnyStream> {
         trace!("connect to socks proxy server {}", self.address);
-        let mut stream = connect_to_remote_tcp(ctx.dns_client.clone(), self.address.clone()).await?;
-        match handshake_as_client(&mut stream, &session).await {
-            Err(err) => {
-                debug!("{}", err);
-            }
-            _ => {}
-        }
+        let mut stream = stream.ok_or_else(|| anyhow!("no stream for socks outbound"))?;
+        // 作为 dialerProxy 时，握手失败的 stream 不能继续交给上层协议
+        handshake_as_client(&mut stream, session).await?;
         Ok(stream)
     }
 }
@@ -41,6 +41,6 @@ pub struct UdpOutboundHandler {
 #[async_trait]
 impl UdpOutboundHandlerTrait for UdpOutboundHandler {
     async fn handle(&self, _ctx: Arc<Context>, _session: &Session) -> anyhow::Result<UdpSocket> {
-        todo!()
+        bail!("udp associate to socks server {}:{} is not supported", self.addr, self.port)
     }
 }
@@ -0,0 +1,32 @@
+use std::io;
+
+use async_trait::async_trait;
+use tokio::net::UdpSocket;
+
+use super::{Address, AnyStream, InboundResult, Session, TcpInboundHandlerTrait, UdpInboundHandlerTrait};
+
+// 透明代理，配合 iptables TPROXY 使用
+// iptables -t mangle -A PREROUTING -p tcp -j TPROXY --on-port <port> --tproxy-mark 1
+// iptables -t mangle -A PREROUTING -p udp -j TPROXY --on-port <port> --tproxy-mark 1
+// ip rule add fwmark 1 lookup 100
+// ip route add local 0.0.0.0/0 dev lo table 100
+pub struct TcpInboundHandler;
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, mut session: Session, stream: AnyStream) -> io::Result<InboundResult> {
+        // IP_TRANSPARENT 的 listener accept 到的连接，local addr 就是原本的 destination
+        session.destination = Address::Ip(session.local_peer);
+        Ok(InboundResult::Stream(stream, session))
+    }
+}
+
+pub struct UdpInboundHandler;
+
+#[async_trait]
+impl UdpInboundHandlerTrait for UdpInboundHandler {
+    async fn handle(&self, session: Session, socket: UdpSocket) -> io::Result<InboundResult> {
+        // 每个 datagram 的 destination 都不同，由 dispatcher 在收包时通过 IP_RECVORIGDSTADDR 获取
+        Ok(InboundResult::Datagram(socket, session))
+    }
+}
@@ -1,54 +1,145 @@
 use etherparse::{
     IpHeader, PacketHeaders, ReadError, TransportHeader,
 };
-use ipnet::Ipv4Net;
-use log::error;
+use ipnet::{ipv4_mask_to_prefix, Ipv4Net};
+use log::{error, info, trace, warn};
 use std::{
-    error::Error,
     io::{self, Cursor, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::JoinHandle,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tun::{AsyncDevice, Device, Layer};
 
+use crate::{
+    app::Dispatcher,
+    common::{
+        linux::{add_default_routes, delete_default_routes},
+        netlink::RouteMonitor,
+    },
+    config::TunInboundSettings,
+    net::set_auto_interface,
+};
+
 use tcp::TcpTun;
 mod tcp;
 pub struct Tun {
     device: AsyncDevice,
     tcp_tun: TcpTun,
+    // auto 时接管的默认路由，Tun drop (shutdown) 时删除
+    _routes: Option<AutoRoutes>,
+}
+
+struct AutoRoutes {
+    interface: String,
+    gateway: Ipv4Addr,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl Drop for AutoRoutes {
+    fn drop(&mut self) {
+        if let Some(monitor) = self.monitor.take() {
+            monitor.abort();
+        }
+        set_auto_interface(None);
+        match delete_default_routes(&self.interface, self.gateway) {
+            Ok(_) => info!("default routes via {} removed", self.interface),
+            Err(err) => error!("failed to remove default routes via {} {}", self.interface, err),
+        }
+    }
 }
 
 impl Tun {
-    pub async fn new() -> io::Result<Tun> {
+    pub async fn new(settings: TunInboundSettings, dispatcher: Arc<Dispatcher>) -> io::Result<Tun> {
+        let netmask = match ipv4_mask_to_prefix(settings.netmask) {
+            Ok(x) => x,
+            Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+        };
         let mut config = tun::Configuration::default();
-        let netmask = 24;
-        config.address("10.0.0.1").netmask(24).layer(Layer::L3).up();
+        config
+            .address(settings.address)
+            .netmask(settings.netmask)
+            .destination(settings.gateway)
+            .layer(Layer::L3)
+            .up();
+        if let Some(ref name) = settings.name {
+            config.name(name);
+        }
+        if let Some(mtu) = settings.mtu {
+            config.mtu(mtu);
+        }
         let device = match tun::create_as_async(&config) {
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+            Err(err) => return Err(io::Error::other(err.to_string())),
             Ok(x) => x,
         };
+        let name = device.get_ref().name().to_string();
         let tun_address = match device.get_ref().address() {
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+            Err(err) => return Err(io::Error::other(err)),
             Ok(x) => x,
         };
         let tun_network = Ipv4Net::new(tun_address, netmask).expect("ipv4 net new");
-        let tcp_tun = TcpTun::new(tun_network.into())
-            .await
-            .expect("tcp tun error");
-        Ok(Tun { device, tcp_tun })
+        let tcp_tun = TcpTun::new(tun_address, settings.gateway, tun_network, dispatcher).await?;
+        let mut routes = None;
+        if settings.auto.unwrap_or(false) {
+            warn!("tun only forwards ipv4 tcp, ipv6 and udp packets routed to {} will be dropped", name);
+            // outbound 的 socket 绑定到物理网卡，避免流量再次进入 tun
+            let monitor = RouteMonitor::new(Some(name.clone()))?;
+            match monitor.current() {
+                Some(route) => {
+                    info!("bind outbound to default interface {}", route.interface);
+                    set_auto_interface(Some(route.interface));
+                }
+                None => error!("no default interface found, outbound may loop back to tun"),
+            }
+            let monitor = tokio::spawn(async move {
+                let res = monitor
+                    .run(|route| {
+                        if route.is_none() {
+                            error!("no default interface found, outbound may loop back to tun");
+                        }
+                        set_auto_interface(route.map(|x| x.interface));
+                    })
+                    .await;
+                if let Err(err) = res {
+                    error!("route monitor stopped {}", err);
+                }
+            });
+            // 添加失败时也会删除已经添加的路由
+            let guard = AutoRoutes {
+                interface: name.clone(),
+                gateway: settings.gateway,
+                monitor: Some(monitor),
+            };
+            // 接管默认路由
+            if let Err(err) = add_default_routes(&name, settings.gateway) {
+                return Err(io::Error::other(err.to_string()));
+            }
+            routes = Some(guard);
+        }
+        Ok(Tun {
+            device,
+            tcp_tun,
+            _routes: routes,
+        })
     }
     pub async fn run(mut self) -> io::Result<()> {
-        let mtu = self.device.get_mut().mtu().expect("mtu");
+        let mtu = match self.device.get_mut().mtu() {
+            Err(err) => return Err(io::Error::other(err)),
+            Ok(x) => x,
+        };
         let mut packet = vec![0u8; mtu as usize].into_boxed_slice();
-        // self.device.read(buf)
         loop {
-            tokio::select! {
-                n = self.device.read(&mut packet) => {
-                    let n = n?;
-                    if self.handle_ip_packet(&mut packet).await? {
-                        self.device.write_all(&packet).await?;
-                    };
-                    println!("{} bytes read", n);
+            let n = self.device.read(&mut packet).await?;
+            trace!("{} bytes read from tun", n);
+            let packet = &mut packet[..n];
+            match self.handle_ip_packet(packet).await {
+                Ok(true) => self.device.write_all(packet).await?,
+                Ok(false) => {}
+                Err(err) => {
+                    error!("bad ip packet from tun {}", err);
                 }
             }
         }
@@ -57,7 +148,7 @@ impl Tun {
         let mut ip_packet = match PacketHeaders::from_ip_slice(packet) {
             Ok(ip) => ip,
             Err(ReadError::IoError(err)) => return Err(err),
-            Err(err) => return Err(io::Error::new(ErrorKind::Other, err)),
+            Err(err) => return Err(io::Error::other(err)),
         };
         // 看内部实现，payload 是 传输层 的 payload
         // 已经排除 传输层 的header
@@ -66,7 +157,7 @@ impl Tun {
             Some(ref mut header) => header,
             None => {
                 error!("unknown ethernet packet {:?}", ip_packet);
-                return Err(io::Error::new(ErrorKind::Other, "unknown ethernet packet"));
+                return Err(io::Error::other("unknown ethernet packet"));
             }
         };
         let (src_ip, destination_ip): (IpAddr, IpAddr) = match ip_header {
@@ -79,6 +170,11 @@ impl Tun {
                 Ipv6Addr::from(v6.destination).into(),
             ),
         };
+        // TcpTun 只分配 IPv4 的 fake 地址，实现 IPv6 NAT 之前直接丢弃
+        if let IpHeader::Version6(_) = ip_header {
+            trace!("ipv6 packet {} => {} dropped", src_ip, destination_ip);
+            return Ok(false);
+        }
         // mapping ip
         match ip_packet.transport {
             Some(TransportHeader::Tcp(ref mut tcp_header)) => {
@@ -94,6 +190,8 @@ impl Tun {
                     None => return Ok(false),
                 };
                 // replace src ip, port
+                tcp_header.source_port = final_src_addr.port();
+                tcp_header.destination_port = final_dest_addr.port();
                 match (final_src_addr, &mut ip_header) {
                     (SocketAddr::V4(v4), IpHeader::Version4(v4_header)) => {
                         v4_header.source = v4.ip().octets()
@@ -117,12 +215,12 @@ impl Tun {
                 match ip_header {
                     IpHeader::Version4(v4_ip_header) => {
                         tcp_header.checksum = tcp_header
-                            .calc_checksum_ipv4(&v4_ip_header, ip_packet.payload)
+                            .calc_checksum_ipv4(v4_ip_header, ip_packet.payload)
                             .expect("tcp calculate check sum error")
                     }
                     IpHeader::Version6(v6_ip_header) => {
                         tcp_header.checksum = tcp_header
-                            .calc_checksum_ipv6(&v6_ip_header, ip_packet.payload)
+                            .calc_checksum_ipv6(v6_ip_header, ip_packet.payload)
                             .expect("tcp calculate check sum error")
                     }
                 }
@@ -136,8 +234,12 @@ impl Tun {
                     .write(&mut cursor)
                     .expect("tcp header write failed!");
             }
-            Some(TransportHeader::Udp(ref mut _udp_header)) => {}
-            None => {}
+            // 还不支持 udp，见 TunInboundSettings::auto
+            Some(TransportHeader::Udp(ref mut _udp_header)) => {
+                trace!("udp packet {} => {} dropped", src_ip, destination_ip);
+                return Ok(false);
+            }
+            None => return Ok(false),
         };
         Ok(true)
     }
@@ -1,17 +1,21 @@
 use std::{
     io,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
     time::Duration,
 };
 
 use etherparse::TcpHeader;
-use ipnet::{IpNet};
+use ipnet::Ipv4Net;
 use log::error;
 use lru_time_cache::LruCache;
 use tokio::{net::TcpStream, sync::Mutex};
 
-use crate::net::{ProxyStream, ProxyTcpListener};
+use crate::{
+    app::Dispatcher,
+    net::ProxyTcpListener,
+    proxy::{Address, Network, Session},
+};
 
 pub struct Nat {
     // fake ip to real_src_ip
@@ -48,25 +52,30 @@ struct TcpConnection {
     state: State,
 }
 impl TcpTun {
-    pub async fn new(tun_network: IpNet) -> io::Result<TcpTun> {
-        let mut hosts = tun_network.hosts();
-        let listener_addr = match hosts.next() {
-            Some(addr) => addr,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "unexpected listener address allocate failed",
-                ))
-            }
-        };
-        let listener = ProxyTcpListener::new(listener_addr, 0).await?;
+    pub async fn new(
+        tun_address: Ipv4Addr,
+        gateway: Ipv4Addr,
+        tun_network: Ipv4Net,
+        dispatcher: Arc<Dispatcher>,
+    ) -> io::Result<TcpTun> {
+        // listener 必须 bind 在 tun 自己的地址上，改写后的包写回 tun 才能被本机接收
+        let listener = ProxyTcpListener::new(IpAddr::V4(tun_address), 0).await?;
         let local_addr = listener.local_addr()?;
-        let free_src_address = hosts.take(10).collect::<Vec<IpAddr>>();
+        // fake ip 从 tun 网段中分配，回包才会经过 tun
+        let free_src_address = tun_network
+            .hosts()
+            .filter(|x| *x != tun_address && *x != gateway)
+            .take(10)
+            .map(IpAddr::V4)
+            .collect::<Vec<IpAddr>>();
+        if free_src_address.is_empty() {
+            return Err(io::Error::other("unexpected fake address allocate failed"));
+        }
         let nat = Arc::new(Mutex::new(Nat::new()));
-        tokio::spawn(TcpTun::tunnel(listener, nat.clone()));
+        tokio::spawn(TcpTun::tunnel(listener, nat.clone(), dispatcher));
         Ok(TcpTun {
             free_address: free_src_address,
-            nat: nat,
+            nat,
             listener_addr: local_addr,
         })
     }
@@ -87,10 +96,9 @@ impl TcpTun {
                 // 1024 below are privilege ports
                 let port = rand::random::<u16>() % (65535 - 1024) + 1024;
                 let fake_addr = SocketAddr::new(
-                    self.free_address
+                    *self.free_address
                         .get(addr_index)
-                        .expect("should works")
-                        .clone(),
+                        .expect("should works"),
                     port,
                 );
                 if !connections.contains_key(&fake_addr) {
@@ -116,7 +124,7 @@ impl TcpTun {
             match mapping.get(&(src_addr, dest_addr)) {
                 Some(fake) => {
                     // isn't reply
-                    (connections.get_mut(&fake).unwrap(), false)
+                    (connections.get_mut(fake).unwrap(), false)
                 }
                 None => {
                     // Does it's a reply packet?
@@ -138,7 +146,8 @@ impl TcpTun {
         //       <---------------------------------     <-------------------------        <---------------------
         //        (src_ip, dest_ip) nat                    (fake_ip, server ip)
         let (final_src_ip, final_dest_ip) = if is_reply {
-            (dest_addr, src_addr)
+            // listener => fake 的回包，还原为 real_dest => real_src
+            (connection.dest_addr, connection.src_addr)
         } else {
             (connection.fake_addr, self.listener_addr)
         };
@@ -159,7 +168,11 @@ impl TcpTun {
         }
         Ok(Some((final_src_ip, final_dest_ip)))
     }
-    async fn tunnel(listener: ProxyTcpListener, translator: Arc<Mutex<Nat>>) {
+    async fn tunnel(
+        listener: ProxyTcpListener,
+        translator: Arc<Mutex<Nat>>,
+        dispatcher: Arc<Dispatcher>,
+    ) {
         loop {
             // remote_addr is fake ip
             let (stream, remote_addr) = match listener.accept().await {
@@ -179,20 +192,30 @@ impl TcpTun {
                     }
                 }
             };
-            tokio::spawn(TcpTun::handle_redir(stream, src_addr, dest_addr));
+            tokio::spawn(TcpTun::handle_redir(
+                dispatcher.clone(),
+                stream,
+                src_addr,
+                dest_addr,
+            ));
         }
     }
     // REDIRECT
     // transparent proxy
-    async fn handle_redir(_stream: TcpStream, _src_addr: SocketAddr, dest_addr: SocketAddr) {
+    async fn handle_redir(
+        dispatcher: Arc<Dispatcher>,
+        stream: TcpStream,
+        src_addr: SocketAddr,
+        dest_addr: SocketAddr,
+    ) {
         // stream is local stream
-        //
-        let _stream = match ProxyStream::connect(dest_addr).await {
-            Ok(s) => s,
-            Err(err) => {
-                error!("connect to {} failed because of {}", dest_addr, err);
-                return;
-            }
+        // 与 tproxy 一致，local_peer 就是原本的 destination
+        let mut sess = Session {
+            destination: Address::Ip(dest_addr),
+            network: Network::TCP,
+            local_peer: dest_addr,
+            peer_address: src_addr,
         };
+        dispatcher.dispatch_tcp(Box::new(stream), &mut sess).await;
     }
 }
@@ -0,0 +1,44 @@
+use std::io;
+
+use async_trait::async_trait;
+use log::error;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::proxy::{AnyStream, InboundResult, Session, TcpInboundHandlerTrait};
+
+use super::{RequestHeader, VERSION};
+
+pub struct TcpInboundHandler {
+    pub users: Vec<Uuid>,
+}
+
+#[async_trait]
+impl TcpInboundHandlerTrait for TcpInboundHandler {
+    async fn handle(&self, conn: Session, mut stream: AnyStream) -> io::Result<InboundResult> {
+        let header = match RequestHeader::read_from(&mut stream).await {
+            Ok(header) => header,
+            Err(err) => {
+                error!("failed to process vless inbound {}", err);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if !self.users.contains(&header.uuid) {
+            error!("unknown vless user {}", header.uuid);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unknown vless user"));
+        }
+        if !header.addons.flow.is_empty() {
+            error!("vless flow {} not supported", header.addons.flow);
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "vless flow not supported"));
+        }
+        // response header 没有 addons
+        stream.write_all(&[VERSION, 0x00]).await?;
+        let session = Session {
+            destination: header.destination,
+            network: header.network,
+            local_peer: conn.local_peer,
+            peer_address: conn.peer_address,
+        };
+        Ok(InboundResult::Stream(stream, session))
+    }
+}
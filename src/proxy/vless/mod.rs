@@ -0,0 +1,294 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use uuid::Uuid;
+
+use crate::common::varint::{get_varint, put_varint};
+
+use super::{Address, Network};
+
+mod inbound;
+mod outbound;
+
+pub use self::inbound::TcpInboundHandler;
+pub use self::outbound::{TcpOutboundHandler, UdpOutboundHandler};
+
+// https://xtls.github.io/development/protocols/vless.html
+// request
+// | 1 byte  | 16 bytes | 1 byte        | M bytes | 1 byte  | 2 bytes | 1 byte | S bytes | X bytes |
+// | version | UUID     | addons length | addons  | command | port    | atyp   | address | payload |
+// response
+// | 1 byte  | 1 byte        | N bytes | Y bytes |
+// | version | addons length | addons  | payload |
+//
+// command 为 UDP 时，payload 是 | 2 bytes length | packet | 的序列
+const VERSION: u8 = 0x00;
+const CMD_TCP: u8 = 0x01;
+const CMD_UDP: u8 = 0x02;
+const CMD_MUX: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x02;
+const ATYP_IPV6: u8 = 0x03;
+
+// addons 是 protobuf 编码
+// message Addons {
+//   string Flow = 1;
+//   bytes Seed = 2;
+// }
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Addons {
+    pub flow: String,
+}
+
+impl Addons {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        if self.flow.is_empty() {
+            return;
+        }
+        buf.put_u8(0x0a);
+        put_varint(buf, self.flow.len() as u64);
+        buf.put_slice(self.flow.as_bytes());
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Addons> {
+        let mut addons = Addons::default();
+        while !data.is_empty() {
+            let key = get_varint(&mut data)?;
+            let (field, wire_type) = (key >> 3, key & 0x07);
+            match wire_type {
+                // varint
+                0 => {
+                    get_varint(&mut data)?;
+                }
+                // length delimited
+                2 => {
+                    let len = get_varint(&mut data)? as usize;
+                    if data.len() < len {
+                        bail!("bad vless addons, length {} overflow", len);
+                    }
+                    if field == 1 {
+                        addons.flow = String::from_utf8_lossy(&data[..len]).to_string();
+                    }
+                    data = &data[len..];
+                }
+                _ => bail!("unknown vless addons wire type {}", wire_type),
+            }
+        }
+        Ok(addons)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestHeader {
+    pub uuid: Uuid,
+    pub addons: Addons,
+    pub network: Network,
+    pub destination: Address,
+}
+
+impl RequestHeader {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(VERSION);
+        buf.put_slice(self.uuid.as_bytes());
+        let mut addons = BytesMut::new();
+        self.addons.encode(&mut addons);
+        buf.put_u8(addons.len() as u8);
+        buf.put_slice(&addons);
+        match self.network {
+            Network::TCP => buf.put_u8(CMD_TCP),
+            Network::UDP => buf.put_u8(CMD_UDP),
+        }
+        buf.put_u16(self.destination.port());
+        match self.destination {
+            Address::Domain(ref name, _) => {
+                buf.put_u8(ATYP_DOMAIN);
+                buf.put_u8(name.len() as u8);
+                buf.put_slice(name.as_bytes());
+            }
+            Address::Ip(SocketAddr::V4(ref v4)) => {
+                buf.put_u8(ATYP_IPV4);
+                buf.put_slice(&v4.ip().octets());
+            }
+            Address::Ip(SocketAddr::V6(ref v6)) => {
+                buf.put_u8(ATYP_IPV6);
+                buf.put_slice(&v6.ip().octets());
+            }
+        }
+    }
+
+    pub async fn read_from<T>(stream: &mut T) -> Result<RequestHeader>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; 18];
+        stream.read_exact(&mut buf).await?;
+        if buf[0] != VERSION {
+            bail!("unsupported vless version {}", buf[0]);
+        }
+        let uuid = Uuid::from_slice(&buf[1..17])?;
+        let addons_len = buf[17] as usize;
+        buf.resize(addons_len, 0);
+        stream.read_exact(&mut buf).await?;
+        let addons = Addons::decode(&buf)?;
+
+        buf.resize(4, 0);
+        stream.read_exact(&mut buf).await?;
+        let network = match buf[0] {
+            CMD_TCP => Network::TCP,
+            CMD_UDP => Network::UDP,
+            CMD_MUX => bail!("vless mux command not supported"),
+            cmd => bail!("unknown vless command {}", cmd),
+        };
+        let port = u16::from_be_bytes([buf[1], buf[2]]);
+        let destination = match buf[3] {
+            ATYP_IPV4 => {
+                buf.resize(4, 0);
+                stream.read_exact(&mut buf).await?;
+                let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+                Address::Ip(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            ATYP_DOMAIN => {
+                let len = stream.read_u8().await?;
+                buf.resize(len as usize, 0);
+                stream.read_exact(&mut buf).await?;
+                Address::Domain(String::from_utf8_lossy(&buf).to_string(), port)
+            }
+            ATYP_IPV6 => {
+                let mut octets = [0u8; 16];
+                stream.read_exact(&mut octets).await?;
+                Address::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+            atyp => bail!("unknown vless atyp {}", atyp),
+        };
+        Ok(RequestHeader {
+            uuid,
+            addons,
+            network,
+            destination,
+        })
+    }
+}
+
+enum ReadState {
+    // 等待 server 的 response header
+    WaitingHeader,
+    Streaming,
+}
+
+// outbound 使用，读取时剥离 server 返回的 response header，之后原样透传
+pub struct VlessStream<T> {
+    stream: T,
+    read_state: ReadState,
+    read_buf: BytesMut,
+}
+
+impl<T> VlessStream<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            read_state: ReadState::WaitingHeader,
+            read_buf: BytesMut::new(),
+        }
+    }
+}
+
+impl<T> AsyncRead for VlessStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        loop {
+            match me.read_state {
+                ReadState::WaitingHeader => {
+                    if me.read_buf.len() >= 2 {
+                        if me.read_buf[0] != VERSION {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("unexpected vless response version {}", me.read_buf[0]),
+                            ))
+                            .into();
+                        }
+                        let header_len = 2 + me.read_buf[1] as usize;
+                        if me.read_buf.len() >= header_len {
+                            me.read_buf.advance(header_len);
+                            me.read_state = ReadState::Streaming;
+                            continue;
+                        }
+                    }
+                    let mut tmp = [0u8; 512];
+                    let mut read_buf = ReadBuf::new(&mut tmp);
+                    ready!(Pin::new(&mut me.stream).poll_read(cx, &mut read_buf))?;
+                    if read_buf.filled().is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "EOF while reading vless response header",
+                        ))
+                        .into();
+                    }
+                    me.read_buf.extend_from_slice(read_buf.filled());
+                }
+                ReadState::Streaming => {
+                    // header 之后多读到的 payload 先返回
+                    if !me.read_buf.is_empty() {
+                        let n = usize::min(buf.remaining(), me.read_buf.len());
+                        buf.put_slice(&me.read_buf[..n]);
+                        me.read_buf.advance(n);
+                        return Ok(()).into();
+                    }
+                    return Pin::new(&mut me.stream).poll_read(cx, buf);
+                }
+            }
+        }
+    }
+}
+
+impl<T> AsyncWrite for VlessStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn request_header_test() {
+    let header = RequestHeader {
+        uuid: Uuid::parse_str("b831381d-6324-4d53-ad4f-8cda48b30811").unwrap(),
+        addons: Addons {
+            flow: "xtls-rprx-direct".to_string(),
+        },
+        network: Network::UDP,
+        destination: Address::Domain("www.example.com".to_string(), 443),
+    };
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf);
+    let decoded = RequestHeader::read_from(&mut &buf[..]).await.unwrap();
+    assert_eq!(decoded.uuid, header.uuid);
+    assert_eq!(decoded.addons, header.addons);
+    assert_eq!(decoded.network, Network::UDP);
+    assert_eq!(decoded.destination.to_string(), "www.example.com:443");
+}
@@ -0,0 +1,85 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use log::{debug, trace};
+use tokio::{io::AsyncWriteExt, net::UdpSocket};
+use uuid::Uuid;
+
+use crate::{
+    proxy::{
+        relay_udp_over_stream, Address, AnyStream, Network, OutboundConnect, Session, TcpOutboundHandlerTrait,
+        UdpOutboundHandlerTrait,
+    },
+    transport::AnyDialer,
+    Context,
+};
+
+use super::{Addons, RequestHeader, VlessStream};
+
+// 本地 socket 对上没有 packet 超过该时间后关闭到 server 的 stream
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn encode_header(uuid: Uuid, network: Network, destination: Address) -> BytesMut {
+    let header = RequestHeader {
+        uuid,
+        addons: Addons::default(),
+        network,
+        destination,
+    };
+    let mut buf = BytesMut::new();
+    header.encode(&mut buf);
+    buf
+}
+
+// session.network 为 UDP 的 stream (例如 vless inbound 的 UDP command) 也走 vless 的 UDP command
+pub struct TcpOutboundHandler {
+    pub address: Address,
+    pub uuid: Uuid,
+}
+
+#[async_trait]
+impl TcpOutboundHandlerTrait for TcpOutboundHandler {
+    fn remote_addr(&self) -> OutboundConnect {
+        OutboundConnect::Proxy(self.address.clone())
+    }
+    async fn handle(&self, _ctx: Arc<Context>, session: &Session, stream: Option<AnyStream>) -> anyhow::Result<AnyStream> {
+        trace!("connect to vless server {}", self.address);
+        let mut stream = stream.ok_or_else(|| anyhow!("no stream for vless outbound"))?;
+        let header = encode_header(self.uuid, session.network.clone(), session.destination.clone());
+        stream.write_all(&header).await?;
+        Ok(Box::new(VlessStream::new(stream)))
+    }
+}
+
+// 每个 udp session 使用一个 UDP command 的 stream，packet 为 | 2 bytes length | payload |
+// transport 为 quic 时 packet 通过 datagram 发送
+// 返回的 socket 与本地另一个 socket 相连，由后台 task 在 socket 和 stream 之间转发
+pub struct UdpOutboundHandler {
+    pub address: Address,
+    pub uuid: Uuid,
+    pub dialer: AnyDialer,
+}
+
+#[async_trait]
+impl UdpOutboundHandlerTrait for UdpOutboundHandler {
+    async fn handle(&self, ctx: Arc<Context>, session: &Session) -> anyhow::Result<UdpSocket> {
+        trace!("udp to {} via vless server {}", session.destination, self.address);
+        let header = encode_header(self.uuid, Network::UDP, session.destination.clone());
+        let stream = self.dialer.dial_udp(ctx, &self.address, &header).await?;
+        let stream: AnyStream = Box::new(VlessStream::new(stream));
+
+        let local = UdpSocket::bind("127.0.0.1:0").await?;
+        let remote = UdpSocket::bind("127.0.0.1:0").await?;
+        local.connect(remote.local_addr()?).await?;
+        remote.connect(local.local_addr()?).await?;
+        let destination = session.destination.clone();
+        tokio::spawn(async move {
+            if let Err(err) = relay_udp_over_stream(stream, local, UDP_IDLE_TIMEOUT).await {
+                debug!("vless udp to {} closed {}", destination, err);
+            }
+        });
+        Ok(remote)
+    }
+}
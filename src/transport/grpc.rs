@@ -0,0 +1,111 @@
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::{
+    common::varint::{get_varint, put_varint},
+    config::GrpcSettings,
+};
+
+// gun 是 v2ray/xray 的 gRPC 传输，stream 承载在 bidirectional streaming rpc 上
+// service GunService {
+//   rpc Tun (stream Hunk) returns (stream Hunk);
+//   rpc TunMulti (stream MultiHunk) returns (stream MultiHunk);
+// }
+// message Hunk { bytes data = 1; }
+// message MultiHunk { repeated bytes data = 1; }
+// 两者的 field 1 编码相同，Hunk 只是只有一个 data 的 MultiHunk
+//
+// 每个 gRPC message
+// | 1 byte     | 4 bytes | N bytes  |
+// | compressed | length  | protobuf |
+const DEFAULT_SERVICE_NAME: &str = "GunService";
+const FIELD_DATA: u64 = 1;
+const WIRE_VARINT: u64 = 0;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+// compressed flag + length + tag + 最长的 varint
+pub const MAX_FRAME_OVERHEAD: usize = 1 + 4 + 1 + 5;
+
+pub fn service_path(settings: &GrpcSettings) -> String {
+    let name = settings.service_name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME);
+    let method = if settings.multi_mode.unwrap_or(false) {
+        "TunMulti"
+    } else {
+        "Tun"
+    };
+    format!("/{}/{}", name.trim_start_matches('/'), method)
+}
+
+pub fn encode(data: &[u8], buf: &mut BytesMut) {
+    let mut message = BytesMut::with_capacity(data.len() + 6);
+    put_varint(&mut message, FIELD_DATA << 3 | WIRE_LENGTH_DELIMITED);
+    put_varint(&mut message, data.len() as u64);
+    message.put_slice(data);
+    buf.put_u8(0);
+    buf.put_u32(message.len() as u32);
+    buf.put_slice(&message);
+}
+
+// 从 buf 中解出一个完整的 gRPC message，将其中的 data 追加到 out
+// buf 中数据不足一个 message 时返回 false
+pub fn decode(buf: &mut BytesMut, out: &mut BytesMut) -> Result<bool> {
+    if buf.len() < 5 {
+        return Ok(false);
+    }
+    if buf[0] != 0 {
+        bail!("compressed gun message not supported");
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + len {
+        return Ok(false);
+    }
+    let message = buf.split_to(5 + len);
+    let mut data = &message[5..];
+    while !data.is_empty() {
+        let key = get_varint(&mut data)?;
+        match key & 0x07 {
+            WIRE_VARINT => {
+                get_varint(&mut data)?;
+            }
+            WIRE_LENGTH_DELIMITED => {
+                let len = get_varint(&mut data)? as usize;
+                if data.len() < len {
+                    bail!("bad gun message, length {} overflow", len);
+                }
+                if key >> 3 == FIELD_DATA {
+                    out.put_slice(&data[..len]);
+                }
+                data.advance(len);
+            }
+            wire_type => bail!("unknown gun message wire type {}", wire_type),
+        }
+    }
+    Ok(true)
+}
+
+#[test]
+fn gun_codec_test() {
+    let mut buf = BytesMut::new();
+    encode(b"hello", &mut buf);
+    encode(&[7u8; 300], &mut buf);
+    // MultiHunk 中有两个 data
+    buf.put_slice(&[0, 0, 0, 0, 6, 0x0a, 1, b'a', 0x0a, 1, b'b']);
+    let mut half = buf.split_to(3);
+    let mut out = BytesMut::new();
+    assert!(!decode(&mut half, &mut out).unwrap());
+    half.unsplit(buf);
+    let mut buf = half;
+    assert!(decode(&mut buf, &mut out).unwrap());
+    assert_eq!(&out[..], b"hello");
+    assert!(decode(&mut buf, &mut out).unwrap());
+    assert_eq!(out.len(), 5 + 300);
+    assert!(decode(&mut buf, &mut out).unwrap());
+    assert_eq!(&out[305..], b"ab");
+    assert!(buf.is_empty());
+
+    let settings = GrpcSettings {
+        service_name: Some("example".to_string()),
+        multi_mode: Some(true),
+    };
+    assert_eq!(service_path(&settings), "/example/TunMulti");
+    assert_eq!(service_path(&GrpcSettings::default()), "/GunService/Tun");
+}
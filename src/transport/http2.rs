@@ -0,0 +1,428 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, FutureExt};
+use h2::{
+    client::{self, ResponseFuture, SendRequest},
+    server, Reason, RecvStream, SendStream,
+};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use log::{debug, error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+
+use crate::{
+    config::{GrpcSettings, HttpSettings},
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+use super::{grpc, AnyDialer, AnyStreamListener, Dialer, StreamListener};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    // h2 的 DATA frame 直接承载数据
+    Raw,
+    // 每次写入编码为一个 gun message
+    Gun,
+}
+
+fn h2_error(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        return err.into_io().unwrap();
+    }
+    io::Error::new(io::ErrorKind::ConnectionReset, err.to_string())
+}
+
+enum Recv {
+    // client 还未收到 response header
+    // AnyStream 需要 Sync，所以放在 Mutex 中
+    Response(StdMutex<ResponseFuture>),
+    Body(RecvStream),
+}
+
+// 一个 h2 stream 作为一个代理连接
+pub struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: Recv,
+    framing: Framing,
+    is_server: bool,
+    read_buf: BytesMut,
+    // 还未解码的 gun message
+    frame_buf: BytesMut,
+    shutdown: bool,
+}
+
+impl H2Stream {
+    fn new(send: SendStream<Bytes>, recv: Recv, framing: Framing, is_server: bool) -> Self {
+        H2Stream {
+            send,
+            recv,
+            framing,
+            is_server,
+            read_buf: BytesMut::new(),
+            frame_buf: BytesMut::new(),
+            shutdown: false,
+        }
+    }
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        loop {
+            if !me.read_buf.is_empty() {
+                let n = usize::min(buf.remaining(), me.read_buf.len());
+                buf.put_slice(&me.read_buf[..n]);
+                me.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if me.framing == Framing::Gun
+                && grpc::decode(&mut me.frame_buf, &mut me.read_buf)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+            {
+                continue;
+            }
+            let body = match &mut me.recv {
+                Recv::Response(response) => {
+                    let response = ready!(response.get_mut().unwrap().poll_unpin(cx)).map_err(h2_error)?;
+                    if response.status() != StatusCode::OK {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            format!("unexpected h2 response status {}", response.status()),
+                        )));
+                    }
+                    me.recv = Recv::Body(response.into_body());
+                    continue;
+                }
+                Recv::Body(body) => body,
+            };
+            match ready!(body.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = body.flow_control().release_capacity(data.len());
+                    match me.framing {
+                        Framing::Raw => me.read_buf.extend_from_slice(&data),
+                        Framing::Gun => me.frame_buf.extend_from_slice(&data),
+                    }
+                }
+                Some(Err(err)) if err.reason() == Some(Reason::NO_ERROR) => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let overhead = match self.framing {
+            Framing::Raw => 0,
+            Framing::Gun => grpc::MAX_FRAME_OVERHEAD,
+        };
+        // 按对端的流控窗口写入，避免 h2 无限缓存
+        self.send.reserve_capacity(buf.len() + overhead);
+        let capacity = loop {
+            let capacity = self.send.capacity();
+            if capacity > 0 {
+                break capacity;
+            }
+            match ready!(self.send.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+                None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "h2 stream closed"))),
+            }
+        };
+        let n = usize::min(buf.len(), usize::max(capacity.saturating_sub(overhead), 1));
+        let data = match self.framing {
+            Framing::Raw => Bytes::copy_from_slice(&buf[..n]),
+            Framing::Gun => {
+                let mut data = BytesMut::with_capacity(n + overhead);
+                grpc::encode(&buf[..n], &mut data);
+                data.freeze()
+            }
+        };
+        self.send.send_data(data, false).map_err(h2_error)?;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if self.shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        self.shutdown = true;
+        let res = if self.framing == Framing::Gun && self.is_server {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            self.send.send_trailers(trailers)
+        } else {
+            self.send.send_data(Bytes::new(), true)
+        };
+        Poll::Ready(res.map_err(h2_error))
+    }
+}
+
+// OUTBOUND
+
+// 所有代理连接复用同一个 h2 连接，连接断开后重新建立
+pub struct H2Dialer {
+    inner: AnyDialer,
+    path: String,
+    host: Option<String>,
+    framing: Framing,
+    conn: Mutex<Option<SendRequest<Bytes>>>,
+}
+
+impl H2Dialer {
+    pub fn new(inner: AnyDialer, settings: &HttpSettings) -> Self {
+        H2Dialer {
+            inner,
+            path: settings.path.clone().unwrap_or_else(|| "/".to_string()),
+            host: settings.host.clone(),
+            framing: Framing::Raw,
+            conn: Mutex::new(None),
+        }
+    }
+
+    pub fn new_grpc(inner: AnyDialer, settings: &GrpcSettings) -> Self {
+        H2Dialer {
+            inner,
+            path: grpc::service_path(settings),
+            host: None,
+            framing: Framing::Gun,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn send_request(&self, ctx: Arc<Context>, addr: &Address) -> Result<SendRequest<Bytes>> {
+        let mut conn = self.conn.lock().await;
+        if let Some(send_request) = conn.clone() {
+            if let Ok(send_request) = send_request.ready().await {
+                return Ok(send_request);
+            }
+        }
+        let stream = self.inner.dial(ctx, addr).await?;
+        let (send_request, connection) = client::handshake(stream)
+            .await
+            .map_err(|err| anyhow!("h2 handshake with {} failed: {}", addr, err))?;
+        let addr = addr.clone();
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("h2 connection to {} closed {}", addr, err);
+            }
+        });
+        *conn = Some(send_request.clone());
+        Ok(send_request.ready().await?)
+    }
+}
+
+#[async_trait]
+impl Dialer for H2Dialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let mut send_request = self.send_request(ctx, addr).await?;
+        let authority = match (&self.host, addr) {
+            (Some(host), _) => host.clone(),
+            (None, Address::Domain(name, port)) => format!("{}:{}", name, port),
+            (None, Address::Ip(addr)) => addr.to_string(),
+        };
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("https://{}{}", authority, self.path));
+        if self.framing == Framing::Gun {
+            request = request
+                .header("content-type", "application/grpc")
+                .header("te", "trailers");
+        }
+        let (response, send) = send_request.send_request(request.body(())?, false)?;
+        let recv = Recv::Response(StdMutex::new(response));
+        Ok(Box::new(H2Stream::new(send, recv, self.framing, false)))
+    }
+}
+
+// INBOUND
+
+pub struct H2StreamListener {
+    incoming: Mutex<mpsc::Receiver<(AnyStream, Session)>>,
+}
+
+impl H2StreamListener {
+    pub fn new(inner: AnyStreamListener, settings: &HttpSettings) -> Self {
+        let path = settings.path.clone().unwrap_or_else(|| "/".to_string());
+        Self::serve(inner, path, Framing::Raw)
+    }
+
+    pub fn new_grpc(inner: AnyStreamListener, settings: &GrpcSettings) -> Self {
+        Self::serve(inner, grpc::service_path(settings), Framing::Gun)
+    }
+
+    fn serve(inner: AnyStreamListener, path: String, framing: Framing) -> Self {
+        let (tx, rx) = mpsc::channel(32);
+        let path = Arc::new(path);
+        tokio::spawn(async move {
+            loop {
+                let (stream, session) = match inner.accept().await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("h2 listener accept error {}", err);
+                        return;
+                    }
+                };
+                if tx.is_closed() {
+                    return;
+                }
+                let tx = tx.clone();
+                let path = path.clone();
+                tokio::spawn(async move {
+                    let mut connection = match timeout(HANDSHAKE_TIMEOUT, server::handshake(stream)).await {
+                        Ok(Ok(x)) => x,
+                        Ok(Err(err)) => {
+                            debug!("h2 handshake with {} failed: {}", session.peer_address, err);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("h2 handshake with {} timeout", session.peer_address);
+                            return;
+                        }
+                    };
+                    // accept 同时驱动整个连接的读写
+                    while let Some(request) = connection.accept().await {
+                        let (request, mut respond) = match request {
+                            Ok(x) => x,
+                            Err(err) => {
+                                debug!("h2 connection from {} closed {}", session.peer_address, err);
+                                return;
+                            }
+                        };
+                        if request.uri().path() != path.as_str() {
+                            let response = Response::builder().status(StatusCode::NOT_FOUND).body(()).unwrap();
+                            let _ = respond.send_response(response, true);
+                            continue;
+                        }
+                        let mut response = Response::builder().status(StatusCode::OK);
+                        if framing == Framing::Gun {
+                            response = response.header("content-type", "application/grpc");
+                        }
+                        let send = match respond.send_response(response.body(()).unwrap(), false) {
+                            Ok(x) => x,
+                            Err(err) => {
+                                debug!("h2 send response to {} failed {}", session.peer_address, err);
+                                continue;
+                            }
+                        };
+                        let stream: AnyStream = Box::new(H2Stream::new(send, Recv::Body(request.into_body()), framing, true));
+                        if tx.send((stream, session.clone())).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        H2StreamListener {
+            incoming: Mutex::new(rx),
+        }
+    }
+}
+
+#[async_trait]
+impl StreamListener for H2StreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => Ok(x),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "h2 listener closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn h2_multiplex_test() {
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{
+        app::DnsClient,
+        config::Config,
+        transport::{TcpDialer, TcpStreamListener},
+    };
+
+    // 记录底层 tcp 连接数
+    struct CountingListener(TcpStreamListener, Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl StreamListener for CountingListener {
+        async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+            let res = self.0.accept().await;
+            self.1.fetch_add(1, Ordering::SeqCst);
+            res
+        }
+    }
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    for grpc in [false, true] {
+        let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = tcp.local_addr().unwrap();
+        let tcp = TcpStreamListener::new(tcp);
+        let count = Arc::new(AtomicUsize::new(0));
+        let inner = Box::new(CountingListener(tcp, count.clone()));
+        let (listener, dialer): (H2StreamListener, H2Dialer) = if grpc {
+            let settings = GrpcSettings::default();
+            (H2StreamListener::new_grpc(inner, &settings), H2Dialer::new_grpc(Arc::new(TcpDialer::default()), &settings))
+        } else {
+            let settings = HttpSettings {
+                path: Some("/h2".to_string()),
+                host: Some("example.com".to_string()),
+            };
+            (H2StreamListener::new(inner, &settings), H2Dialer::new(Arc::new(TcpDialer::default()), &settings))
+        };
+        for _ in 0..3 {
+            let mut client = dialer.dial(ctx.clone(), &Address::Ip(addr)).await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            // 超过初始流控窗口，需要 client 同时读取
+            let data = vec![7u8; 100000];
+            let expected = data.clone();
+            tokio::spawn(async move {
+                server.write_all(&data).await.unwrap();
+                server.shutdown().await.unwrap();
+            });
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
+use rand::Rng;
+
+// 与 v2ray mKCP 相同的伪装头部，接收方直接跳过固定长度
+pub enum PacketHeader {
+    None,
+    Srtp { number: u16 },
+    Utp { connection_id: u16 },
+    WechatVideo { sn: u32 },
+    Dtls { epoch: u16, sequence: u32, length: u16 },
+    Wireguard,
+}
+
+impl PacketHeader {
+    pub fn new(header_type: &str) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        Ok(match header_type {
+            "none" => PacketHeader::None,
+            "srtp" => PacketHeader::Srtp { number: rng.gen() },
+            "utp" => PacketHeader::Utp {
+                connection_id: rng.gen(),
+            },
+            "wechat-video" => PacketHeader::WechatVideo { sn: rng.gen() },
+            "dtls" => PacketHeader::Dtls {
+                epoch: rng.gen(),
+                sequence: 0,
+                length: 17,
+            },
+            "wireguard" => PacketHeader::Wireguard,
+            _ => bail!("unsupported kcp header type {}", header_type),
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            PacketHeader::None => 0,
+            PacketHeader::Srtp { .. } | PacketHeader::Utp { .. } | PacketHeader::Wireguard => 4,
+            PacketHeader::WechatVideo { .. } | PacketHeader::Dtls { .. } => 13,
+        }
+    }
+
+    pub fn write(&mut self, buf: &mut BytesMut) {
+        match self {
+            PacketHeader::None => {}
+            PacketHeader::Srtp { number } => {
+                buf.put_u16(0xb5e8);
+                buf.put_u16(*number);
+                *number = number.wrapping_add(1);
+            }
+            PacketHeader::Utp { connection_id } => {
+                buf.put_u8(1);
+                buf.put_u8(0);
+                buf.put_u16(*connection_id);
+            }
+            PacketHeader::WechatVideo { sn } => {
+                buf.put_slice(&[0xa1, 0x08]);
+                buf.put_u32(*sn);
+                *sn = sn.wrapping_add(1);
+                buf.put_slice(&[0x00, 0x10, 0x11, 0x18, 0x30, 0x22, 0x30]);
+            }
+            PacketHeader::Dtls { epoch, sequence, length } => {
+                buf.put_slice(&[23, 254, 253]);
+                buf.put_u16(*epoch);
+                buf.put_slice(&[0, 0]);
+                buf.put_u32(*sequence);
+                *sequence = sequence.wrapping_add(1);
+                buf.put_u16(*length);
+                *length += 17;
+                if *length > 100 {
+                    *length -= 50;
+                }
+            }
+            PacketHeader::Wireguard => buf.put_slice(&[0x04, 0x00, 0x00, 0x00]),
+        }
+    }
+}
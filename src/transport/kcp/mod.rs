@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context as TaskContext, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    sync::{mpsc, Mutex, Notify},
+};
+
+use crate::{
+    config::KcpSettings,
+    net::SocketOpts,
+    proxy::{name_to_socket_addr, Address, AnyStream, Network, Session},
+    Context,
+};
+
+use super::{Dialer, StreamListener};
+
+mod header;
+mod protocol;
+
+use self::{header::PacketHeader, protocol::Kcp};
+
+// 超过该时间没有收到对端的 packet 认为会话断开
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+// 空闲时发送窗口探测，保持会话
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+// 双方都关闭后保留一段时间，回复对端重传的 FIN
+const LINGER_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone)]
+struct KcpConfig {
+    mtu: usize,
+    tti: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    congestion: bool,
+    header_type: String,
+}
+
+impl KcpConfig {
+    fn new(settings: &KcpSettings) -> Result<Self> {
+        let mtu = settings.mtu.unwrap_or(1350).clamp(576, 1460);
+        let tti = settings.tti.unwrap_or(50).clamp(10, 100);
+        // 与 v2ray 相同，按带宽计算每个 tti 内最多发送的 packet 数
+        let window = |capacity: usize| (capacity * 1024 * 1024 / mtu / (1000 / tti as usize)).max(8) as u32;
+        let header_type = settings
+            .header
+            .as_ref()
+            .and_then(|x| x.header_type.clone())
+            .unwrap_or_else(|| "none".to_string());
+        // 检查 header 类型
+        PacketHeader::new(&header_type)?;
+        Ok(KcpConfig {
+            mtu,
+            tti,
+            snd_wnd: window(settings.uplink_capacity.unwrap_or(5)),
+            rcv_wnd: window(settings.downlink_capacity.unwrap_or(20)),
+            congestion: settings.congestion.unwrap_or(false),
+            header_type,
+        })
+    }
+
+    fn new_kcp(&self, conv: u32, header: &PacketHeader) -> Result<Kcp> {
+        let mut kcp = Kcp::new(conv);
+        kcp.set_mtu(self.mtu - header.size())?;
+        kcp.set_nodelay(true, self.tti, 2, !self.congestion);
+        kcp.set_wndsize(self.snd_wnd, self.rcv_wnd);
+        Ok(kcp)
+    }
+}
+
+// KCP 没有关闭连接的命令，使用长度为 0 的消息作为 FIN
+struct State {
+    kcp: Kcp,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    fin_sent: bool,
+    read_eof: bool,
+    dropped: bool,
+    closed: bool,
+}
+
+struct Shared {
+    state: StdMutex<State>,
+    notify: Notify,
+}
+
+// 发送 packet 的 socket，server 的所有会话共享同一个 socket
+struct Output {
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    header: PacketHeader,
+}
+
+impl Output {
+    async fn send(&mut self, packets: Vec<Bytes>) {
+        let mut buf = BytesMut::new();
+        for packet in packets {
+            buf.clear();
+            self.header.write(&mut buf);
+            buf.extend_from_slice(&packet);
+            let result = match self.peer {
+                Some(peer) => self.socket.send_to(&buf, peer).await,
+                None => self.socket.send(&buf).await,
+            };
+            if let Err(err) = result {
+                debug!("kcp send packet failed: {}", err);
+            }
+        }
+    }
+}
+
+fn new_session(
+    conv: u32,
+    config: &KcpConfig,
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    incoming: mpsc::Receiver<Bytes>,
+    on_close: Option<Box<dyn FnOnce() + Send>>,
+) -> Result<KcpStream> {
+    let header = PacketHeader::new(&config.header_type)?;
+    let shared = Arc::new(Shared {
+        state: StdMutex::new(State {
+            kcp: config.new_kcp(conv, &header)?,
+            read_waker: None,
+            write_waker: None,
+            fin_sent: false,
+            read_eof: false,
+            dropped: false,
+            closed: false,
+        }),
+        notify: Notify::new(),
+    });
+    let output = Output { socket, peer, header };
+    let interval = Duration::from_millis(config.tti as u64);
+    tokio::spawn(run_session(shared.clone(), incoming, output, interval, on_close));
+    Ok(KcpStream {
+        shared,
+        read_buf: Bytes::new(),
+    })
+}
+
+// 驱动 KCP 状态机: 输入 packet，按 tti 刷新，写入后立即刷新
+async fn run_session(
+    shared: Arc<Shared>,
+    mut incoming: mpsc::Receiver<Bytes>,
+    mut output: Output,
+    interval: Duration,
+    on_close: Option<Box<dyn FnOnce() + Send>>,
+) {
+    let start = Instant::now();
+    let header_size = output.header.size();
+    let mut ticker = tokio::time::interval(interval);
+    let mut last_recv = Instant::now();
+    let mut last_probe = Instant::now();
+    let mut linger: Option<Instant> = None;
+    loop {
+        let packet = tokio::select! {
+            packet = incoming.recv() => match packet {
+                Some(packet) => Some(packet),
+                None => break,
+            },
+            _ = ticker.tick() => None,
+            _ = shared.notify.notified() => None,
+        };
+        let packets = {
+            let mut state = shared.state.lock().unwrap();
+            state.kcp.update(start.elapsed().as_millis() as u32);
+            if let Some(packet) = packet {
+                if packet.len() > header_size {
+                    match state.kcp.input(&packet[header_size..]) {
+                        Ok(_) => last_recv = Instant::now(),
+                        Err(err) => debug!("kcp drop packet: {}", err),
+                    }
+                }
+            }
+            if last_recv.elapsed() >= KEEPALIVE_INTERVAL && last_probe.elapsed() >= KEEPALIVE_INTERVAL {
+                state.kcp.probe_window();
+                last_probe = Instant::now();
+            }
+            state.kcp.flush();
+            if state.kcp.has_message() {
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            }
+            if state.kcp.wait_snd() < state.kcp.snd_wnd() as usize * 2 {
+                if let Some(waker) = state.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            if linger.is_none() && state.fin_sent && (state.read_eof || state.dropped) && state.kcp.wait_snd() == 0 {
+                linger = Some(Instant::now());
+            }
+            if state.kcp.is_dead() {
+                debug!("kcp session dead link");
+                break;
+            }
+            state.kcp.take_output()
+        };
+        output.send(packets).await;
+        if last_recv.elapsed() >= SESSION_TIMEOUT {
+            debug!("kcp session timeout");
+            break;
+        }
+        if matches!(linger, Some(x) if x.elapsed() >= LINGER_TIMEOUT) {
+            break;
+        }
+    }
+    {
+        let mut state = shared.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = state.write_waker.take() {
+            waker.wake();
+        }
+    }
+    if let Some(on_close) = on_close {
+        on_close();
+    }
+}
+
+pub struct KcpStream {
+    shared: Arc<Shared>,
+    read_buf: Bytes,
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_buf.is_empty() {
+            let mut state = this.shared.state.lock().unwrap();
+            if state.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            match state.kcp.recv() {
+                Some(message) if message.is_empty() => {
+                    state.read_eof = true;
+                    return Poll::Ready(Ok(()));
+                }
+                Some(message) => {
+                    this.read_buf = message.into();
+                    // 接收窗口可能已经恢复
+                    this.shared.notify.notify_one();
+                }
+                None if state.closed => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "kcp session closed")));
+                }
+                None => {
+                    state.read_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        let n = usize::min(this.read_buf.len(), buf.remaining());
+        buf.put_slice(&this.read_buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed || state.fin_sent {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "kcp session closed")));
+        }
+        if state.kcp.wait_snd() >= state.kcp.snd_wnd() as usize * 2 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = usize::min(buf.len(), state.kcp.mss());
+        state.kcp.send(&buf[..n]).map_err(io::Error::other)?;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.fin_sent && !state.closed {
+            state.kcp.send(&[]).map_err(io::Error::other)?;
+            state.fin_sent = true;
+            self.shared.notify.notify_one();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for KcpStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.fin_sent && !state.closed && state.kcp.send(&[]).is_ok() {
+            state.fin_sent = true;
+        }
+        state.dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+// OUTBOUND
+
+// 每个 stream 使用独立的 UDP socket 和随机的 conv
+pub struct KcpDialer {
+    config: KcpConfig,
+    opts: SocketOpts,
+}
+
+impl KcpDialer {
+    pub fn new(settings: &KcpSettings, opts: SocketOpts) -> Result<Self> {
+        Ok(KcpDialer {
+            config: KcpConfig::new(settings)?,
+            opts,
+        })
+    }
+}
+
+#[async_trait]
+impl Dialer for KcpDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let remote = name_to_socket_addr(ctx.dns_client.clone(), addr.clone()).await?;
+        let socket = Arc::new(self.opts.connect_udp(remote).await?);
+        let (tx, rx) = mpsc::channel(256);
+        let reader = socket.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let n = tokio::select! {
+                    result = reader.recv(&mut buf) => match result {
+                        Ok(n) => n,
+                        Err(err) => {
+                            debug!("kcp recv from {} failed: {}", remote, err);
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => return,
+                };
+                if tx.send(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let stream = new_session(rand::random(), &self.config, socket, None, rx, None)?;
+        Ok(Box::new(stream))
+    }
+}
+
+// INBOUND
+
+type Sessions = HashMap<(SocketAddr, u32), mpsc::Sender<Bytes>>;
+
+// 按 (peer, conv) 区分会话
+pub struct KcpStreamListener {
+    local_addr: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<(AnyStream, Session)>>,
+}
+
+impl KcpStreamListener {
+    pub async fn bind(addr: SocketAddr, settings: &KcpSettings) -> Result<Self> {
+        let config = KcpConfig::new(settings)?;
+        let header_size = PacketHeader::new(&config.header_type)?.size();
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            let sessions: Arc<StdMutex<Sessions>> = Default::default();
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let (n, peer) = tokio::select! {
+                    result = socket.recv_from(&mut buf) => match result {
+                        Ok(x) => x,
+                        Err(err) => {
+                            debug!("kcp recv failed: {}", err);
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => return,
+                };
+                if n < header_size {
+                    continue;
+                }
+                let packet = &buf[header_size..n];
+                let conv = match protocol::get_conv(packet) {
+                    Some(conv) => conv,
+                    None => continue,
+                };
+                let key = (peer, conv);
+                if let Some(session_tx) = sessions.lock().unwrap().get(&key) {
+                    // 会话处理不过来时丢弃，由 KCP 重传
+                    let _ = session_tx.try_send(Bytes::copy_from_slice(&buf[..n]));
+                    continue;
+                }
+                if !protocol::is_first_push(packet) {
+                    continue;
+                }
+                let (session_tx, session_rx) = mpsc::channel(256);
+                let _ = session_tx.try_send(Bytes::copy_from_slice(&buf[..n]));
+                let on_close = {
+                    let sessions = sessions.clone();
+                    Box::new(move || {
+                        sessions.lock().unwrap().remove(&key);
+                    })
+                };
+                let stream = match new_session(conv, &config, socket.clone(), Some(peer), session_rx, Some(on_close)) {
+                    Ok(x) => x,
+                    Err(err) => {
+                        debug!("kcp new session from {} failed: {}", peer, err);
+                        continue;
+                    }
+                };
+                sessions.lock().unwrap().insert(key, session_tx);
+                let session = Session {
+                    destination: Address::Ip(peer),
+                    network: Network::TCP,
+                    local_peer: local_addr,
+                    peer_address: peer,
+                };
+                let stream: AnyStream = Box::new(stream);
+                if tx.send((stream, session)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(KcpStreamListener {
+            local_addr,
+            incoming: Mutex::new(rx),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl StreamListener for KcpStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => Ok(x),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "kcp listener closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn kcp_transport_test() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{
+        app::DnsClient,
+        config::{Config, KcpHeaderSettings},
+    };
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let settings = KcpSettings {
+        header: Some(KcpHeaderSettings {
+            header_type: Some("wechat-video".to_string()),
+        }),
+        ..Default::default()
+    };
+    let listener = KcpStreamListener::bind("127.0.0.1:0".parse().unwrap(), &settings).await.unwrap();
+    let addr = Address::Ip(listener.local_addr());
+    let dialer = KcpDialer::new(&settings, SocketOpts::default()).unwrap();
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|x| (x % 251) as u8).collect();
+    let mut client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+    let expected = data.clone();
+    let write = tokio::spawn(async move {
+        client.write_all(&expected).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    let (mut server, session) = listener.accept().await.unwrap();
+    assert_eq!(session.peer_address.ip(), addr.to_string().parse::<SocketAddr>().unwrap().ip());
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    assert!(received == data);
+    server.write_all(b"pong").await.unwrap();
+    server.shutdown().await.unwrap();
+    assert_eq!(write.await.unwrap(), b"pong");
+
+    assert!(KcpDialer::new(&KcpSettings {
+        header: Some(KcpHeaderSettings {
+            header_type: Some("unknown".to_string()),
+        }),
+        ..Default::default()
+    }, SocketOpts::default())
+    .is_err());
+}
@@ -0,0 +1,650 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// ikcp 的实现，不使用回调，待发送的 packet 通过 take_output 取出
+const RTO_NDL: u32 = 30;
+const RTO_MIN: u32 = 100;
+const RTO_DEF: u32 = 200;
+const RTO_MAX: u32 = 60000;
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+const ASK_SEND: u32 = 1;
+const ASK_TELL: u32 = 2;
+const WND_SND: u32 = 32;
+const WND_RCV: u32 = 128;
+const MTU_DEF: usize = 1400;
+const INTERVAL: u32 = 100;
+pub const OVERHEAD: usize = 24;
+const DEADLINK: u32 = 20;
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120000;
+const FASTACK_LIMIT: u32 = 5;
+
+// 处理 u32 回绕
+fn timediff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Default)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Bytes,
+}
+
+impl Segment {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u32_le(self.conv);
+        buf.put_u8(self.cmd);
+        buf.put_u8(self.frg);
+        buf.put_u16_le(self.wnd);
+        buf.put_u32_le(self.ts);
+        buf.put_u32_le(self.sn);
+        buf.put_u32_le(self.una);
+        buf.put_u32_le(self.data.len() as u32);
+        buf.put_slice(&self.data);
+    }
+}
+
+// 读取 packet 中的 conv
+pub fn get_conv(packet: &[u8]) -> Option<u32> {
+    if packet.len() < OVERHEAD {
+        return None;
+    }
+    Some(u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]))
+}
+
+// 是否为 sn 为 0 的 PUSH，server 只为这样的 packet 创建新会话
+pub fn is_first_push(packet: &[u8]) -> bool {
+    packet.len() >= OVERHEAD && packet[4] == CMD_PUSH && packet[12..16] == [0, 0, 0, 0]
+}
+
+pub struct Kcp {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    dead: bool,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    nodelay: u32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    incr: u32,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+    fastresend: u32,
+    nocwnd: bool,
+    buffer: BytesMut,
+    output: Vec<Bytes>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Self {
+        Kcp {
+            conv,
+            mtu: MTU_DEF,
+            mss: MTU_DEF - OVERHEAD,
+            dead: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: RTO_DEF,
+            rx_minrto: RTO_MIN,
+            snd_wnd: WND_SND,
+            rcv_wnd: WND_RCV,
+            rmt_wnd: WND_RCV,
+            cwnd: 0,
+            probe: 0,
+            current: 0,
+            interval: INTERVAL,
+            ts_flush: INTERVAL,
+            nodelay: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            incr: 0,
+            snd_queue: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: Vec::new(),
+            fastresend: 0,
+            nocwnd: false,
+            buffer: BytesMut::with_capacity(MTU_DEF),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<()> {
+        if mtu < 50 {
+            bail!("kcp mtu {} too small", mtu);
+        }
+        self.mtu = mtu;
+        self.mss = mtu - OVERHEAD;
+        Ok(())
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool, interval: u32, resend: u32, nocwnd: bool) {
+        self.nodelay = nodelay as u32;
+        self.rx_minrto = if nodelay { RTO_NDL } else { RTO_MIN };
+        self.interval = interval.clamp(10, 5000);
+        self.fastresend = resend;
+        self.nocwnd = nocwnd;
+    }
+
+    pub fn set_wndsize(&mut self, snd_wnd: u32, rcv_wnd: u32) {
+        self.snd_wnd = snd_wnd;
+        self.rcv_wnd = rcv_wnd.max(WND_RCV);
+    }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    pub fn snd_wnd(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    // 重传次数超过 DEADLINK
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    // 下次 flush 时请求对端回复窗口大小，用作 keep alive
+    pub fn probe_window(&mut self) {
+        self.probe |= ASK_SEND;
+    }
+
+    pub fn take_output(&mut self) -> Vec<Bytes> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn peek_size(&self) -> Option<usize> {
+        let seg = self.rcv_queue.front()?;
+        if seg.frg == 0 {
+            return Some(seg.data.len());
+        }
+        if self.rcv_queue.len() < seg.frg as usize + 1 {
+            return None;
+        }
+        let mut size = 0;
+        for seg in self.rcv_queue.iter() {
+            size += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        Some(size)
+    }
+
+    // 是否有完整的消息可以读取
+    pub fn has_message(&self) -> bool {
+        self.peek_size().is_some()
+    }
+
+    // 读取一个完整的消息
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let size = self.peek_size()?;
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+        let mut message = Vec::with_capacity(size);
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            message.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        self.move_rcv_buf();
+        // 窗口恢复后通知对端
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            self.probe |= ASK_TELL;
+        }
+        Some(message)
+    }
+
+    // 发送一个消息，长度为 0 的消息也会作为一个 segment 发送
+    pub fn send(&mut self, mut data: &[u8]) -> Result<()> {
+        let count = if data.len() <= self.mss {
+            1
+        } else {
+            data.len().div_ceil(self.mss)
+        };
+        if count >= self.rcv_wnd as usize {
+            bail!("kcp message too large");
+        }
+        for i in 0..count {
+            let size = usize::min(data.len(), self.mss);
+            self.snd_queue.push_back(Segment {
+                frg: (count - i - 1) as u8,
+                data: Bytes::copy_from_slice(&data[..size]),
+                ..Default::default()
+            });
+            data = &data[size..];
+        }
+        Ok(())
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + u32::max(self.interval, 4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for i in 0..self.snd_buf.len() {
+            let seg_sn = self.snd_buf[i].sn;
+            if sn == seg_sn {
+                self.snd_buf.remove(i);
+                break;
+            }
+            if timediff(sn, seg_sn) < 0 {
+                break;
+            }
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if timediff(una, seg.sn) > 0 {
+                self.snd_buf.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if timediff(sn, seg.sn) < 0 {
+                break;
+            } else if sn != seg.sn {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, seg: Segment) {
+        let sn = seg.sn;
+        if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 || timediff(sn, self.rcv_nxt) < 0 {
+            return;
+        }
+        let mut pos = self.rcv_buf.len();
+        let mut repeat = false;
+        for (i, item) in self.rcv_buf.iter().enumerate().rev() {
+            if item.sn == sn {
+                repeat = true;
+                break;
+            }
+            if timediff(sn, item.sn) > 0 {
+                break;
+            }
+            pos = i;
+        }
+        if !repeat {
+            self.rcv_buf.insert(pos, seg);
+        }
+        self.move_rcv_buf();
+    }
+
+    fn move_rcv_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn == self.rcv_nxt && self.rcv_queue.len() < self.rcv_wnd as usize {
+                let seg = self.rcv_buf.pop_front().unwrap();
+                self.rcv_queue.push_back(seg);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn input(&mut self, mut data: &[u8]) -> Result<()> {
+        let prev_una = self.snd_una;
+        let mut maxack: Option<u32> = None;
+        if data.len() < OVERHEAD {
+            bail!("kcp packet too short");
+        }
+        while data.len() >= OVERHEAD {
+            let conv = data.get_u32_le();
+            let cmd = data.get_u8();
+            let frg = data.get_u8();
+            let wnd = data.get_u16_le();
+            let ts = data.get_u32_le();
+            let sn = data.get_u32_le();
+            let una = data.get_u32_le();
+            let len = data.get_u32_le() as usize;
+            if conv != self.conv {
+                bail!("kcp conv mismatch");
+            }
+            if data.len() < len {
+                bail!("kcp packet truncated");
+            }
+            if !(CMD_PUSH..=CMD_WINS).contains(&cmd) {
+                bail!("unknown kcp cmd {}", cmd);
+            }
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+            match cmd {
+                CMD_ACK => {
+                    if timediff(self.current, ts) >= 0 {
+                        self.update_ack(timediff(self.current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    maxack = match maxack {
+                        Some(max) if timediff(sn, max) <= 0 => Some(max),
+                        _ => Some(sn),
+                    };
+                }
+                CMD_PUSH if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 => {
+                    self.acklist.push((sn, ts));
+                    if timediff(sn, self.rcv_nxt) >= 0 {
+                        self.parse_data(Segment {
+                            conv,
+                            cmd,
+                            frg,
+                            wnd,
+                            ts,
+                            sn,
+                            una,
+                            data: Bytes::copy_from_slice(&data[..len]),
+                            ..Default::default()
+                        });
+                    }
+                }
+                CMD_WASK => self.probe |= ASK_TELL,
+                _ => {}
+            }
+            data.advance(len);
+        }
+        if let Some(maxack) = maxack {
+            self.parse_fastack(maxack);
+        }
+        // 拥塞窗口
+        if timediff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                if self.incr < mss {
+                    self.incr = mss;
+                }
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd + 1) * mss <= self.incr {
+                    self.cwnd = (self.incr + mss - 1) / mss.max(1);
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd * mss;
+            }
+        }
+        Ok(())
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        self.rcv_wnd.saturating_sub(self.rcv_queue.len() as u32) as u16
+    }
+
+    fn output_segment(&mut self, seg: &Segment) {
+        if self.buffer.len() + OVERHEAD + seg.data.len() > self.mtu {
+            self.flush_buffer();
+        }
+        seg.encode(&mut self.buffer);
+    }
+
+    fn flush_buffer(&mut self) {
+        if !self.buffer.is_empty() {
+            let packet = self.buffer.split().freeze();
+            self.output.push(packet);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if !self.updated {
+            return;
+        }
+        let current = self.current;
+        let mut change = false;
+        let mut lost = false;
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: CMD_ACK,
+            wnd: self.wnd_unused(),
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        for (sn, ts) in std::mem::take(&mut self.acklist) {
+            seg.sn = sn;
+            seg.ts = ts;
+            self.output_segment(&seg);
+        }
+
+        // 对端窗口为 0 时探测
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if timediff(current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait += self.probe_wait / 2;
+                self.probe_wait = self.probe_wait.min(PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        seg.sn = 0;
+        seg.ts = 0;
+        if self.probe & ASK_SEND != 0 {
+            seg.cmd = CMD_WASK;
+            self.output_segment(&seg);
+        }
+        if self.probe & ASK_TELL != 0 {
+            seg.cmd = CMD_WINS;
+            self.output_segment(&seg);
+        }
+        self.probe = 0;
+
+        let mut cwnd = u32::min(self.snd_wnd, self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = u32::min(self.cwnd, cwnd);
+        }
+        while timediff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let mut newseg = match self.snd_queue.pop_front() {
+                Some(x) => x,
+                None => break,
+            };
+            newseg.conv = self.conv;
+            newseg.cmd = CMD_PUSH;
+            newseg.sn = self.snd_nxt;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(newseg);
+        }
+
+        let resent = if self.fastresend > 0 { self.fastresend } else { u32::MAX };
+        let rtomin = if self.nodelay == 0 { self.rx_rto >> 3 } else { 0 };
+        let wnd = seg.wnd;
+        let rcv_nxt = self.rcv_nxt;
+        let mut snd_buf = std::mem::take(&mut self.snd_buf);
+        for segment in snd_buf.iter_mut() {
+            let mut needsend = false;
+            if segment.xmit == 0 {
+                needsend = true;
+                segment.xmit += 1;
+                segment.rto = self.rx_rto;
+                segment.resendts = current.wrapping_add(segment.rto + rtomin);
+            } else if timediff(current, segment.resendts) >= 0 {
+                needsend = true;
+                segment.xmit += 1;
+                if self.nodelay == 0 {
+                    segment.rto += u32::max(segment.rto, self.rx_rto);
+                } else {
+                    let step = if self.nodelay < 2 { segment.rto } else { self.rx_rto };
+                    segment.rto += step / 2;
+                }
+                segment.resendts = current.wrapping_add(segment.rto);
+                lost = true;
+            } else if segment.fastack >= resent && segment.xmit <= FASTACK_LIMIT {
+                needsend = true;
+                segment.xmit += 1;
+                segment.fastack = 0;
+                segment.resendts = current.wrapping_add(segment.rto);
+                change = true;
+            }
+            if needsend {
+                segment.ts = current;
+                segment.wnd = wnd;
+                segment.una = rcv_nxt;
+                self.output_segment(segment);
+                if segment.xmit >= DEADLINK {
+                    self.dead = true;
+                }
+            }
+        }
+        self.snd_buf = snd_buf;
+        self.flush_buffer();
+
+        let mss = self.mss as u32;
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh + resent;
+            self.incr = self.cwnd * mss;
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+    }
+
+    // current 为毫秒时间戳，需要按 interval 周期调用
+    pub fn update(&mut self, current: u32) {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+        let mut slap = timediff(current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
+            if timediff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.interval);
+            }
+            self.flush();
+        }
+    }
+}
+
+#[test]
+fn kcp_lossy_test() {
+    use rand::Rng;
+
+    let mut a = Kcp::new(1);
+    let mut b = Kcp::new(1);
+    for kcp in [&mut a, &mut b] {
+        kcp.set_nodelay(true, 10, 2, true);
+        kcp.set_wndsize(128, 128);
+    }
+    let messages: Vec<Vec<u8>> = (0..200u32).map(|i| vec![i as u8; (i as usize * 37) % 3000]).collect();
+    for message in messages.iter() {
+        a.send(message).unwrap();
+    }
+    // 丢弃 20% 的 packet
+    let mut rng = rand::thread_rng();
+    let mut received = Vec::new();
+    let mut current = 0;
+    while received.len() < messages.len() && current < 60000 {
+        current += 10;
+        a.update(current);
+        b.update(current);
+        for packet in a.take_output() {
+            if rng.gen_range(0..5) != 0 {
+                b.input(&packet).unwrap();
+            }
+        }
+        for packet in b.take_output() {
+            if rng.gen_range(0..5) != 0 {
+                a.input(&packet).unwrap();
+            }
+        }
+        while let Some(message) = b.recv() {
+            received.push(message);
+        }
+    }
+    assert_eq!(received, messages);
+    assert!(!a.is_dead());
+}
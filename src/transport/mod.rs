@@ -0,0 +1,302 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::{QuicSettings, StreamSettings, TlsSettings},
+    net::{reserve_reuse_port, SocketOpts},
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+mod grpc;
+mod http2;
+mod kcp;
+mod mux;
+mod obfs;
+mod quic;
+mod tcp;
+mod tls;
+mod websocket;
+
+pub use self::http2::{H2Dialer, H2StreamListener};
+pub use self::kcp::{KcpDialer, KcpStreamListener};
+pub use self::mux::{MuxDialer, MuxStreamListener};
+pub use self::obfs::{ObfsDialer, ObfsStreamListener};
+pub use self::quic::{QuicDialer, QuicStreamListener};
+pub use self::tcp::{TcpDialer, TcpStreamListener};
+pub use self::tls::{TlsDialer, TlsStreamListener};
+pub use self::websocket::{WsDialer, WsStreamListener};
+
+// 传输层，proxy 协议 (socks, vless ...) 运行在 transport 提供的 stream 之上
+// 由 inbound / outbound 的 streamSettings 配置，默认 tcp
+// outbound 通过 Dialer 建立到 server 的 stream
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream>;
+    // UDP over stream，header 为代理协议的请求头，之后读写的是 | 2 bytes length | packet | 的序列
+    // quic 把 packet 放在 datagram 中，其他 transport 直接写在 stream 上
+    async fn dial_udp(&self, ctx: Arc<Context>, addr: &Address, header: &[u8]) -> Result<AnyStream> {
+        let mut stream = self.dial(ctx, addr).await?;
+        stream.write_all(header).await?;
+        Ok(stream)
+    }
+}
+
+// inbound 通过 StreamListener 接受连接
+// 返回的 session 只填充了 local_peer 和 peer_address，destination 由 inbound handler 决定
+#[async_trait]
+pub trait StreamListener: Send + Sync {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)>;
+}
+
+pub type AnyDialer = Arc<dyn Dialer>;
+pub type AnyStreamListener = Box<dyn StreamListener>;
+
+fn network_and_security(settings: &Option<StreamSettings>) -> (&str, &str) {
+    match settings {
+        Some(settings) => (
+            settings.network.as_deref().unwrap_or("tcp"),
+            settings.security.as_deref().unwrap_or("none"),
+        ),
+        None => ("tcp", "none"),
+    }
+}
+
+// 分层构建: tcp => security (tls) => network (ws ...) => mux
+// quic 和 kcp 基于 UDP，不在 tcp 之上
+pub fn build_dialer(settings: &Option<StreamSettings>) -> Result<AnyDialer> {
+    build_chained_dialer(settings, None, &SocketOpts::default())
+}
+
+// base 为其他 outbound 时，最底层的 stream 通过该 outbound 建立，而不是直接 tcp 连接
+// opts 只作用于直接建立的 socket，base 存在时由 base 自己的 opts 决定
+pub fn build_chained_dialer(settings: &Option<StreamSettings>, base: Option<AnyDialer>, opts: &SocketOpts) -> Result<AnyDialer> {
+    let dialer = build_network_dialer(settings, base, opts)?;
+    match settings.as_ref().and_then(|x| x.mux_settings.as_ref()) {
+        Some(mux_settings) => Ok(Arc::new(MuxDialer::new(dialer, mux_settings))),
+        None => Ok(dialer),
+    }
+}
+
+fn build_network_dialer(settings: &Option<StreamSettings>, base: Option<AnyDialer>, opts: &SocketOpts) -> Result<AnyDialer> {
+    let (network, _) = network_and_security(settings);
+    if base.is_some() && (network == "quic" || network == "kcp") {
+        bail!("stream network {} can not be chained over another outbound", network);
+    }
+    if network == "quic" {
+        let (tls_settings, quic_settings) = quic_and_tls_settings(settings);
+        return Ok(Arc::new(QuicDialer::new(&tls_settings, &quic_settings, opts.clone())?));
+    }
+    if network == "kcp" {
+        let kcp_settings = settings.as_ref().and_then(|x| x.kcp_settings.clone()).unwrap_or_default();
+        return Ok(Arc::new(KcpDialer::new(&kcp_settings, opts.clone())?));
+    }
+    let dialer = build_tcp_dialer(settings, base, opts)?;
+    match network {
+        "tcp" => Ok(dialer),
+        "ws" => {
+            let ws_settings = settings.as_ref().and_then(|x| x.ws_settings.clone()).unwrap_or_default();
+            Ok(Arc::new(WsDialer::new(dialer, &ws_settings)?))
+        }
+        "h2" | "http" => {
+            let http_settings = settings.as_ref().and_then(|x| x.http_settings.clone()).unwrap_or_default();
+            Ok(Arc::new(H2Dialer::new(dialer, &http_settings)))
+        }
+        "grpc" => {
+            let grpc_settings = settings.as_ref().and_then(|x| x.grpc_settings.clone()).unwrap_or_default();
+            Ok(Arc::new(H2Dialer::new_grpc(dialer, &grpc_settings)))
+        }
+        "obfs" => {
+            let obfs_settings = settings.as_ref().and_then(|x| x.obfs_settings.clone()).unwrap_or_default();
+            Ok(Arc::new(ObfsDialer::new(dialer, &obfs_settings)?))
+        }
+        _ => bail!("unsupported stream network {}", network),
+    }
+}
+
+// h2 和 grpc 的 server (Go net/http, grpc-go) 只在 ALPN 协商为 h2 后才使用 HTTP/2
+fn tls_settings(settings: &Option<StreamSettings>) -> TlsSettings {
+    let mut tls_settings = settings.as_ref().and_then(|x| x.tls_settings.clone()).unwrap_or_default();
+    let (network, _) = network_and_security(settings);
+    if tls_settings.alpn.is_none() && matches!(network, "h2" | "http" | "grpc") {
+        tls_settings.alpn = Some(vec!["h2".to_string()]);
+    }
+    tls_settings
+}
+
+fn quic_and_tls_settings(settings: &Option<StreamSettings>) -> (TlsSettings, QuicSettings) {
+    match settings {
+        Some(settings) => (
+            settings.tls_settings.clone().unwrap_or_default(),
+            settings.quic_settings.clone().unwrap_or_default(),
+        ),
+        None => (TlsSettings::default(), QuicSettings::default()),
+    }
+}
+
+fn build_tcp_dialer(settings: &Option<StreamSettings>, base: Option<AnyDialer>, opts: &SocketOpts) -> Result<AnyDialer> {
+    let (_, security) = network_and_security(settings);
+    let dialer = base.unwrap_or_else(|| Arc::new(TcpDialer::new(opts.clone())));
+    match security {
+        "none" => Ok(dialer),
+        "tls" => {
+            let tls_settings = tls_settings(settings);
+            Ok(Arc::new(TlsDialer::new(dialer, &tls_settings)?))
+        }
+        _ => bail!("unsupported stream security {}", security),
+    }
+}
+
+// sockopt.acceptors 大于 1 时通过 SO_REUSEPORT 在同一地址 bind 多个 listener，每个 listener 由单独的 accept 循环处理
+// 内核按四元组 hash 分配连接，避免单个 accept 循环成为瓶颈
+pub async fn bind(addr: SocketAddr, settings: &Option<StreamSettings>) -> Result<Vec<AnyStreamListener>> {
+    let (network, _) = network_and_security(settings);
+    let acceptors = match network {
+        // 基于 UDP 的 transport 只有一个 socket
+        "quic" | "kcp" => 1,
+        // 同一地址的 ws inbound 共享一个 server，按 path 分发
+        "ws" => 1,
+        _ => acceptors(settings),
+    };
+    if acceptors == 1 {
+        return Ok(vec![bind_one(addr, settings).await?]);
+    }
+    // port 为 0 时先占用一个 port，所有 listener 都 bind 到该 port
+    let reserved = match addr.port() {
+        0 => Some(reserve_reuse_port(addr)?),
+        _ => None,
+    };
+    let addr = reserved.as_ref().map(|x| x.1).unwrap_or(addr);
+    let mut listeners = Vec::with_capacity(acceptors);
+    for _ in 0..acceptors {
+        listeners.push(bind_one(addr, settings).await?);
+    }
+    Ok(listeners)
+}
+
+fn acceptors(settings: &Option<StreamSettings>) -> usize {
+    settings
+        .as_ref()
+        .and_then(|x| x.sockopt.as_ref())
+        .and_then(|x| x.acceptors)
+        .unwrap_or(1)
+        .max(1)
+}
+
+async fn bind_one(addr: SocketAddr, settings: &Option<StreamSettings>) -> Result<AnyStreamListener> {
+    let listener = bind_network(addr, settings).await?;
+    match settings.as_ref().and_then(|x| x.mux_settings.as_ref()) {
+        Some(_) => Ok(Box::new(MuxStreamListener::new(listener))),
+        None => Ok(listener),
+    }
+}
+
+async fn bind_network(addr: SocketAddr, settings: &Option<StreamSettings>) -> Result<AnyStreamListener> {
+    let (network, _) = network_and_security(settings);
+    match network {
+        "tcp" => bind_tcp(addr, settings).await,
+        "ws" => Ok(Box::new(WsStreamListener::bind(addr, settings).await?)),
+        "quic" => {
+            let (tls_settings, quic_settings) = quic_and_tls_settings(settings);
+            Ok(Box::new(QuicStreamListener::bind(addr, &tls_settings, &quic_settings)?))
+        }
+        "kcp" => {
+            let kcp_settings = settings.as_ref().and_then(|x| x.kcp_settings.clone()).unwrap_or_default();
+            Ok(Box::new(KcpStreamListener::bind(addr, &kcp_settings).await?))
+        }
+        "h2" | "http" => {
+            let http_settings = settings.as_ref().and_then(|x| x.http_settings.clone()).unwrap_or_default();
+            Ok(Box::new(H2StreamListener::new(bind_tcp(addr, settings).await?, &http_settings)))
+        }
+        "grpc" => {
+            let grpc_settings = settings.as_ref().and_then(|x| x.grpc_settings.clone()).unwrap_or_default();
+            Ok(Box::new(H2StreamListener::new_grpc(bind_tcp(addr, settings).await?, &grpc_settings)))
+        }
+        "obfs" => {
+            let obfs_settings = settings.as_ref().and_then(|x| x.obfs_settings.clone()).unwrap_or_default();
+            Ok(Box::new(ObfsStreamListener::new(bind_tcp(addr, settings).await?, &obfs_settings)?))
+        }
+        _ => bail!("unsupported stream network {}", network),
+    }
+}
+
+async fn bind_tcp(addr: SocketAddr, settings: &Option<StreamSettings>) -> Result<AnyStreamListener> {
+    let (_, security) = network_and_security(settings);
+    let sockopt = settings.as_ref().and_then(|x| x.sockopt.clone()).unwrap_or_default();
+    let listener: AnyStreamListener = Box::new(TcpStreamListener::bind(addr, &sockopt, acceptors(settings) > 1)?);
+    match security {
+        "none" => Ok(listener),
+        "tls" => {
+            let tls_settings = tls_settings(settings);
+            Ok(Box::new(TlsStreamListener::new(listener, &tls_settings)?))
+        }
+        _ => bail!("unsupported stream security {}", security),
+    }
+}
+
+#[test]
+fn build_dialer_test() {
+    assert!(build_dialer(&None).is_ok());
+    let settings = StreamSettings {
+        network: Some("tcp".to_string()),
+        security: Some("none".to_string()),
+        ..Default::default()
+    };
+    assert!(build_dialer(&Some(settings)).is_ok());
+    let settings = StreamSettings {
+        network: Some("unknown".to_string()),
+        ..Default::default()
+    };
+    assert!(build_dialer(&Some(settings)).is_err());
+    // 基于 UDP 的 transport 不能通过其他 outbound 建立
+    let settings = StreamSettings {
+        network: Some("kcp".to_string()),
+        ..Default::default()
+    };
+    assert!(build_dialer(&Some(settings.clone())).is_ok());
+    assert!(build_chained_dialer(&Some(settings), Some(Arc::new(TcpDialer::default())), &SocketOpts::default()).is_err());
+}
+
+#[test]
+fn tls_settings_test() {
+    let mut settings = StreamSettings {
+        network: Some("grpc".to_string()),
+        security: Some("tls".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(tls_settings(&Some(settings.clone())).alpn, Some(vec!["h2".to_string()]));
+    settings.tls_settings = Some(TlsSettings {
+        alpn: Some(vec!["http/1.1".to_string()]),
+        ..Default::default()
+    });
+    assert_eq!(tls_settings(&Some(settings.clone())).alpn, Some(vec!["http/1.1".to_string()]));
+    settings.network = Some("ws".to_string());
+    settings.tls_settings = None;
+    assert_eq!(tls_settings(&Some(settings)).alpn, None);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn bind_acceptors_test() {
+    use crate::config::SockoptSettings;
+
+    let mut settings = StreamSettings {
+        sockopt: Some(SockoptSettings {
+            acceptors: Some(4),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    // port 为 0 时同样 bind 4 个 listener
+    let listeners = bind("127.0.0.1:0".parse().unwrap(), &Some(settings.clone())).await.unwrap();
+    assert_eq!(listeners.len(), 4);
+    settings.network = Some("kcp".to_string());
+    let listeners = bind("127.0.0.1:0".parse().unwrap(), &Some(settings.clone())).await.unwrap();
+    assert_eq!(listeners.len(), 1);
+    settings.network = Some("ws".to_string());
+    let listeners = bind("127.0.0.1:0".parse().unwrap(), &Some(settings)).await.unwrap();
+    assert_eq!(listeners.len(), 1);
+}
@@ -0,0 +1,535 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    ready, SinkExt, StreamExt,
+};
+use log::{debug, error};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{mpsc as tokio_mpsc, Mutex, Notify},
+};
+
+use crate::{
+    config::MuxSettings,
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+use super::{AnyDialer, AnyStreamListener, Dialer, StreamListener};
+
+// smux v1 兼容的帧格式
+// | ver (1) | cmd (1) | length (2, LE) | stream id (4, LE) | data |
+const VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const HEADER_LEN: usize = 8;
+const MAX_FRAME_SIZE: usize = 32768;
+// 每个 stream 缓存的帧数
+// 没有流控，某个 stream 的队列满了之后 read_frames 会一直等待，
+// 同一连接上的其他 stream 也读不到数据，直到该 stream 被读取或关闭
+const STREAM_QUEUE_SIZE: usize = 64;
+const DEFAULT_MAX_STREAMS: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: u64 = 60;
+
+struct Frame {
+    cmd: u8,
+    sid: u32,
+    data: Bytes,
+}
+
+impl Frame {
+    fn new(cmd: u8, sid: u32) -> Self {
+        Frame {
+            cmd,
+            sid,
+            data: Bytes::new(),
+        }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported mux version {}", header[0]),
+        ));
+    }
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let sid = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Frame {
+        cmd: header[1],
+        sid,
+        data: data.into(),
+    })
+}
+
+// 一个底层连接上的所有 stream
+struct MuxConnection {
+    // SYN 优先发送，data 满时 Drop 的 FIN 也走这里
+    control: UnboundedSender<Frame>,
+    data: mpsc::Sender<Frame>,
+    streams: StdMutex<HashMap<u32, mpsc::Sender<Bytes>>>,
+    next_id: AtomicU32,
+    active: AtomicUsize,
+    idle_since: StdMutex<Instant>,
+    closed: AtomicBool,
+    shutdown: Notify,
+}
+
+impl MuxConnection {
+    // incoming 为 None 时作为 client，只能主动打开 stream
+    fn new(
+        stream: AnyStream,
+        incoming: Option<(tokio_mpsc::Sender<(AnyStream, Session)>, Session)>,
+        idle_timeout: Option<Duration>,
+    ) -> Arc<Self> {
+        let (control, control_rx) = mpsc::unbounded();
+        let (data, data_rx) = mpsc::channel(STREAM_QUEUE_SIZE);
+        let conn = Arc::new(MuxConnection {
+            control,
+            data,
+            streams: StdMutex::new(HashMap::new()),
+            // client 使用奇数 id
+            next_id: AtomicU32::new(1),
+            active: AtomicUsize::new(0),
+            idle_since: StdMutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+            shutdown: Notify::new(),
+        });
+        let (reader, writer) = tokio::io::split(stream);
+        tokio::spawn(conn.clone().write_frames(writer, control_rx, data_rx));
+        tokio::spawn(conn.clone().read_frames(reader, incoming));
+        if let Some(idle_timeout) = idle_timeout {
+            tokio::spawn(conn.clone().close_when_idle(idle_timeout));
+        }
+        conn
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn num_streams(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.shutdown.notify_one();
+    }
+
+    fn register(self: &Arc<Self>, sid: u32) -> MuxStream {
+        let (tx, rx) = mpsc::channel(STREAM_QUEUE_SIZE);
+        self.streams.lock().unwrap().insert(sid, tx);
+        self.active.fetch_add(1, Ordering::SeqCst);
+        MuxStream {
+            sid,
+            conn: self.clone(),
+            data: self.data.clone(),
+            rx,
+            read_buf: Bytes::new(),
+            fin_sent: false,
+        }
+    }
+
+    fn open(self: &Arc<Self>) -> Result<MuxStream> {
+        if self.is_closed() {
+            return Err(anyhow!("mux connection closed"));
+        }
+        let sid = self.next_id.fetch_add(2, Ordering::SeqCst);
+        let stream = self.register(sid);
+        self.control
+            .unbounded_send(Frame::new(CMD_SYN, sid))
+            .map_err(|_| anyhow!("mux connection closed"))?;
+        Ok(stream)
+    }
+
+    async fn write_frames<W: AsyncWrite + Unpin>(
+        self: Arc<Self>,
+        mut writer: W,
+        mut control: mpsc::UnboundedReceiver<Frame>,
+        mut data: mpsc::Receiver<Frame>,
+    ) {
+        let mut buf = Vec::with_capacity(HEADER_LEN + MAX_FRAME_SIZE);
+        loop {
+            let frame = tokio::select! {
+                biased;
+                _ = self.shutdown.notified() => break,
+                Some(frame) = control.next() => frame,
+                Some(frame) = data.next() => frame,
+                else => break,
+            };
+            buf.clear();
+            buf.push(VERSION);
+            buf.push(frame.cmd);
+            buf.extend_from_slice(&(frame.data.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&frame.sid.to_le_bytes());
+            buf.extend_from_slice(&frame.data);
+            if let Err(err) = writer.write_all(&buf).await {
+                debug!("mux write failed {}", err);
+                break;
+            }
+            if let Err(err) = writer.flush().await {
+                debug!("mux flush failed {}", err);
+                break;
+            }
+        }
+        self.close();
+        let _ = writer.shutdown().await;
+    }
+
+    async fn read_frames<R: AsyncRead + Unpin>(
+        self: Arc<Self>,
+        mut reader: R,
+        incoming: Option<(tokio_mpsc::Sender<(AnyStream, Session)>, Session)>,
+    ) {
+        loop {
+            let frame = match read_frame(&mut reader).await {
+                Ok(frame) => frame,
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::UnexpectedEof {
+                        debug!("mux read failed {}", err);
+                    }
+                    break;
+                }
+            };
+            match frame.cmd {
+                CMD_SYN => {
+                    if let Some((incoming, session)) = &incoming {
+                        let stream: AnyStream = Box::new(self.register(frame.sid));
+                        if incoming.send((stream, session.clone())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                CMD_PSH => {
+                    let tx = self.streams.lock().unwrap().get(&frame.sid).cloned();
+                    if let Some(mut tx) = tx {
+                        // 队列满时阻塞整个连接，见 STREAM_QUEUE_SIZE
+                        let _ = tx.send(frame.data).await;
+                    }
+                }
+                CMD_FIN => {
+                    self.streams.lock().unwrap().remove(&frame.sid);
+                }
+                // NOP
+                _ => {}
+            }
+        }
+        self.close();
+        // 所有 stream 读到 EOF
+        self.streams.lock().unwrap().clear();
+    }
+
+    // 没有 stream 超过 idle_timeout 后关闭底层连接
+    async fn close_when_idle(self: Arc<Self>, idle_timeout: Duration) {
+        let mut interval = tokio::time::interval(idle_timeout / 2);
+        loop {
+            interval.tick().await;
+            if self.is_closed() {
+                return;
+            }
+            if self.num_streams() == 0 && self.idle_since.lock().unwrap().elapsed() >= idle_timeout {
+                self.close();
+                return;
+            }
+        }
+    }
+}
+
+pub struct MuxStream {
+    sid: u32,
+    conn: Arc<MuxConnection>,
+    data: mpsc::Sender<Frame>,
+    rx: mpsc::Receiver<Bytes>,
+    read_buf: Bytes,
+    fin_sent: bool,
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "mux connection closed")
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let n = usize::min(buf.remaining(), self.read_buf.len());
+                buf.put_slice(&self.read_buf[..n]);
+                self.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.rx.poll_next_unpin(cx)) {
+                Some(data) => self.read_buf = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.fin_sent {
+            return Poll::Ready(Err(connection_closed()));
+        }
+        ready!(self.data.poll_ready(cx)).map_err(|_| connection_closed())?;
+        let n = usize::min(buf.len(), MAX_FRAME_SIZE);
+        let frame = Frame {
+            cmd: CMD_PSH,
+            sid: self.sid,
+            data: Bytes::copy_from_slice(&buf[..n]),
+        };
+        self.data.start_send(frame).map_err(|_| connection_closed())?;
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    // FIN 在数据之后发送，对端读到 EOF
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if self.fin_sent {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(self.data.poll_ready(cx)).map_err(|_| connection_closed())?;
+        let sid = self.sid;
+        self.data.start_send(Frame::new(CMD_FIN, sid)).map_err(|_| connection_closed())?;
+        self.fin_sent = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        // FIN 排在已发送的数据之后，只有 data 满时才走 control
+        if !self.fin_sent {
+            if let Err(err) = self.data.try_send(Frame::new(CMD_FIN, self.sid)) {
+                if err.is_full() {
+                    let _ = self.conn.control.unbounded_send(err.into_inner());
+                }
+            }
+        }
+        self.conn.streams.lock().unwrap().remove(&self.sid);
+        if self.conn.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            *self.conn.idle_since.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+// OUTBOUND
+
+// 按 server 地址复用底层连接，每个连接最多 max_streams 个 stream
+pub struct MuxDialer {
+    inner: AnyDialer,
+    max_streams: usize,
+    idle_timeout: Duration,
+    pools: Mutex<HashMap<String, Vec<Arc<MuxConnection>>>>,
+}
+
+impl MuxDialer {
+    pub fn new(inner: AnyDialer, settings: &MuxSettings) -> Self {
+        MuxDialer {
+            inner,
+            max_streams: settings.max_streams.unwrap_or(DEFAULT_MAX_STREAMS).max(1),
+            idle_timeout: Duration::from_secs(settings.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT).max(1)),
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for MuxDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let key = addr.to_string();
+        {
+            let mut pools = self.pools.lock().await;
+            if let Some(conns) = pools.get_mut(&key) {
+                conns.retain(|x| !x.is_closed());
+                if let Some(conn) = conns.iter().find(|x| x.num_streams() < self.max_streams) {
+                    if let Ok(stream) = conn.open() {
+                        return Ok(Box::new(stream));
+                    }
+                }
+                if conns.is_empty() {
+                    pools.remove(&key);
+                }
+            }
+        }
+        let stream = self.inner.dial(ctx, addr).await?;
+        let conn = MuxConnection::new(stream, None, Some(self.idle_timeout));
+        let stream = conn.open()?;
+        self.pools.lock().await.entry(key).or_default().push(conn);
+        Ok(Box::new(stream))
+    }
+}
+
+// INBOUND
+
+pub struct MuxStreamListener {
+    incoming: Mutex<tokio_mpsc::Receiver<(AnyStream, Session)>>,
+}
+
+impl MuxStreamListener {
+    pub fn new(inner: AnyStreamListener) -> Self {
+        let (tx, rx) = tokio_mpsc::channel(32);
+        tokio::spawn(async move {
+            loop {
+                let (stream, session) = match inner.accept().await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("mux listener accept error {}", err);
+                        return;
+                    }
+                };
+                if tx.is_closed() {
+                    return;
+                }
+                // 底层连接由 client 负责关闭
+                MuxConnection::new(stream, Some((tx.clone(), session)), None);
+            }
+        });
+        MuxStreamListener {
+            incoming: Mutex::new(rx),
+        }
+    }
+}
+
+#[async_trait]
+impl StreamListener for MuxStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => Ok(x),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "mux listener closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn mux_test() {
+    use std::net::SocketAddr;
+
+    use tokio::sync::RwLock;
+
+    use crate::{
+        app::DnsClient,
+        config::Config,
+        transport::{TcpDialer, TcpStreamListener},
+    };
+
+    // 记录底层 tcp 连接数
+    struct CountingListener(TcpStreamListener, Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl StreamListener for CountingListener {
+        async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+            let res = self.0.accept().await;
+            self.1.fetch_add(1, Ordering::SeqCst);
+            res
+        }
+    }
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = tcp.local_addr().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let listener = MuxStreamListener::new(Box::new(CountingListener(TcpStreamListener::new(tcp), count.clone())));
+    let settings = MuxSettings {
+        max_streams: Some(2),
+        idle_timeout: Some(1),
+    };
+    let dialer = MuxDialer::new(Arc::new(TcpDialer::default()), &settings);
+    let addr = Address::Ip(addr);
+
+    let echo = |mut server: AnyStream| async move {
+        let mut data = Vec::new();
+        server.read_to_end(&mut data).await.unwrap();
+        server.write_all(&data).await.unwrap();
+        server.shutdown().await.unwrap();
+    };
+    // 3 个并发 stream 需要 2 个底层连接
+    let mut clients = Vec::new();
+    for i in 0..3u8 {
+        let mut client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+        client.write_all(&vec![i; 100000]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        tokio::spawn(echo(server));
+        clients.push(client);
+    }
+    for (i, mut client) in clients.into_iter().enumerate() {
+        let mut data = Vec::new();
+        client.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, vec![i as u8; 100000]);
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // stream 结束后复用已有连接
+    let mut client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+    client.write_all(b"reuse").await.unwrap();
+    client.shutdown().await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    tokio::spawn(echo(server));
+    let mut data = Vec::new();
+    client.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data, b"reuse");
+    drop(client);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // 空闲超时后关闭，重新建立连接
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let _client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+    let _ = listener.accept().await.unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn mux_drop_test() {
+    // 未 shutdown 直接 drop，FIN 不能越过还在队列中的数据
+    let (client, server) = tokio::io::duplex(1024);
+    let client = MuxConnection::new(Box::new(client), None, None);
+    let (tx, mut rx) = tokio_mpsc::channel(1);
+    let peer = "127.0.0.1:0".parse().unwrap();
+    let session = Session {
+        destination: Address::Ip(peer),
+        network: crate::proxy::Network::TCP,
+        local_peer: peer,
+        peer_address: peer,
+    };
+    let _server = MuxConnection::new(Box::new(server), Some((tx, session)), None);
+
+    let mut stream = client.open().unwrap();
+    for i in 0..STREAM_QUEUE_SIZE as u8 {
+        stream.write_all(&[i; 1000]).await.unwrap();
+    }
+    drop(stream);
+    let (mut server, _) = rx.recv().await.unwrap();
+    let mut data = Vec::new();
+    server.read_to_end(&mut data).await.unwrap();
+    assert_eq!(data.len(), STREAM_QUEUE_SIZE * 1000);
+}
@@ -0,0 +1,498 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    config::ObfsSettings,
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+use super::{AnyDialer, AnyStreamListener, Dialer, StreamListener};
+
+// simple-obfs 兼容的混淆
+// http: 首包放在伪造的 websocket upgrade 请求/响应之后，之后为原始数据
+// tls: 首包放在伪造的 ClientHello 的 session ticket 扩展中，server 的首包放在伪造的握手消息中，之后为 application data
+const MAX_RECORD_SIZE: usize = 16384;
+const MAX_HTTP_HEADER_SIZE: usize = 8192;
+const RECORD_HEADER_LEN: usize = 5;
+const READ_SIZE: usize = 16384;
+
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const EXT_SESSION_TICKET: u16 = 0x0023;
+
+const CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e, 0xc0,
+    0x24, 0xc0, 0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09,
+    0xc0, 0x13, 0x00, 0x33, 0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+// ec_point_formats, supported_groups, signature_algorithms, encrypt_then_mac, extended_master_secret
+const CLIENT_HELLO_EXTENSIONS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00,
+    0x19, 0x00, 0x18, 0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02,
+    0x05, 0x03, 0x04, 0x01, 0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02,
+    0x03, 0x00, 0x16, 0x00, 0x00, 0x00, 0x17, 0x00, 0x00,
+];
+
+// renegotiation_info, extended_master_secret, ec_point_formats
+const SERVER_HELLO_EXTENSIONS: [u8; 15] = [
+    0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+];
+
+const CHANGE_CIPHER_SPEC: [u8; 6] = [CONTENT_TYPE_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ObfsMode {
+    Http,
+    Tls,
+}
+
+impl ObfsMode {
+    fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "http" => Ok(ObfsMode::Http),
+            "tls" => Ok(ObfsMode::Tls),
+            _ => bail!("unsupported obfs mode {}", mode),
+        }
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs() as u32).unwrap_or(0)
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf
+}
+
+fn http_request(host: &str, uri: &str, payload: &[u8], out: &mut BytesMut) {
+    let mut rng = rand::thread_rng();
+    let header = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/7.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+        uri,
+        host,
+        rng.gen_range(0..51),
+        rng.gen_range(0..2),
+        base64::encode(random_bytes(16)),
+        payload.len(),
+    );
+    out.put_slice(header.as_bytes());
+    out.put_slice(payload);
+}
+
+fn http_response(payload: &[u8], out: &mut BytesMut) {
+    let mut rng = rand::thread_rng();
+    let header = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nServer: nginx/1.{}.{}\r\nDate: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        rng.gen_range(0..11),
+        rng.gen_range(0..12),
+        chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT"),
+        base64::encode(random_bytes(20)),
+    );
+    out.put_slice(header.as_bytes());
+    out.put_slice(payload);
+}
+
+fn client_hello(host: &str, payload: &[u8], out: &mut BytesMut) {
+    let ext_len = 4 + payload.len() + 9 + host.len() + CLIENT_HELLO_EXTENSIONS.len();
+    // record header 之后的长度
+    let len = 4 + 2 + 32 + 1 + 32 + 2 + CIPHER_SUITES.len() + 2 + 2 + ext_len;
+    out.put_u8(CONTENT_TYPE_HANDSHAKE);
+    out.put_u16(0x0301);
+    out.put_u16(len as u16);
+    // ClientHello
+    out.put_u8(0x01);
+    out.put_u8(0x00);
+    out.put_u16((len - 4) as u16);
+    out.put_u16(0x0303);
+    out.put_u32(unix_time());
+    out.put_slice(&random_bytes(28));
+    out.put_u8(32);
+    out.put_slice(&random_bytes(32));
+    out.put_u16(CIPHER_SUITES.len() as u16);
+    out.put_slice(&CIPHER_SUITES);
+    out.put_u8(1);
+    out.put_u8(0);
+    out.put_u16(ext_len as u16);
+    out.put_u16(EXT_SESSION_TICKET);
+    out.put_u16(payload.len() as u16);
+    out.put_slice(payload);
+    // server_name
+    out.put_u16(0x0000);
+    out.put_u16((host.len() + 5) as u16);
+    out.put_u16((host.len() + 3) as u16);
+    out.put_u8(0);
+    out.put_u16(host.len() as u16);
+    out.put_slice(host.as_bytes());
+    out.put_slice(&CLIENT_HELLO_EXTENSIONS);
+}
+
+// 返回 session id 和 session ticket 中的首包
+fn parse_client_hello(body: &[u8]) -> Option<(Vec<u8>, Bytes)> {
+    if *body.first()? != 0x01 {
+        return None;
+    }
+    let u16_at = |pos: usize| -> Option<usize> { Some(u16::from_be_bytes([*body.get(pos)?, *body.get(pos + 1)?]) as usize) };
+    let mut pos = 4 + 2 + 32;
+    let session_id_len = *body.get(pos)? as usize;
+    let session_id = body.get(pos + 1..pos + 1 + session_id_len)?.to_vec();
+    pos += 1 + session_id_len;
+    pos += 2 + u16_at(pos)?;
+    pos += 1 + *body.get(pos)? as usize;
+    let end = pos + 2 + u16_at(pos)?;
+    pos += 2;
+    while pos + 4 <= end {
+        let ext_type = u16_at(pos)? as u16;
+        let ext_len = u16_at(pos + 2)?;
+        if ext_type == EXT_SESSION_TICKET {
+            let payload = body.get(pos + 4..pos + 4 + ext_len)?;
+            return Some((session_id, Bytes::copy_from_slice(payload)));
+        }
+        pos += 4 + ext_len;
+    }
+    None
+}
+
+fn server_hello(session_id: &[u8], payload: &[u8], out: &mut BytesMut) {
+    out.put_u8(CONTENT_TYPE_HANDSHAKE);
+    out.put_u16(0x0301);
+    out.put_u16(91);
+    // ServerHello
+    out.put_u8(0x02);
+    out.put_u8(0x00);
+    out.put_u16(87);
+    out.put_u16(0x0303);
+    out.put_u32(unix_time());
+    out.put_slice(&random_bytes(28));
+    out.put_u8(32);
+    out.put_slice(session_id);
+    out.put_u16(0xcca8);
+    out.put_u8(0);
+    out.put_u16(SERVER_HELLO_EXTENSIONS.len() as u16);
+    out.put_slice(&SERVER_HELLO_EXTENSIONS);
+    out.put_slice(&CHANGE_CIPHER_SPEC);
+    // 伪造的 encrypted handshake
+    out.put_u8(CONTENT_TYPE_HANDSHAKE);
+    out.put_u16(0x0303);
+    out.put_u16(payload.len() as u16);
+    out.put_slice(payload);
+}
+
+fn application_data(payload: &[u8], out: &mut BytesMut) {
+    out.put_u8(CONTENT_TYPE_APPLICATION_DATA);
+    out.put_u16(0x0303);
+    out.put_u16(payload.len() as u16);
+    out.put_slice(payload);
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub struct ObfsStream {
+    inner: AnyStream,
+    mode: ObfsMode,
+    // client 的 (host, uri)，server 为 None
+    client: Option<(String, String)>,
+    // 已发送首包
+    written_first: bool,
+    // client 已跳过 http 响应头或 ServerHello，server 已解析请求头或 ClientHello
+    read_first: bool,
+    session_id: Vec<u8>,
+    // 未解码的输入
+    raw: BytesMut,
+    // 已解码的数据
+    plain: BytesMut,
+    // 已编码但未写出的数据
+    pending: BytesMut,
+}
+
+impl ObfsStream {
+    fn new(inner: AnyStream, mode: ObfsMode, client: Option<(String, String)>) -> Self {
+        ObfsStream {
+            inner,
+            mode,
+            client,
+            written_first: false,
+            read_first: false,
+            session_id: random_bytes(32),
+            raw: BytesMut::new(),
+            plain: BytesMut::new(),
+            pending: BytesMut::new(),
+        }
+    }
+
+    // 尝试从 raw 中解码，返回 false 表示需要更多数据
+    fn decode(&mut self) -> io::Result<bool> {
+        match self.mode {
+            ObfsMode::Http if self.read_first => {
+                if self.raw.is_empty() {
+                    return Ok(false);
+                }
+                let raw = self.raw.split();
+                self.plain.unsplit(raw);
+                Ok(true)
+            }
+            ObfsMode::Http => {
+                let end = match self.raw.windows(4).position(|x| x == b"\r\n\r\n") {
+                    Some(pos) => pos + 4,
+                    None if self.raw.len() > MAX_HTTP_HEADER_SIZE => return Err(invalid_data("obfs http header too large")),
+                    None => return Ok(false),
+                };
+                let header = self.raw.split_to(end);
+                let valid = match self.client {
+                    Some(_) => header.starts_with(b"HTTP/1.1 101 "),
+                    None => header.starts_with(b"GET ") || header.starts_with(b"POST "),
+                };
+                if !valid {
+                    return Err(invalid_data("bad obfs http header"));
+                }
+                self.read_first = true;
+                Ok(true)
+            }
+            ObfsMode::Tls => {
+                if self.raw.len() < RECORD_HEADER_LEN {
+                    return Ok(false);
+                }
+                let len = u16::from_be_bytes([self.raw[3], self.raw[4]]) as usize;
+                if self.raw.len() < RECORD_HEADER_LEN + len {
+                    return Ok(false);
+                }
+                let content_type = self.raw[0];
+                self.raw.advance(RECORD_HEADER_LEN);
+                let body = self.raw.split_to(len);
+                match (content_type, self.read_first, &self.client) {
+                    (CONTENT_TYPE_HANDSHAKE, false, None) => {
+                        let (session_id, payload) =
+                            parse_client_hello(&body).ok_or_else(|| invalid_data("bad obfs tls client hello"))?;
+                        if session_id.len() == 32 {
+                            self.session_id = session_id;
+                        }
+                        self.plain.put_slice(&payload);
+                        self.read_first = true;
+                    }
+                    (CONTENT_TYPE_HANDSHAKE, false, Some(_)) => {
+                        if body.first() != Some(&0x02) {
+                            return Err(invalid_data("bad obfs tls server hello"));
+                        }
+                        self.read_first = true;
+                    }
+                    (_, false, _) => return Err(invalid_data("unexpected obfs tls record")),
+                    (CONTENT_TYPE_CHANGE_CIPHER_SPEC, true, _) => {}
+                    // server 的首包
+                    (CONTENT_TYPE_HANDSHAKE, true, Some(_)) => self.plain.put_slice(&body),
+                    (CONTENT_TYPE_APPLICATION_DATA, true, _) => self.plain.put_slice(&body),
+                    _ => return Err(invalid_data("unexpected obfs tls record")),
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    // 返回编码的字节数
+    fn encode(&mut self, buf: &[u8]) -> usize {
+        let n = match self.mode {
+            ObfsMode::Http => buf.len(),
+            ObfsMode::Tls => usize::min(buf.len(), MAX_RECORD_SIZE),
+        };
+        let payload = &buf[..n];
+        match (self.mode, self.written_first, &self.client) {
+            (ObfsMode::Http, false, Some((host, uri))) => http_request(host, uri, payload, &mut self.pending),
+            (ObfsMode::Http, false, None) => http_response(payload, &mut self.pending),
+            (ObfsMode::Http, true, _) => self.pending.put_slice(payload),
+            (ObfsMode::Tls, false, Some((host, _))) => client_hello(host, payload, &mut self.pending),
+            (ObfsMode::Tls, false, None) => server_hello(&self.session_id, payload, &mut self.pending),
+            (ObfsMode::Tls, true, _) => application_data(payload, &mut self.pending),
+        }
+        self.written_first = true;
+        n
+    }
+
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for ObfsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        loop {
+            if !me.plain.is_empty() {
+                let n = usize::min(buf.remaining(), me.plain.len());
+                buf.put_slice(&me.plain[..n]);
+                me.plain.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            // http 握手之后直接读取
+            if me.mode == ObfsMode::Http && me.read_first && me.raw.is_empty() {
+                return Pin::new(&mut me.inner).poll_read(cx, buf);
+            }
+            if me.decode()? {
+                continue;
+            }
+            let mut tmp = [0u8; READ_SIZE];
+            let mut read_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut me.inner).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                if me.raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            me.raw.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl AsyncWrite for ObfsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        ready!(me.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if me.mode == ObfsMode::Http && me.written_first {
+            return Pin::new(&mut me.inner).poll_write(cx, buf);
+        }
+        let n = me.encode(buf);
+        // 已经缓存在 pending 中，flush 时继续写出
+        if let Poll::Ready(Err(err)) = me.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub struct ObfsDialer {
+    inner: AnyDialer,
+    mode: ObfsMode,
+    host: Option<String>,
+    uri: String,
+}
+
+impl ObfsDialer {
+    pub fn new(inner: AnyDialer, settings: &ObfsSettings) -> Result<Self> {
+        Ok(ObfsDialer {
+            inner,
+            mode: ObfsMode::parse(settings.mode.as_deref().unwrap_or("http"))?,
+            host: settings.host.clone(),
+            uri: settings.uri.clone().unwrap_or_else(|| "/".to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl Dialer for ObfsDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let (name, port) = match addr {
+            Address::Domain(name, port) => (name.clone(), *port),
+            Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+        };
+        let host = self.host.clone().unwrap_or(name);
+        // 和 simple-obfs 一样，http 的 Host 带上非 80 端口
+        let host = match self.mode {
+            ObfsMode::Http if port != 80 => format!("{}:{}", host, port),
+            _ => host,
+        };
+        let stream = self.inner.dial(ctx, addr).await?;
+        Ok(Box::new(ObfsStream::new(stream, self.mode, Some((host, self.uri.clone())))))
+    }
+}
+
+// 首包在第一次读取时解析，不阻塞 accept
+pub struct ObfsStreamListener {
+    inner: AnyStreamListener,
+    mode: ObfsMode,
+}
+
+impl ObfsStreamListener {
+    pub fn new(inner: AnyStreamListener, settings: &ObfsSettings) -> Result<Self> {
+        Ok(ObfsStreamListener {
+            inner,
+            mode: ObfsMode::parse(settings.mode.as_deref().unwrap_or("http"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl StreamListener for ObfsStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        let (stream, session) = self.inner.accept().await?;
+        Ok((Box::new(ObfsStream::new(stream, self.mode, None)), session))
+    }
+}
+
+#[tokio::test]
+async fn obfs_test() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut hello = BytesMut::new();
+    client_hello("example.com", b"payload", &mut hello);
+    assert_eq!(hello.len(), 138 + 4 + 7 + 9 + 11 + 66);
+    let (session_id, payload) = parse_client_hello(&hello[RECORD_HEADER_LEN..]).unwrap();
+    assert_eq!(session_id.len(), 32);
+    assert_eq!(&payload[..], b"payload");
+
+    for mode in [ObfsMode::Http, ObfsMode::Tls] {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = ObfsStream::new(Box::new(client), mode, Some(("example.com".to_string(), "/".to_string())));
+        let mut server = ObfsStream::new(Box::new(server), mode, None);
+        let data: Vec<u8> = (0..100000u32).map(|x| x as u8).collect();
+        let expected = data.clone();
+        let echo = tokio::spawn(async move {
+            let mut buf = vec![0u8; 100000];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let write = tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.flush().await.unwrap();
+        });
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+        write.await.unwrap();
+        echo.await.unwrap();
+    }
+}
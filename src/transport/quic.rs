@@ -0,0 +1,640 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use log::debug;
+use quinn::{
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, RecvStream, SendDatagramError, SendStream,
+    ServerConfig, TokioRuntime, TransportConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+
+use crate::{
+    common::varint::{get_varint, put_varint},
+    config::{QuicSettings, TlsSettings},
+    net::SocketOpts,
+    proxy::{name_to_socket_addr, Address, AnyStream, Network, Session},
+    Context,
+};
+
+use super::{tls, Dialer, StreamListener};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+const DEFAULT_KEEP_ALIVE_PERIOD: u64 = 10;
+// client 打开的 stream 的第一个字节
+const STREAM_TCP: u8 = 0;
+const STREAM_UDP: u8 = 1;
+// 每个 flow 缓存的 datagram 数，满了直接丢弃，和 UDP 一样
+const FLOW_QUEUE_SIZE: usize = 128;
+
+fn transport_config(settings: &QuicSettings) -> Result<TransportConfig> {
+    let mut config = TransportConfig::default();
+    let idle_timeout = Duration::from_secs(settings.max_idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+    let idle_timeout = IdleTimeout::try_from(idle_timeout).map_err(|_| anyhow!("quic maxIdleTimeout too large"))?;
+    config.max_idle_timeout(Some(idle_timeout));
+    match settings.keep_alive_period.unwrap_or(DEFAULT_KEEP_ALIVE_PERIOD) {
+        0 => config.keep_alive_interval(None),
+        period => config.keep_alive_interval(Some(Duration::from_secs(period))),
+    };
+    Ok(config)
+}
+
+// 一个 bidirectional stream 承载一个代理连接
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+// 一个 QUIC 连接上的 UDP flow，按 stream id 分发收到的 datagram
+// datagram 为 | stream id (varint) | packet |
+#[derive(Default)]
+struct Flows {
+    senders: StdMutex<HashMap<u64, mpsc::Sender<Bytes>>>,
+}
+
+impl Flows {
+    fn register(&self, id: u64) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE_SIZE);
+        self.senders.lock().unwrap().insert(id, tx);
+        rx
+    }
+}
+
+async fn read_datagrams(conn: Connection, flows: Arc<Flows>) {
+    loop {
+        let data = match conn.read_datagram().await {
+            Ok(data) => data,
+            Err(err) => {
+                debug!("quic connection to {} closed {}", conn.remote_address(), err);
+                break;
+            }
+        };
+        let mut rest = &data[..];
+        let id = match get_varint(&mut rest) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        let packet = data.slice(data.len() - rest.len()..);
+        let tx = flows.senders.lock().unwrap().get(&id).cloned();
+        // flow 还没有建立或者已经关闭时丢弃
+        if let Some(tx) = tx {
+            let _ = tx.try_send(packet);
+        }
+    }
+    flows.senders.lock().unwrap().clear();
+}
+
+// UDP over stream，上层读写的仍然是 | 2 bytes length | packet | 的序列
+// stream 上先是代理协议的头，拆成 | 2 bytes length | data | 的 chunk，长度为 0 的 chunk 表示头结束
+// 收到对端的结束标记后 packet 通过 datagram 发送，超过 max_datagram_size 的仍然写在 stream 上
+// client 写完请求头后立即结束；server 在读到第一个 packet 时结束，此时 response header 已经写完
+pub struct QuicFlow {
+    send: SendStream,
+    recv: RecvStream,
+    conn: Connection,
+    flows: Arc<Flows>,
+    id: u64,
+    datagrams: mpsc::Receiver<Bytes>,
+    // 对端的头已经结束
+    read_packets: bool,
+    // 自己的头已经结束
+    write_packets: bool,
+    recv_buf: BytesMut,
+    read_buf: Bytes,
+    // 上层写入的不完整 packet
+    write_buf: BytesMut,
+    // 等待写入 stream 的数据
+    pending: BytesMut,
+}
+
+impl QuicFlow {
+    fn new(send: SendStream, recv: RecvStream, conn: Connection, flows: Arc<Flows>, write_packets: bool) -> Self {
+        let id = send.id().index();
+        let datagrams = flows.register(id);
+        QuicFlow {
+            send,
+            recv,
+            conn,
+            flows,
+            id,
+            datagrams,
+            read_packets: false,
+            write_packets,
+            recv_buf: BytesMut::new(),
+            read_buf: Bytes::new(),
+            write_buf: BytesMut::new(),
+            pending: BytesMut::new(),
+        }
+    }
+
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.send).poll_write(cx, &self.pending))?;
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // frame 为 | 2 bytes length | packet |
+    fn send_packet(&mut self, frame: BytesMut) -> io::Result<()> {
+        // 收到对端的结束标记时，对端已经注册了 flow
+        if self.read_packets {
+            let mut data = BytesMut::with_capacity(8 + frame.len());
+            put_varint(&mut data, self.id);
+            data.put_slice(&frame[2..]);
+            if self.conn.max_datagram_size().map(|max| data.len() <= max).unwrap_or(false) {
+                match self.conn.send_datagram(data.freeze()) {
+                    Ok(_) => return Ok(()),
+                    Err(SendDatagramError::ConnectionLost(err)) => {
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, err))
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        self.pending.extend_from_slice(&frame);
+        Ok(())
+    }
+}
+
+impl AsyncRead for QuicFlow {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        // 尽快发出 server 的结束标记
+        if let Poll::Ready(Err(err)) = me.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        loop {
+            if !me.read_buf.is_empty() {
+                let n = usize::min(buf.remaining(), me.read_buf.len());
+                buf.put_slice(&me.read_buf[..n]);
+                me.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if me.recv_buf.len() >= 2 {
+                let len = u16::from_be_bytes([me.recv_buf[0], me.recv_buf[1]]) as usize;
+                if !me.read_packets && len == 0 {
+                    me.recv_buf.advance(2);
+                    me.read_packets = true;
+                    if !me.write_packets {
+                        me.write_packets = true;
+                        me.pending.put_u16(0);
+                        if let Poll::Ready(Err(err)) = me.poll_pending(cx) {
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    continue;
+                }
+                if me.recv_buf.len() >= 2 + len {
+                    if me.read_packets {
+                        me.read_buf = me.recv_buf.split_to(2 + len).freeze();
+                    } else {
+                        me.recv_buf.advance(2);
+                        me.read_buf = me.recv_buf.split_to(len).freeze();
+                    }
+                    continue;
+                }
+            }
+            if me.read_packets {
+                if let Poll::Ready(Some(packet)) = me.datagrams.poll_recv(cx) {
+                    let mut frame = BytesMut::with_capacity(2 + packet.len());
+                    frame.put_u16(packet.len() as u16);
+                    frame.put_slice(&packet);
+                    me.read_buf = frame.freeze();
+                    continue;
+                }
+            }
+            let mut tmp = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut me.recv).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                if !me.recv_buf.is_empty() {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                return Poll::Ready(Ok(()));
+            }
+            me.recv_buf.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl AsyncWrite for QuicFlow {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let me = &mut *self;
+        ready!(me.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = if me.write_packets {
+            me.write_buf.extend_from_slice(buf);
+            while me.write_buf.len() >= 2 {
+                let len = u16::from_be_bytes([me.write_buf[0], me.write_buf[1]]) as usize;
+                if me.write_buf.len() < 2 + len {
+                    break;
+                }
+                let frame = me.write_buf.split_to(2 + len);
+                me.send_packet(frame)?;
+            }
+            buf.len()
+        } else {
+            let n = usize::min(buf.len(), u16::MAX as usize);
+            me.pending.put_u16(n as u16);
+            me.pending.put_slice(&buf[..n]);
+            n
+        };
+        if let Poll::Ready(Err(err)) = me.poll_pending(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl Drop for QuicFlow {
+    fn drop(&mut self) {
+        self.flows.senders.lock().unwrap().remove(&self.id);
+    }
+}
+
+// OUTBOUND
+
+// 所有代理连接复用同一个 QUIC 连接，断开后重连时通过 session ticket 使用 0-RTT
+pub struct QuicDialer {
+    config: ClientConfig,
+    server_name: Option<String>,
+    conn: Mutex<Option<(Connection, Arc<Flows>)>>,
+    opts: SocketOpts,
+}
+
+impl QuicDialer {
+    pub fn new(tls_settings: &TlsSettings, settings: &QuicSettings, opts: SocketOpts) -> Result<Self> {
+        let mut crypto = tls::client_config(tls_settings)?;
+        crypto.enable_early_data = true;
+        let mut config = ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport_config(settings)?));
+        Ok(QuicDialer {
+            config,
+            server_name: tls_settings.server_name.clone(),
+            conn: Mutex::new(None),
+            opts,
+        })
+    }
+
+    async fn connection(&self, ctx: Arc<Context>, addr: &Address) -> Result<(Connection, Arc<Flows>)> {
+        let mut conn = self.conn.lock().await;
+        if let Some((connection, flows)) = conn.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok((connection.clone(), flows.clone()));
+            }
+        }
+        let remote = name_to_socket_addr(ctx.dns_client.clone(), addr.clone()).await?;
+        let server_name = match (&self.server_name, addr) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Domain(name, _)) => name.clone(),
+            (None, Address::Ip(addr)) => addr.ip().to_string(),
+        };
+        let endpoint = Endpoint::new(EndpointConfig::default(), None, self.opts.udp_socket(remote)?, TokioRuntime)?;
+        let connecting = endpoint.connect_with(self.config.clone(), remote, &server_name)?;
+        // 0-RTT 被 server 拒绝时，握手完成前打开的 stream 会失败
+        let connection = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                tokio::spawn(async move {
+                    if !accepted.await {
+                        debug!("quic 0-rtt rejected by {}", remote);
+                    }
+                });
+                connection
+            }
+            Err(connecting) => match timeout(HANDSHAKE_TIMEOUT, connecting).await {
+                Ok(Ok(connection)) => connection,
+                Ok(Err(err)) => return Err(anyhow!("quic handshake with {} failed: {}", addr, err)),
+                Err(_) => return Err(anyhow!("quic handshake with {} timeout", addr)),
+            },
+        };
+        let flows = Arc::new(Flows::default());
+        tokio::spawn(read_datagrams(connection.clone(), flows.clone()));
+        *conn = Some((connection.clone(), flows.clone()));
+        Ok((connection, flows))
+    }
+
+    async fn open(&self, connection: &Connection, addr: &Address, prefix: &[u8]) -> Result<(SendStream, RecvStream)> {
+        let (mut send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|err| anyhow!("quic open stream to {} failed: {}", addr, err))?;
+        send.write_all(prefix)
+            .await
+            .map_err(|err| anyhow!("quic write to {} failed: {}", addr, err))?;
+        Ok((send, recv))
+    }
+}
+
+#[async_trait]
+impl Dialer for QuicDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let (connection, _) = self.connection(ctx, addr).await?;
+        let (send, recv) = self.open(&connection, addr, &[STREAM_TCP]).await?;
+        Ok(Box::new(QuicStream { send, recv }))
+    }
+
+    async fn dial_udp(&self, ctx: Arc<Context>, addr: &Address, header: &[u8]) -> Result<AnyStream> {
+        if header.is_empty() || header.len() > u16::MAX as usize {
+            bail!("bad header length {} for quic flow", header.len());
+        }
+        let (connection, flows) = self.connection(ctx, addr).await?;
+        let mut prefix = BytesMut::with_capacity(5 + header.len());
+        prefix.put_u8(STREAM_UDP);
+        prefix.put_u16(header.len() as u16);
+        prefix.put_slice(header);
+        prefix.put_u16(0);
+        let (send, recv) = self.open(&connection, addr, &prefix).await?;
+        Ok(Box::new(QuicFlow::new(send, recv, connection, flows, true)))
+    }
+}
+
+// INBOUND
+
+pub struct QuicStreamListener {
+    local_addr: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<(AnyStream, Session)>>,
+}
+
+impl QuicStreamListener {
+    pub fn bind(addr: SocketAddr, tls_settings: &TlsSettings, settings: &QuicSettings) -> Result<Self> {
+        let mut crypto = tls::server_config(tls_settings)?;
+        // QUIC 要求为 u32::MAX
+        crypto.max_early_data_size = u32::MAX;
+        let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport_config(settings)?));
+        let endpoint = Endpoint::server(config, addr)?;
+        let local_addr = endpoint.local_addr()?;
+
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                if tx.is_closed() {
+                    return;
+                }
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let peer = connecting.remote_address();
+                    // 0.5-RTT，client 的 0-RTT 数据可能被重放，由上层协议负责
+                    let connection = match connecting.into_0rtt() {
+                        Ok((connection, _)) => connection,
+                        Err(connecting) => match timeout(HANDSHAKE_TIMEOUT, connecting).await {
+                            Ok(Ok(connection)) => connection,
+                            Ok(Err(err)) => {
+                                debug!("quic handshake with {} failed: {}", peer, err);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("quic handshake with {} timeout", peer);
+                                return;
+                            }
+                        },
+                    };
+                    let session = Session {
+                        destination: Address::Ip(peer),
+                        network: Network::TCP,
+                        local_peer: local_addr,
+                        peer_address: peer,
+                    };
+                    let flows = Arc::new(Flows::default());
+                    tokio::spawn(read_datagrams(connection.clone(), flows.clone()));
+                    loop {
+                        let (send, mut recv) = match connection.accept_bi().await {
+                            Ok(x) => x,
+                            Err(err) => {
+                                debug!("quic connection from {} closed {}", peer, err);
+                                return;
+                            }
+                        };
+                        if tx.is_closed() {
+                            return;
+                        }
+                        let (tx, session, connection, flows) = (tx.clone(), session.clone(), connection.clone(), flows.clone());
+                        // 读取 stream 类型时不阻塞后续 stream
+                        tokio::spawn(async move {
+                            let stream: AnyStream = match timeout(HANDSHAKE_TIMEOUT, recv.read_u8()).await {
+                                Ok(Ok(STREAM_TCP)) => Box::new(QuicStream { send, recv }),
+                                // 在交给 inbound 之前注册 flow
+                                Ok(Ok(STREAM_UDP)) => Box::new(QuicFlow::new(send, recv, connection, flows, false)),
+                                Ok(Ok(kind)) => {
+                                    debug!("unknown quic stream type {} from {}", kind, peer);
+                                    return;
+                                }
+                                Ok(Err(err)) => {
+                                    debug!("quic stream from {} closed {}", peer, err);
+                                    return;
+                                }
+                                Err(_) => {
+                                    debug!("quic stream from {} timeout", peer);
+                                    return;
+                                }
+                            };
+                            let _ = tx.send((stream, session)).await;
+                        });
+                    }
+                });
+            }
+        });
+        Ok(QuicStreamListener {
+            local_addr,
+            incoming: Mutex::new(rx),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl StreamListener for QuicStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => Ok(x),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "quic listener closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn quic_transport_test() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{app::DnsClient, config::Config};
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let server_tls = TlsSettings {
+        certificate_file: Some("tests/certs/server.pem".to_string()),
+        key_file: Some("tests/certs/server.key".to_string()),
+        ..Default::default()
+    };
+    let listener = QuicStreamListener::bind("127.0.0.1:0".parse().unwrap(), &server_tls, &QuicSettings::default()).unwrap();
+    let addr = Address::Ip(listener.local_addr());
+    let client_tls = TlsSettings {
+        server_name: Some("localhost".to_string()),
+        ca: Some("tests/certs/ca.pem".to_string()),
+        ..Default::default()
+    };
+    let dialer = QuicDialer::new(&client_tls, &QuicSettings::default(), SocketOpts::default()).unwrap();
+
+    // 多个 stream 复用同一个连接
+    let mut peers = Vec::new();
+    for i in 0..3u8 {
+        let mut client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+        client.write_all(&[i]).await.unwrap();
+        let (mut server, session) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[0], i);
+        server.write_all(b"pong").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"pong");
+        peers.push(session.peer_address);
+    }
+    assert!(peers.iter().all(|x| *x == peers[0]));
+
+    // 连接关闭后重连
+    dialer.conn.lock().await.as_ref().unwrap().0.close(0u32.into(), b"");
+    let mut client = dialer.dial(ctx.clone(), &addr).await.unwrap();
+    client.write_all(b"again").await.unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"again");
+}
+
+#[tokio::test]
+async fn quic_flow_test() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{app::DnsClient, config::Config};
+
+    async fn write_packet(stream: &mut AnyStream, packet: &[u8]) {
+        stream.write_u16(packet.len() as u16).await.unwrap();
+        stream.write_all(packet).await.unwrap();
+    }
+
+    async fn read_packet(stream: &mut AnyStream) -> Vec<u8> {
+        let len = stream.read_u16().await.unwrap() as usize;
+        let mut packet = vec![0u8; len];
+        stream.read_exact(&mut packet).await.unwrap();
+        packet
+    }
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let server_tls = TlsSettings {
+        certificate_file: Some("tests/certs/server.pem".to_string()),
+        key_file: Some("tests/certs/server.key".to_string()),
+        ..Default::default()
+    };
+    let listener = QuicStreamListener::bind("127.0.0.1:0".parse().unwrap(), &server_tls, &QuicSettings::default()).unwrap();
+    let addr = Address::Ip(listener.local_addr());
+    let client_tls = TlsSettings {
+        server_name: Some("localhost".to_string()),
+        ca: Some("tests/certs/ca.pem".to_string()),
+        ..Default::default()
+    };
+    let dialer = QuicDialer::new(&client_tls, &QuicSettings::default(), SocketOpts::default()).unwrap();
+
+    // 收到 server 的结束标记前，packet 写在 stream 上
+    let mut client = dialer.dial_udp(ctx.clone(), &addr, b"header").await.unwrap();
+    write_packet(&mut client, b"first").await;
+    let (mut server, _) = listener.accept().await.unwrap();
+    let mut header = [0u8; 6];
+    server.read_exact(&mut header).await.unwrap();
+    assert_eq!(&header, b"header");
+    server.write_all(b"ok").await.unwrap();
+    assert_eq!(read_packet(&mut server).await, b"first");
+
+    write_packet(&mut server, b"reply").await;
+    let mut response = [0u8; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&response, b"ok");
+    assert_eq!(read_packet(&mut client).await, b"reply");
+
+    // 小 packet 通过 datagram，超过 max_datagram_size 的仍然在 stream 上
+    let connection = dialer.conn.lock().await.as_ref().unwrap().0.clone();
+    let sent = connection.stats().frame_tx.datagram;
+    write_packet(&mut client, b"small").await;
+    write_packet(&mut client, &[7u8; 5000]).await;
+    let mut received = vec![read_packet(&mut server).await, read_packet(&mut server).await];
+    received.sort_by_key(|x| x.len());
+    assert_eq!(received, vec![b"small".to_vec(), vec![7u8; 5000]]);
+    assert_eq!(connection.stats().frame_tx.datagram, sent + 1);
+
+    // 同一个连接上的普通 stream 不受影响
+    let mut stream = dialer.dial(ctx.clone(), &addr).await.unwrap();
+    stream.write_all(b"tcp").await.unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 3];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"tcp");
+}
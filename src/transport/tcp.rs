@@ -0,0 +1,96 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+
+use crate::{
+    config::SockoptSettings,
+    net::{create_tcp_listener, SocketOpts},
+    proxy::{connect_to_remote_tcp, Address, AnyStream, Network, Session},
+    Context,
+};
+
+use super::{Dialer, StreamListener};
+
+#[derive(Default)]
+pub struct TcpDialer {
+    opts: SocketOpts,
+}
+
+impl TcpDialer {
+    pub fn new(opts: SocketOpts) -> Self {
+        TcpDialer { opts }
+    }
+}
+
+#[async_trait]
+impl Dialer for TcpDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let stream = connect_to_remote_tcp(ctx.dns_client.clone(), addr.clone(), &self.opts).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+pub struct TcpStreamListener {
+    inner: TcpListener,
+}
+
+impl TcpStreamListener {
+    pub fn new(inner: TcpListener) -> Self {
+        TcpStreamListener { inner }
+    }
+
+    // reuse_port 为 true 时可以在同一地址 bind 多个 listener
+    pub fn bind(addr: SocketAddr, sockopt: &SockoptSettings, reuse_port: bool) -> io::Result<Self> {
+        Ok(TcpStreamListener::new(create_tcp_listener(addr, sockopt, reuse_port)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[async_trait]
+impl StreamListener for TcpStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        let (stream, peer) = self.inner.accept().await?;
+        let session = Session {
+            destination: Address::Ip(peer),
+            network: Network::TCP,
+            local_peer: stream.local_addr()?,
+            peer_address: peer,
+        };
+        Ok((Box::new(stream), session))
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn reuse_port_listener_test() {
+    use futures::{stream::FuturesUnordered, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    let first = TcpStreamListener::bind("127.0.0.1:0".parse().unwrap(), &SockoptSettings::default(), true).unwrap();
+    let addr = first.local_addr().unwrap();
+    let mut listeners = vec![first];
+    for _ in 1..4 {
+        listeners.push(TcpStreamListener::bind(addr, &SockoptSettings::default(), true).unwrap());
+    }
+    // 4 个 listener 共享同一个 port，连接可能被分配到任意一个
+    for _ in 0..8 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut accepts: FuturesUnordered<_> = listeners.iter().map(|x| x.accept()).collect();
+        let (mut accepted, session) = accepts.next().await.unwrap().unwrap();
+        assert_eq!(session.peer_address, stream.local_addr().unwrap());
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+    // 没有设置 SO_REUSEPORT 的 listener 无法 bind
+    assert!(TcpStreamListener::bind(addr, &SockoptSettings::default(), false).is_err());
+}
@@ -0,0 +1,542 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader},
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use ring::{
+    digest::{digest, SHA256},
+    signature::{self, UnparsedPublicKey, VerificationAlgorithm},
+};
+use rustls_pemfile::Item;
+use tokio::{sync::mpsc, time::timeout};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+        server::{AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey, SigningKey},
+        Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+        ServerName, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use x509_parser::parse_x509_certificate;
+
+use crate::{
+    config::TlsSettings,
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+use super::{AnyDialer, AnyStreamListener, Dialer, StreamListener};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// accept 出错时重试前的等待时间
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+// 轮询证书文件的修改时间，certbot 续期后无需重启
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct TlsDialer {
+    inner: AnyDialer,
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl TlsDialer {
+    pub fn new(inner: AnyDialer, settings: &TlsSettings) -> Result<Self> {
+        Ok(TlsDialer {
+            inner,
+            connector: TlsConnector::from(Arc::new(client_config(settings)?)),
+            server_name: settings.server_name.clone(),
+        })
+    }
+
+    fn server_name(&self, addr: &Address) -> Result<ServerName> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => match addr {
+                Address::Domain(name, _) => name.as_str(),
+                Address::Ip(addr) => return Ok(ServerName::IpAddress(addr.ip())),
+            },
+        };
+        ServerName::try_from(name).map_err(|_| anyhow!("invalid tls server name {}", name))
+    }
+}
+
+// client 和 server 的 rustls 配置，tls 和 quic 共用
+pub(super) fn client_config(settings: &TlsSettings) -> Result<ClientConfig> {
+    let verifier = CertVerifier::new(settings)?;
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match (&settings.certificate_file, &settings.key_file) {
+        (Some(cert), Some(key)) => {
+            let certs = load_certs(cert)?.into_iter().map(Certificate).collect();
+            builder.with_single_cert(certs, load_private_key(key)?)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+    if let Some(alpn) = &settings.alpn {
+        config.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+    }
+    Ok(config)
+}
+
+pub(super) fn server_config(settings: &TlsSettings) -> Result<ServerConfig> {
+    let (certificate_file, key_file) = match (&settings.certificate_file, &settings.key_file) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        _ => bail!("tls inbound requires certificateFile and keyFile"),
+    };
+    let resolver = Arc::new(CertResolver::new(certificate_file, key_file)?);
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _ignored) = roots.add_parsable_certificates(&load_certs(path)?);
+            if added == 0 {
+                bail!("no valid client ca certificate found in {}", path);
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_client_cert_verifier(NoClientAuth::new()),
+    };
+    let mut config = builder.with_cert_resolver(resolver.clone());
+    if let Some(alpn) = &settings.alpn {
+        config.alpn_protocols = alpn.iter().map(|x| x.as_bytes().to_vec()).collect();
+    }
+    tokio::spawn(watch_certificate(Arc::downgrade(&resolver)));
+    Ok(config)
+}
+
+#[async_trait]
+impl Dialer for TlsDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let server_name = self.server_name(addr)?;
+        let stream = self.inner.dial(ctx, addr).await?;
+        let stream = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|err| anyhow!("tls handshake with {} failed: {}", addr, err))?;
+        Ok(Box::new(stream))
+    }
+}
+
+pub fn load_certs(path: &str) -> Result<Vec<Vec<u8>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", path);
+    }
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key found in {}", path)
+}
+
+pub fn spki_sha256(cert: &[u8]) -> Result<Vec<u8>> {
+    let (_, cert) = parse_x509_certificate(cert).map_err(|err| anyhow!("bad certificate {}", err))?;
+    Ok(digest(&SHA256, cert.tbs_certificate.subject_pki.raw).as_ref().to_vec())
+}
+
+// allowInsecure 时跳过证书链校验
+// 设置了 pinnedPeerSpkiSha256 时，server 证书的 SPKI 必须在其中
+struct CertVerifier {
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl CertVerifier {
+    fn new(settings: &TlsSettings) -> Result<Self> {
+        let mut pins = Vec::new();
+        for pin in settings.pinned_peer_spki_sha256.iter().flatten() {
+            match base64::decode(pin) {
+                Ok(x) if x.len() == 32 => pins.push(x),
+                _ => bail!("bad pinned spki sha256 {}", pin),
+            }
+        }
+        if settings.allow_insecure.unwrap_or(false) {
+            if pins.is_empty() {
+                warn!("tls certificate verification disabled by allowInsecure");
+            }
+            return Ok(CertVerifier { webpki: None, pins });
+        }
+        let mut roots = RootCertStore::empty();
+        match &settings.ca {
+            Some(path) => {
+                let (added, _ignored) = roots.add_parsable_certificates(&load_certs(path)?);
+                if added == 0 {
+                    bail!("no valid ca certificate found in {}", path);
+                }
+            }
+            None => {
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+            }
+        }
+        Ok(CertVerifier {
+            webpki: Some(WebPkiVerifier::new(roots, None)),
+            pins,
+        })
+    }
+}
+
+impl ServerCertVerifier for CertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let hash = spki_sha256(&end_entity.0)
+                .map_err(|err| rustls::Error::InvalidCertificateData(err.to_string()))?;
+            if !self.pins.contains(&hash) {
+                return Err(rustls::Error::InvalidCertificateData(
+                    "server public key not pinned".to_string(),
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// 证书文件变化时重新加载，已建立的连接不受影响
+struct CertResolver {
+    certificate_file: String,
+    key_file: String,
+    modified: RwLock<(SystemTime, SystemTime)>,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    fn new(certificate_file: String, key_file: String) -> Result<Self> {
+        let modified = (fs::metadata(&certificate_file)?.modified()?, fs::metadata(&key_file)?.modified()?);
+        let key = Self::load(&certificate_file, &key_file)?;
+        Ok(CertResolver {
+            certificate_file,
+            key_file,
+            modified: RwLock::new(modified),
+            key: RwLock::new(key),
+        })
+    }
+
+    // 非原子地替换证书和私钥时，可能读到新证书和旧私钥，不匹配时返回错误
+    fn load(certificate_file: &str, key_file: &str) -> Result<Arc<CertifiedKey>> {
+        let certs: Vec<Certificate> = load_certs(certificate_file)?.into_iter().map(Certificate).collect();
+        let key = any_supported_type(&load_private_key(key_file)?)
+            .map_err(|_| anyhow!("unsupported private key in {}", key_file))?;
+        let end_entity = certs.first().ok_or_else(|| anyhow!("no certificate found in {}", certificate_file))?;
+        if !key_matches(end_entity, key.as_ref())? {
+            bail!("certificate {} does not match private key {}", certificate_file, key_file);
+        }
+        Ok(Arc::new(CertifiedKey::new(certs, key)))
+    }
+
+    // 文件有变化且重新加载成功时返回 true
+    fn reload(&self) -> Result<bool> {
+        let modified = (
+            fs::metadata(&self.certificate_file)?.modified()?,
+            fs::metadata(&self.key_file)?.modified()?,
+        );
+        if *self.modified.read().unwrap() == modified {
+            return Ok(false);
+        }
+        let key = Self::load(&self.certificate_file, &self.key_file)?;
+        *self.key.write().unwrap() = key;
+        *self.modified.write().unwrap() = modified;
+        Ok(true)
+    }
+}
+
+// 用私钥签名，再用证书中的公钥验证
+fn key_matches(cert: &Certificate, key: &dyn SigningKey) -> Result<bool> {
+    const MESSAGE: &[u8] = b"tunnel certificate key check";
+    let schemes = [
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ];
+    let signer = key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| anyhow!("unsupported private key algorithm {:?}", key.algorithm()))?;
+    let algorithm: &'static dyn VerificationAlgorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &signature::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        _ => &signature::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let sig = signer.sign(MESSAGE)?;
+    let (_, cert) = parse_x509_certificate(&cert.0).map_err(|err| anyhow!("bad certificate {}", err))?;
+    let public_key = cert.tbs_certificate.subject_pki.subject_public_key.data;
+    Ok(UnparsedPublicKey::new(algorithm, public_key).verify(MESSAGE, &sig).is_ok())
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+async fn watch_certificate(resolver: Weak<CertResolver>) {
+    loop {
+        tokio::time::sleep(CERT_RELOAD_INTERVAL).await;
+        // listener 已经关闭
+        let resolver = match resolver.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+        match resolver.reload() {
+            Ok(true) => info!("tls certificate {} reloaded", resolver.certificate_file),
+            Ok(false) => {}
+            // 证书可能正在被替换，保留旧证书，下次再试
+            Err(err) => warn!("failed to reload tls certificate {} {}", resolver.certificate_file, err),
+        }
+    }
+}
+
+// TLS 握手在单独的 task 中进行，避免慢速 client 阻塞 accept
+pub struct TlsStreamListener {
+    incoming: tokio::sync::Mutex<mpsc::Receiver<io::Result<(AnyStream, Session)>>>,
+}
+
+impl TlsStreamListener {
+    pub fn new(inner: AnyStreamListener, settings: &TlsSettings) -> Result<Self> {
+        let config = server_config(settings)?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            loop {
+                let res = tokio::select! {
+                    res = inner.accept() => res,
+                    // TlsStreamListener 已经 drop
+                    _ = tx.closed() => return,
+                };
+                let (stream, session) = match res {
+                    Ok(x) => x,
+                    // 单个连接 accept 失败 (例如 fd 耗尽) 不结束 listener，稍后重试
+                    Err(err) => {
+                        warn!("tls listener accept error {}", err);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let stream: AnyStream = Box::new(stream);
+                            let _ = tx.send(Ok((stream, session))).await;
+                        }
+                        Ok(Err(err)) => debug!("tls handshake with {} failed: {}", session.peer_address, err),
+                        Err(_) => debug!("tls handshake with {} timeout", session.peer_address),
+                    }
+                });
+            }
+        });
+        Ok(TlsStreamListener {
+            incoming: tokio::sync::Mutex::new(rx),
+        })
+    }
+}
+
+#[async_trait]
+impl StreamListener for TlsStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => x,
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "tls listener closed")),
+        }
+    }
+}
+
+#[test]
+fn spki_sha256_test() {
+    let cert = load_certs("tests/certs/server.pem").unwrap().remove(0);
+    assert_eq!(
+        base64::encode(spki_sha256(&cert).unwrap()),
+        "XOxmzHvr+6yggu290ZART7IaZ7b4SEosG773rw3FPf8="
+    );
+}
+
+#[tokio::test]
+async fn tls_dialer_test() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::RwLock,
+    };
+    use tokio_rustls::{
+        rustls::{PrivateKey, ServerConfig},
+        TlsAcceptor,
+    };
+
+    use crate::{app::DnsClient, config::Config, transport::TcpDialer};
+
+    async fn dial(settings: TlsSettings, addr: &Address) -> Result<()> {
+        let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+        let dialer = TlsDialer::new(Arc::new(TcpDialer::default()), &settings)?;
+        let mut stream = dialer.dial(ctx, addr).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(())
+    }
+
+    // tls echo server
+    let certs = load_certs("tests/certs/server.pem").unwrap();
+    let mut reader = BufReader::new(File::open("tests/certs/server.key").unwrap());
+    let key = rustls_pemfile::pkcs8_private_keys(&mut reader).unwrap().remove(0);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = Address::Ip(listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let mut buf = [0u8; 5];
+                    if stream.read_exact(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(&buf).await;
+                    }
+                }
+            });
+        }
+    });
+
+    let settings = TlsSettings {
+        server_name: Some("localhost".to_string()),
+        ca: Some("tests/certs/ca.pem".to_string()),
+        ..Default::default()
+    };
+    dial(settings.clone(), &addr).await.unwrap();
+
+    let mut pinned = settings.clone();
+    pinned.pinned_peer_spki_sha256 = Some(vec!["XOxmzHvr+6yggu290ZART7IaZ7b4SEosG773rw3FPf8=".to_string()]);
+    dial(pinned, &addr).await.unwrap();
+
+    let mut wrong_name = settings.clone();
+    wrong_name.server_name = Some("example.com".to_string());
+    assert!(dial(wrong_name, &addr).await.is_err());
+
+    // 内置的 webpki roots 不信任测试 CA
+    let mut untrusted = settings;
+    untrusted.ca = None;
+    assert!(dial(untrusted.clone(), &addr).await.is_err());
+    untrusted.allow_insecure = Some(true);
+    dial(untrusted.clone(), &addr).await.unwrap();
+    untrusted.pinned_peer_spki_sha256 = Some(vec![base64::encode([0u8; 32])]);
+    assert!(dial(untrusted, &addr).await.is_err());
+}
+
+#[test]
+fn cert_reload_test() {
+    let dir = std::env::temp_dir().join(format!("tunnel-cert-reload-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert = dir.join("cert.pem").to_string_lossy().to_string();
+    let key = dir.join("key.pem").to_string_lossy().to_string();
+    fs::copy("tests/certs/server.pem", &cert).unwrap();
+    fs::copy("tests/certs/server.key", &key).unwrap();
+
+    let resolver = CertResolver::new(cert.clone(), key.clone()).unwrap();
+    let before = resolver.key.read().unwrap().cert[0].clone();
+    assert!(!resolver.reload().unwrap());
+
+    std::thread::sleep(Duration::from_millis(10));
+    // 只替换了证书，保留旧的证书和私钥
+    fs::copy("tests/certs/client.pem", &cert).unwrap();
+    assert!(resolver.reload().is_err());
+    assert!(resolver.key.read().unwrap().cert[0] == before);
+    fs::copy("tests/certs/client.key", &key).unwrap();
+    assert!(resolver.reload().unwrap());
+    assert!(resolver.key.read().unwrap().cert[0] != before);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn tls_listener_accept_error_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{app::DnsClient, config::{Config, SockoptSettings}, transport::{TcpDialer, TcpStreamListener}};
+
+    // 第一次 accept 返回错误
+    struct FailOnce {
+        inner: TcpStreamListener,
+        failed: AtomicBool,
+    }
+
+    #[async_trait]
+    impl StreamListener for FailOnce {
+        async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::Other, "too many open files"));
+            }
+            self.inner.accept().await
+        }
+    }
+
+    let inner = TcpStreamListener::bind("127.0.0.1:0".parse().unwrap(), &SockoptSettings::default(), false).unwrap();
+    let addr = Address::Ip(inner.local_addr().unwrap());
+    let settings = TlsSettings {
+        certificate_file: Some("tests/certs/server.pem".to_string()),
+        key_file: Some("tests/certs/server.key".to_string()),
+        ..Default::default()
+    };
+    let listener = TlsStreamListener::new(Box::new(FailOnce { inner, failed: AtomicBool::new(false) }), &settings).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let settings = TlsSettings {
+        server_name: Some("localhost".to_string()),
+        ca: Some("tests/certs/ca.pem".to_string()),
+        ..Default::default()
+    };
+    let dialer = TlsDialer::new(Arc::new(TcpDialer::default()), &settings).unwrap();
+    let mut stream = timeout(Duration::from_secs(2), dialer.dial(ctx, &addr)).await.unwrap().unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+}
@@ -0,0 +1,577 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use futures::{future::BoxFuture, ready, FutureExt, SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+use tokio_tungstenite::{
+    accept_hdr_async, client_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{HeaderName, HeaderValue, StatusCode},
+        Error as WsError, Message,
+    },
+    WebSocketStream,
+};
+
+use crate::{
+    config::{StreamSettings, WsSettings},
+    proxy::{Address, AnyStream, Session},
+    Context,
+};
+
+use super::{bind_tcp, AnyDialer, AnyStreamListener, Dialer, StreamListener};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn ws_error(err: WsError) -> io::Error {
+    match err {
+        WsError::Io(err) => err,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, "websocket closed")
+        }
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+fn decode_early_data(data: &[u8]) -> Option<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
+
+// 将 websocket 的 binary message 转换为字节流
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: BytesMut,
+}
+
+impl<S> WsStream<S> {
+    // early data 是握手时已经收到的首包
+    pub fn new(inner: WebSocketStream<S>, early_data: BytesMut) -> Self {
+        WsStream {
+            inner,
+            read_buf: early_data,
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let me = &mut *self;
+        loop {
+            if !me.read_buf.is_empty() {
+                let n = usize::min(buf.remaining(), me.read_buf.len());
+                buf.put_slice(&me.read_buf[..n]);
+                me.read_buf.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(me.inner.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => me.read_buf.extend_from_slice(&data),
+                Some(Ok(Message::Text(data))) => me.read_buf.extend_from_slice(data.as_bytes()),
+                // ping 由 tungstenite 自动回复
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) | Some(Ok(Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(ws_error(err))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.inner.poll_ready_unpin(cx)).map_err(ws_error)?;
+        self.inner
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_flush_unpin(cx).map_err(ws_error)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match ready!(self.inner.poll_close_unpin(cx)) {
+            Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            res => Poll::Ready(res.map_err(ws_error)),
+        }
+    }
+}
+
+// OUTBOUND
+
+struct WsClientConfig {
+    path: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    max_early_data: usize,
+    early_data_header_name: Option<HeaderName>,
+}
+
+impl WsClientConfig {
+    fn request(&self, addr: &Address, early_data: &[u8]) -> Result<Request> {
+        let mut path = self.path.clone();
+        let mut early_data_header = None;
+        if !early_data.is_empty() {
+            let encoded = base64::encode_config(early_data, base64::URL_SAFE_NO_PAD);
+            match &self.early_data_header_name {
+                Some(name) => early_data_header = Some((name.clone(), HeaderValue::from_str(&encoded)?)),
+                None => path.push_str(&encoded),
+            }
+        }
+        // Host 默认使用 server 地址，可以被 headers 覆盖
+        let host = match addr {
+            Address::Domain(name, port) => format!("{}:{}", name, port),
+            Address::Ip(addr) => addr.to_string(),
+        };
+        let mut request = format!("ws://{}{}", host, path).into_client_request()?;
+        for (name, value) in self.headers.iter().cloned().chain(early_data_header) {
+            request.headers_mut().insert(name, value);
+        }
+        Ok(request)
+    }
+}
+
+pub struct WsDialer {
+    inner: AnyDialer,
+    config: Arc<WsClientConfig>,
+}
+
+impl WsDialer {
+    pub fn new(inner: AnyDialer, settings: &WsSettings) -> Result<Self> {
+        let mut headers = Vec::new();
+        for (name, value) in settings.headers.iter().flatten() {
+            headers.push((HeaderName::from_bytes(name.as_bytes())?, HeaderValue::from_str(value)?));
+        }
+        let early_data_header_name = match &settings.early_data_header_name {
+            Some(name) => Some(HeaderName::from_bytes(name.as_bytes())?),
+            None => None,
+        };
+        Ok(WsDialer {
+            inner,
+            config: Arc::new(WsClientConfig {
+                path: settings.path.clone().unwrap_or_else(|| "/".to_string()),
+                headers,
+                max_early_data: settings.max_early_data.unwrap_or(0),
+                early_data_header_name,
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl Dialer for WsDialer {
+    async fn dial(&self, ctx: Arc<Context>, addr: &Address) -> Result<AnyStream> {
+        let stream = self.inner.dial(ctx, addr).await?;
+        if self.config.max_early_data > 0 {
+            return Ok(Box::new(EarlyDataStream {
+                state: ClientState::Pending(stream),
+                config: self.config.clone(),
+                addr: addr.clone(),
+                early_written: 0,
+            }));
+        }
+        let request = self.config.request(addr, &[])?;
+        let (stream, _) = client_async(request, stream)
+            .await
+            .map_err(|err| anyhow!("websocket handshake with {} failed: {}", addr, err))?;
+        Ok(Box::new(WsStream::new(stream, BytesMut::new())))
+    }
+}
+
+type ConnectFuture = BoxFuture<'static, io::Result<WsStream<AnyStream>>>;
+
+enum ClientState {
+    // 还未握手
+    Pending(AnyStream),
+    // 握手中，usize 为随握手发送的 early data 长度
+    // AnyStream 需要 Sync，BoxFuture 只有 Send，所以放在 Mutex 中
+    Connecting(StdMutex<ConnectFuture>, usize),
+    Connected(Box<WsStream<AnyStream>>),
+    Closed,
+}
+
+// 握手推迟到第一次写入，首包作为 early data 随握手发送
+// 如果先读，则不带 early data 直接握手
+struct EarlyDataStream {
+    state: ClientState,
+    config: Arc<WsClientConfig>,
+    addr: Address,
+    // 已随握手发送，还未返回给 poll_write 的长度
+    early_written: usize,
+}
+
+impl EarlyDataStream {
+    fn connect(&mut self, early_data: &[u8]) {
+        let stream = match std::mem::replace(&mut self.state, ClientState::Closed) {
+            ClientState::Pending(stream) => stream,
+            state => {
+                self.state = state;
+                return;
+            }
+        };
+        let config = self.config.clone();
+        let addr = self.addr.clone();
+        let early_len = early_data.len();
+        let early_data = early_data.to_vec();
+        let future = async move {
+            let request = config
+                .request(&addr, &early_data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+            let (stream, _) = client_async(request, stream).await.map_err(ws_error)?;
+            Ok(WsStream::new(stream, BytesMut::new()))
+        }
+        .boxed();
+        self.state = ClientState::Connecting(StdMutex::new(future), early_len);
+    }
+
+    fn poll_connect(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let (res, early_len) = match &mut self.state {
+            ClientState::Connecting(future, early_len) => {
+                (ready!(future.get_mut().unwrap().poll_unpin(cx)), *early_len)
+            }
+            _ => return Poll::Ready(Ok(())),
+        };
+        match res {
+            Ok(stream) => {
+                self.state = ClientState::Connected(Box::new(stream));
+                self.early_written = early_len;
+                Poll::Ready(Ok(()))
+            }
+            Err(err) => {
+                self.state = ClientState::Closed;
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+impl AsyncRead for EarlyDataStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                ClientState::Pending(_) => self.connect(&[]),
+                ClientState::Connecting(..) => {
+                    ready!(self.poll_connect(cx))?;
+                }
+                ClientState::Connected(stream) => return Pin::new(stream).poll_read(cx, buf),
+                ClientState::Closed => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for EarlyDataStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.early_written > 0 {
+            return Poll::Ready(Ok(std::mem::take(&mut self.early_written)));
+        }
+        loop {
+            match &mut self.state {
+                ClientState::Pending(_) => {
+                    let n = usize::min(buf.len(), self.config.max_early_data);
+                    self.connect(&buf[..n]);
+                }
+                ClientState::Connecting(..) => {
+                    ready!(self.poll_connect(cx))?;
+                    if self.early_written > 0 {
+                        return Poll::Ready(Ok(std::mem::take(&mut self.early_written)));
+                    }
+                }
+                ClientState::Connected(stream) => return Pin::new(stream).poll_write(cx, buf),
+                ClientState::Closed => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "websocket closed")))
+                }
+            }
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                ClientState::Pending(_) | ClientState::Closed => return Poll::Ready(Ok(())),
+                ClientState::Connecting(..) => {
+                    ready!(self.poll_connect(cx))?;
+                }
+                ClientState::Connected(stream) => return Pin::new(stream).poll_flush(cx),
+            }
+        }
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                ClientState::Pending(stream) => return Pin::new(stream).poll_shutdown(cx),
+                ClientState::Closed => return Poll::Ready(Ok(())),
+                ClientState::Connecting(..) => {
+                    ready!(self.poll_connect(cx))?;
+                }
+                ClientState::Connected(stream) => return Pin::new(stream).poll_shutdown(cx),
+            }
+        }
+    }
+}
+
+// INBOUND
+
+struct Route {
+    path: String,
+    max_early_data: usize,
+    early_data_header_name: Option<HeaderName>,
+    tx: mpsc::Sender<(AnyStream, Session)>,
+}
+
+// 握手时按 path 找到的 inbound
+struct Matched {
+    tx: mpsc::Sender<(AnyStream, Session)>,
+    early_data: Vec<u8>,
+}
+
+// 同一地址上的 ws inbound 共享一个 listener，握手时按 path 分发
+struct WsServer {
+    routes: StdMutex<Vec<Route>>,
+}
+
+lazy_static! {
+    static ref SERVERS: Mutex<HashMap<SocketAddr, Arc<WsServer>>> = Mutex::new(HashMap::new());
+}
+
+impl WsServer {
+    fn route(&self, request: &Request, response: &mut Response) -> Option<Matched> {
+        let path = request.uri().path();
+        let routes = self.routes.lock().unwrap();
+        if let Some(route) = routes.iter().find(|x| x.path == path) {
+            let mut early_data = Vec::new();
+            if let (true, Some(name)) = (route.max_early_data > 0, &route.early_data_header_name) {
+                if let Some(value) = request.headers().get(name) {
+                    if let Some(data) = decode_early_data(value.as_bytes()) {
+                        if data.len() > route.max_early_data {
+                            return None;
+                        }
+                        early_data = data;
+                        // 浏览器和 CDN 要求 server 回应相同的 Sec-WebSocket-Protocol
+                        response.headers_mut().insert(name.clone(), value.clone());
+                    }
+                }
+            }
+            return Some(Matched {
+                tx: route.tx.clone(),
+                early_data,
+            });
+        }
+        // early data 追加在 path 之后
+        for route in routes.iter() {
+            if route.max_early_data == 0 || route.early_data_header_name.is_some() {
+                continue;
+            }
+            if let Some(data) = path.strip_prefix(route.path.as_str()).and_then(|x| decode_early_data(x.as_bytes())) {
+                if data.len() <= route.max_early_data {
+                    return Some(Matched {
+                        tx: route.tx.clone(),
+                        early_data: data,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    async fn serve(self: Arc<Self>, addr: SocketAddr, listener: AnyStreamListener) {
+        loop {
+            let (stream, session) = match listener.accept().await {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("websocket listener at {} accept error {}", addr, err);
+                    SERVERS.lock().await.remove(&addr);
+                    return;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let mut matched = None;
+                // ErrorResponse 由 tungstenite 决定
+                #[allow(clippy::result_large_err)]
+                let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+                    match server.route(request, &mut response) {
+                        Some(x) => {
+                            matched = Some(x);
+                            Ok(response)
+                        }
+                        None => {
+                            let mut response = ErrorResponse::new(None);
+                            *response.status_mut() = StatusCode::NOT_FOUND;
+                            Err(response)
+                        }
+                    }
+                };
+                let stream = match timeout(HANDSHAKE_TIMEOUT, accept_hdr_async(stream, callback)).await {
+                    Ok(Ok(x)) => x,
+                    Ok(Err(err)) => {
+                        debug!("websocket handshake with {} failed: {}", session.peer_address, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("websocket handshake with {} timeout", session.peer_address);
+                        return;
+                    }
+                };
+                if let Some(Matched { tx, early_data }) = matched {
+                    let stream: AnyStream = Box::new(WsStream::new(stream, BytesMut::from(&early_data[..])));
+                    let _ = tx.send((stream, session)).await;
+                }
+            });
+        }
+    }
+}
+
+pub struct WsStreamListener {
+    server: Arc<WsServer>,
+    path: String,
+    incoming: Mutex<mpsc::Receiver<(AnyStream, Session)>>,
+}
+
+impl WsStreamListener {
+    pub async fn bind(addr: SocketAddr, settings: &Option<StreamSettings>) -> Result<Self> {
+        let ws_settings = settings.as_ref().and_then(|x| x.ws_settings.clone()).unwrap_or_default();
+        let path = ws_settings.path.clone().unwrap_or_else(|| "/".to_string());
+        let early_data_header_name = match &ws_settings.early_data_header_name {
+            Some(name) => Some(HeaderName::from_bytes(name.as_bytes())?),
+            None => None,
+        };
+        // 持有锁直到 listener 创建完成，避免同一地址被 bind 两次
+        let mut servers = SERVERS.lock().await;
+        let server = match servers.get(&addr) {
+            Some(x) => x.clone(),
+            None => {
+                let listener = bind_tcp(addr, settings).await?;
+                let server = Arc::new(WsServer {
+                    routes: StdMutex::new(Vec::new()),
+                });
+                servers.insert(addr, server.clone());
+                tokio::spawn(server.clone().serve(addr, listener));
+                server
+            }
+        };
+        let (tx, rx) = mpsc::channel(32);
+        {
+            let mut routes = server.routes.lock().unwrap();
+            if routes.iter().any(|x| x.path == path) {
+                bail!("websocket path {} already in use at {}", path, addr);
+            }
+            routes.push(Route {
+                path: path.clone(),
+                max_early_data: ws_settings.max_early_data.unwrap_or(0),
+                early_data_header_name,
+                tx,
+            });
+        }
+        Ok(WsStreamListener {
+            server,
+            path,
+            incoming: Mutex::new(rx),
+        })
+    }
+}
+
+impl Drop for WsStreamListener {
+    fn drop(&mut self) {
+        self.server.routes.lock().unwrap().retain(|x| x.path != self.path);
+    }
+}
+
+#[async_trait]
+impl StreamListener for WsStreamListener {
+    async fn accept(&self) -> io::Result<(AnyStream, Session)> {
+        match self.incoming.lock().await.recv().await {
+            Some(x) => Ok(x),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "websocket listener closed")),
+        }
+    }
+}
+
+#[tokio::test]
+async fn websocket_route_test() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::RwLock,
+    };
+
+    use crate::{app::DnsClient, config::Config};
+
+    let addr: SocketAddr = "127.0.0.1:12350".parse().unwrap();
+    let ctx = Arc::new(Context::new(Arc::new(RwLock::new(DnsClient::new(Config::default())))));
+    let settings = |path: &str, max_early_data: usize, header: Option<&str>| {
+        Some(StreamSettings {
+            network: Some("ws".to_string()),
+            ws_settings: Some(WsSettings {
+                path: Some(path.to_string()),
+                headers: Some(HashMap::from([("Host".to_string(), "example.com".to_string())])),
+                max_early_data: Some(max_early_data),
+                early_data_header_name: header.map(|x| x.to_string()),
+            }),
+            ..Default::default()
+        })
+    };
+    let cases = vec![
+        settings("/plain", 0, None),
+        settings("/header", 2048, Some("Sec-WebSocket-Protocol")),
+        settings("/path", 2048, None),
+    ];
+    // 三个 inbound 共享同一个端口
+    let mut listeners = Vec::new();
+    for case in cases.iter() {
+        listeners.push(WsStreamListener::bind(addr, case).await.unwrap());
+    }
+    assert!(WsStreamListener::bind(addr, &cases[0]).await.is_err());
+
+    for (case, listener) in cases.iter().zip(listeners.iter()) {
+        let dialer = super::build_dialer(case).unwrap();
+        let mut client = dialer.dial(ctx.clone(), &Address::Ip(addr)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.write_all(b"world").await.unwrap();
+        server.flush().await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    // 未知的 path
+    let dialer = super::build_dialer(&settings("/unknown", 0, None)).unwrap();
+    assert!(dialer.dial(ctx, &Address::Ip(addr)).await.is_err());
+}
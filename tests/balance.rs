@@ -0,0 +1,113 @@
+mod server;
+// socks => load-balance (vless, vless) => vless => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1113,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "load-balance",
+                "settings": {
+                    "outbounds": ["vless_a", "vless_b"],
+                    "strategy": "round-robin"
+                },
+                "tag": "balancer"
+            },
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1114,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "tag": "vless_a"
+            },
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1115,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "tag": "vless_b"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "balancer"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1114,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_a_in"
+            },
+            {
+                "port": 1115,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_b_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12358", "127.0.0.1:1113");
+}
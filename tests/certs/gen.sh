@@ -0,0 +1,20 @@
+#!/bin/sh
+# 生成测试用证书，server 证书签发给 localhost 和 127.0.0.1
+set -e
+cd "$(dirname "$0")"
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out ca.key
+openssl req -x509 -new -key ca.key -sha256 -days 36500 -subj "/CN=tunnel test ca" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out ca.pem
+for name in server client; do
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out $name.key
+    openssl req -new -key $name.key -subj "/CN=$name" -out $name.csr
+    if [ $name = server ]; then
+        printf "subjectAltName=DNS:localhost,IP:127.0.0.1\nextendedKeyUsage=serverAuth\n" > $name.ext
+    else
+        printf "subjectAltName=DNS:client\nextendedKeyUsage=clientAuth\n" > $name.ext
+    fi
+    openssl x509 -req -in $name.csr -CA ca.pem -CAkey ca.key -CAcreateserial -sha256 -days 36500 \
+        -extfile $name.ext -out $name.pem
+    rm $name.csr $name.ext
+done
+rm -f ca.srl ca.key
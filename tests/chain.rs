@@ -0,0 +1,99 @@
+mod server;
+// socks => vless (dialerProxy socks) => socks => vless => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1110,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1112,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "dialerProxy": "socks_up",
+                "tag": "vless_out"
+            },
+            {
+                "protocol": "socks",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1111
+                },
+                "tag": "socks_up"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1111,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_up_in"
+            },
+            {
+                "port": 1112,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12357", "127.0.0.1:1110");
+}
@@ -0,0 +1,103 @@
+mod server;
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+#[test]
+fn start() {
+    let config = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1095,
+                "listen": "127.0.0.1",
+                "protocol": "dokodemo-door",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 12348
+                },
+                "tag": "dokodemo_in"
+            },
+            {
+                "port": 1120,
+                "listen": "127.0.0.1",
+                "protocol": "dokodemo-door",
+                "settings": {
+                    "address": "127.0.0.2",
+                    "port": 12348
+                },
+                "tag": "dokodemo_socks"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            },
+            {
+                "protocol": "socks",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1121
+                },
+                "tag": "socks_out"
+            }
+        ],
+        "routes": [
+            {
+                "ip": [
+                    "127.0.0.2/32"
+                ],
+                "target": "socks_out"
+            },
+            {
+                "ip": [
+                    "127.0.0.1/32"
+                ],
+                "target": "socks_out",
+                "fallback": ["direct_out"]
+            },
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let configs = vec![serde_json::from_str(config).unwrap()];
+    let client = async {
+        let buf = "helloworld".as_bytes();
+        let mut stream = TcpStream::connect("127.0.0.1:1095").await?;
+        stream.write_all(buf).await?;
+        let mut received = vec![0; buf.len()];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(buf, received);
+
+        // socks outbound 不支持 udp，packet 被丢弃
+        let dropped = UdpSocket::bind("127.0.0.1:0").await?;
+        dropped.connect("127.0.0.1:1120").await?;
+        dropped.send(buf).await?;
+
+        // socks_out 没有 udp handler，tcp 连接被拒绝，都通过 fallback 的 direct_out 转发
+        // 多个 peer 同时使用同一个 inbound
+        for _ in 0..2 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+            socket.connect("127.0.0.1:1095").await?;
+            socket.send(buf).await?;
+            let mut received = vec![0; 1024];
+            let n = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut received)).await??;
+            assert_eq!(buf, &received[..n]);
+        }
+        Ok(())
+    };
+    server::run_tunnel(configs, "127.0.0.1:12348", client);
+}
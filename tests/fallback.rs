@@ -0,0 +1,88 @@
+mod server;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// 通过 socks inbound 请求 127.0.0.1:12359 (或 127.0.0.2:12359)，返回 reply 的 REP
+async fn socks_connect(ip: [u8; 4]) -> anyhow::Result<(TcpStream, u8)> {
+    let mut stream = TcpStream::connect("127.0.0.1:1116").await?;
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+    let port = 12359u16.to_be_bytes();
+    stream
+        .write_all(&[0x05, 0x01, 0x00, 0x01, ip[0], ip[1], ip[2], ip[3], port[0], port[1]])
+        .await?;
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await?;
+    Ok((stream, reply[1]))
+}
+
+// socks => socks (没有监听, 连接失败) => fallback direct
+#[test]
+fn start() {
+    let config = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1116,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "socks",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1117
+                },
+                "tag": "dead"
+            },
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "ip": [
+                    "127.0.0.2/32"
+                ],
+                "target": "dead"
+            },
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "dead",
+                "fallback": ["unknown", "direct_out"],
+                "connectTimeout": 5
+            }
+        ]
+    }"#;
+    let configs = vec![serde_json::from_str(config).unwrap()];
+    let client = async {
+        let buf = "helloworld".as_bytes();
+        let (mut stream, rep) = socks_connect([127, 0, 0, 1]).await?;
+        assert_eq!(rep, 0x00);
+        stream.write_all(buf).await?;
+        let mut received = vec![0; buf.len()];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(buf, received);
+
+        // 没有 fallback，client 收到 connection refused
+        let (_, rep) = socks_connect([127, 0, 0, 2]).await?;
+        assert_eq!(rep, 0x05);
+        Ok(())
+    };
+    server::run_tunnel(configs, "127.0.0.1:12359", client);
+}
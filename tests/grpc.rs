@@ -0,0 +1,107 @@
+mod server;
+// socks => vless over grpc (tls) => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1100,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1101,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "streamSettings": {
+                    "network": "grpc",
+                    "security": "tls",
+                    "tlsSettings": {
+                        "serverName": "localhost",
+                        "alpn": ["h2"],
+                        "ca": "tests/certs/ca.pem"
+                    },
+                    "grpcSettings": {
+                        "serviceName": "vless"
+                    }
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1101,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "streamSettings": {
+                    "network": "grpc",
+                    "security": "tls",
+                    "tlsSettings": {
+                        "alpn": ["h2"],
+                        "certificateFile": "tests/certs/server.pem",
+                        "keyFile": "tests/certs/server.key"
+                    },
+                    "grpcSettings": {
+                        "serviceName": "vless"
+                    }
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12352", "127.0.0.1:1100");
+}
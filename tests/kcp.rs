@@ -0,0 +1,106 @@
+mod server;
+// socks => vless over kcp => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1108,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1109,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "streamSettings": {
+                    "network": "kcp",
+                    "kcpSettings": {
+                        "mtu": 1350,
+                        "tti": 20,
+                        "uplinkCapacity": 10,
+                        "downlinkCapacity": 10,
+                        "congestion": true,
+                        "header": {
+                            "type": "srtp"
+                        }
+                    }
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1109,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "streamSettings": {
+                    "network": "kcp",
+                    "kcpSettings": {
+                        "tti": 20,
+                        "congestion": true,
+                        "header": {
+                            "type": "srtp"
+                        }
+                    }
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12356", "127.0.0.1:1108");
+}
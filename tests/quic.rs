@@ -0,0 +1,218 @@
+mod server;
+
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+// socks => vless over quic => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1102,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1103,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "streamSettings": {
+                    "network": "quic",
+                    "tlsSettings": {
+                        "serverName": "localhost",
+                        "alpn": ["h3"],
+                        "ca": "tests/certs/ca.pem"
+                    }
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1103,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "streamSettings": {
+                    "network": "quic",
+                    "tlsSettings": {
+                        "alpn": ["h3"],
+                        "certificateFile": "tests/certs/server.pem",
+                        "keyFile": "tests/certs/server.key"
+                    }
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12353", "127.0.0.1:1102");
+}
+
+// udp client => dokodemo-door => vless over quic (datagram) => direct => udp echo
+#[test]
+fn udp() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1124,
+                "listen": "127.0.0.1",
+                "protocol": "dokodemo-door",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 12363
+                },
+                "tag": "dokodemo_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1125,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "streamSettings": {
+                    "network": "quic",
+                    "tlsSettings": {
+                        "serverName": "localhost",
+                        "alpn": ["h3"],
+                        "ca": "tests/certs/ca.pem"
+                    }
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1125,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "streamSettings": {
+                    "network": "quic",
+                    "tlsSettings": {
+                        "alpn": ["h3"],
+                        "certificateFile": "tests/certs/server.pem",
+                        "keyFile": "tests/certs/server.key"
+                    }
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        configs.push(serde_json::from_str(config).unwrap());
+    }
+    let client = async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect("127.0.0.1:1124").await?;
+        // 超过 max_datagram_size 的 packet 在 stream 上转发
+        let large = vec![7u8; 4000];
+        for packet in ["helloworld".as_bytes(), "second packet".as_bytes(), &large] {
+            socket.send(packet).await?;
+            let mut received = vec![0; 8192];
+            let n = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut received)).await??;
+            assert_eq!(packet, &received[..n]);
+        }
+        Ok(())
+    };
+    server::run_tunnel(configs, "127.0.0.1:12363", client);
+}
@@ -2,7 +2,7 @@
 // 所以 local-proxy inbound 只需要socks就行，
 // 其他协议的 inbound，通过 local-proxy#outbound => remote-proxy-server#inbound 测试
 
-use std::{net::SocketAddr, str::FromStr, time::Duration, convert::TryFrom};
+use std::{net::SocketAddr, str::FromStr, time::Duration, convert::TryFrom, future::Future};
 
 use futures::{future::BoxFuture, FutureExt};
 use log::debug;
@@ -33,7 +33,7 @@ pub async fn tcp_echo_server(addr: SocketAddr) {
 }
 pub async fn udp_echo_server(bind_addr: SocketAddr) {
     let socket = UdpSocket::bind(bind_addr).await.unwrap();
-    let mut buf = Vec::new();
+    let mut buf = vec![0u8; 65535];
     loop {
         let (n, remote_addr) = match socket.recv_from(&mut buf).await {
             Ok(x) => x,
@@ -67,6 +67,21 @@ pub fn start_tunnel(
     socks_server_listening_at: &str,
 ) {
     let buf = "helloworld".as_bytes();
+    run_tunnel(
+        configs,
+        echo_server_listening_at,
+        send_data_socks5_tcp(socks_server_listening_at, echo_server_listening_at, buf),
+    );
+}
+
+// start all tunnel instances and echo server, then run client against them
+pub fn run_tunnel<F>(
+    configs: Vec<tunnel::config::Config>,
+    echo_server_listening_at: &str,
+    client: F,
+) where
+    F: Future<Output = anyhow::Result<()>>,
+{
     let rt = Builder::new_current_thread().enable_all().build().unwrap();
     let mut abort_handlers = Vec::new();
 
@@ -93,9 +108,7 @@ pub fn start_tunnel(
         futures::future::abortable(futures::future::join_all(tasks));
     let test_future = async {
         tokio::time::sleep(Duration::from_millis(100)).await;
-        send_data_socks5_tcp(socks_server_listening_at, echo_server_listening_at, &buf)
-            .await
-            .unwrap();
+        client.await.unwrap();
         // call abort handler after test completed
         abort_handler.abort();
     };
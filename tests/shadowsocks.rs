@@ -0,0 +1,94 @@
+mod server;
+// socks => shadowsocks over obfs (http) => direct
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1126,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "shadowsocks",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1127,
+                    "method": "aes-256-gcm",
+                    "password": "password"
+                },
+                "streamSettings": {
+                    "network": "obfs",
+                    "obfsSettings": {
+                        "mode": "http",
+                        "host": "www.bing.com"
+                    }
+                },
+                "tag": "ss_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "ss_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1127,
+                "listen": "127.0.0.1",
+                "protocol": "shadowsocks",
+                "settings": {
+                    "method": "aes-256-gcm",
+                    "password": "password"
+                },
+                "streamSettings": {
+                    "network": "obfs",
+                    "obfsSettings": {
+                        "mode": "http"
+                    }
+                },
+                "tag": "ss_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12364", "127.0.0.1:1126");
+}
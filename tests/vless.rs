@@ -0,0 +1,309 @@
+mod server;
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::{BufMut, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tunnel::proxy::{
+    vless::{Addons, RequestHeader},
+    Address, Network,
+};
+#[test]
+fn start() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1090,
+                "listen": "127.0.0.1",
+                "protocol": "socks",
+                "settings": {},
+                "tag": "socks_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1091,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1091,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        let c = serde_json::from_str(config).unwrap();
+        configs.push(c);
+    }
+    server::start_tunnel(configs, "127.0.0.1:12347", "127.0.0.1:1090");
+}
+
+// client (vless UDP command) => vless inbound => vless outbound => vless inbound => direct => udp echo
+#[test]
+fn udp() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1118,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1119,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1119,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        configs.push(serde_json::from_str(config).unwrap());
+    }
+    let client = async {
+        let mut stream = TcpStream::connect("127.0.0.1:1118").await?;
+        let header = RequestHeader {
+            uuid: "b831381d-6324-4d53-ad4f-8cda48b30811".parse()?,
+            addons: Addons::default(),
+            network: Network::UDP,
+            destination: Address::Ip("127.0.0.1:12361".parse::<SocketAddr>()?),
+        };
+        let mut buf = BytesMut::new();
+        header.encode(&mut buf);
+        stream.write_all(&buf).await?;
+        // response header: version + addons length
+        let mut response = [0u8; 2];
+        let mut header_read = false;
+        for packet in ["helloworld".as_bytes(), "second packet".as_bytes()] {
+            let mut buf = BytesMut::new();
+            buf.put_u16(packet.len() as u16);
+            buf.put_slice(packet);
+            stream.write_all(&buf).await?;
+            if !header_read {
+                stream.read_exact(&mut response).await?;
+                assert_eq!(response, [0x00, 0x00]);
+                header_read = true;
+            }
+            let len = stream.read_u16().await? as usize;
+            let mut received = vec![0; len];
+            stream.read_exact(&mut received).await?;
+            assert_eq!(packet, &received[..]);
+        }
+        Ok(())
+    };
+    server::run_tunnel(configs, "127.0.0.1:12361", client);
+}
+
+// udp client => dokodemo-door => vless outbound => vless inbound => direct => udp echo
+#[test]
+fn udp_outbound() {
+    let local = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1122,
+                "listen": "127.0.0.1",
+                "protocol": "dokodemo-door",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 12362
+                },
+                "tag": "dokodemo_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "vless",
+                "settings": {
+                    "address": "127.0.0.1",
+                    "port": 1123,
+                    "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                },
+                "tag": "vless_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "vless_out"
+            }
+        ]
+    }
+    "#;
+    let server = r#"
+    {
+        "general": {
+            "prefer_ipv6": false,
+            "use_ipv6": false
+        },
+        "inbounds": [
+            {
+                "port": 1123,
+                "listen": "127.0.0.1",
+                "protocol": "vless",
+                "settings": {
+                    "clients": [
+                        {
+                            "id": "b831381d-6324-4d53-ad4f-8cda48b30811"
+                        }
+                    ]
+                },
+                "tag": "vless_in"
+            }
+        ],
+        "outbounds": [
+            {
+                "protocol": "direct",
+                "tag": "direct_out"
+            }
+        ],
+        "routes": [
+            {
+                "regexp": [
+                    ".*"
+                ],
+                "target": "direct_out"
+            }
+        ]
+    }"#;
+    let mut configs = Vec::new();
+    for config in vec![local, server] {
+        configs.push(serde_json::from_str(config).unwrap());
+    }
+    let client = async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        socket.connect("127.0.0.1:1122").await?;
+        for packet in ["helloworld".as_bytes(), "second packet".as_bytes()] {
+            socket.send(packet).await?;
+            let mut received = vec![0; 1024];
+            let n = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut received)).await??;
+            assert_eq!(packet, &received[..n]);
+        }
+        Ok(())
+    };
+    server::run_tunnel(configs, "127.0.0.1:12362", client);
+}